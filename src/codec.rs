@@ -0,0 +1,266 @@
+//! Encodings of the data legion-sync puts on the wire.
+//!
+//! The [WireCodec](WireCodec) resource decides how the server and client world encode the data they exchange,
+//! both worlds should use the same codec, which is checked when a client connects.
+//! [WireCodec::Packed](WireCodec::Packed) bit-packs all the data, including the message envelopes and world states.
+//! Registered components can opt in to a bit-packed [Encoding](Encoding) with any codec, which also allows quantized floats.
+
+use std::fmt::{self, Display, Formatter};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use net_sync::re_exports::bincode;
+
+pub use self::packed::{PackedDeserializer, PackedError, PackedSerializer, Quantization};
+
+pub mod packed;
+
+/// The codec the modification tracker serializes the unchanged and changed component data with.
+pub(crate) const TRACKER_CODEC: WireCodec = WireCodec::BincodeFixint;
+
+/// Errors that can occur while encoding or decoding with a [WireCodec](WireCodec).
+#[derive(Debug)]
+pub enum CodecError {
+    Bincode(bincode::Error),
+    MessagePackEncode(rmp_serde::encode::Error),
+    MessagePackDecode(rmp_serde::decode::Error),
+    Packed(PackedError),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Bincode(e) => write!(fmt, "Bincode error occurred: {:?}", e),
+            CodecError::MessagePackEncode(e) => {
+                write!(fmt, "MessagePack encode error occurred: {:?}", e)
+            }
+            CodecError::MessagePackDecode(e) => {
+                write!(fmt, "MessagePack decode error occurred: {:?}", e)
+            }
+            CodecError::Packed(e) => write!(fmt, "Packed error occurred: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// The codec used for the data the server and client world exchange.
+///
+/// Insert it with `with_wire_codec` on the world builders, both sides should use the same codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireCodec {
+    /// Bincode with fixed size integers.
+    BincodeFixint,
+    /// Bincode with variable length integers.
+    BincodeVarint,
+    /// MessagePack with structs encoded as arrays.
+    MessagePack,
+    /// The bit-packed format of the [packed](packed) module, floats at full precision.
+    Packed,
+}
+
+impl WireCodec {
+    pub fn serialize<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match *self {
+            WireCodec::BincodeFixint => fixint_options()
+                .serialize(value)
+                .map_err(CodecError::Bincode),
+            WireCodec::BincodeVarint => varint_options()
+                .serialize(value)
+                .map_err(CodecError::Bincode),
+            WireCodec::MessagePack => {
+                rmp_serde::to_vec(value).map_err(CodecError::MessagePackEncode)
+            }
+            WireCodec::Packed => packed::to_bytes(value, None).map_err(CodecError::Packed),
+        }
+    }
+
+    pub fn deserialize<'a, T: Deserialize<'a>>(&self, bytes: &'a [u8]) -> Result<T, CodecError> {
+        match *self {
+            WireCodec::BincodeFixint => fixint_options()
+                .deserialize(bytes)
+                .map_err(CodecError::Bincode),
+            WireCodec::BincodeVarint => varint_options()
+                .deserialize(bytes)
+                .map_err(CodecError::Bincode),
+            WireCodec::MessagePack => {
+                rmp_serde::from_read_ref(bytes).map_err(CodecError::MessagePackDecode)
+            }
+            WireCodec::Packed => packed::from_bytes(bytes, None).map_err(CodecError::Packed),
+        }
+    }
+
+    /// Encodes whatever the given function serializes.
+    pub(crate) fn serialize_erased<R>(
+        &self,
+        serialize: impl FnOnce(&mut dyn erased_serde::Serializer) -> R,
+    ) -> (R, Vec<u8>) {
+        let mut buffer = Vec::new();
+
+        let result = match *self {
+            WireCodec::BincodeFixint => {
+                let mut serializer = bincode::Serializer::new(&mut buffer, fixint_options());
+                serialize(&mut erased_serde::Serializer::erase(&mut serializer))
+            }
+            WireCodec::BincodeVarint => {
+                let mut serializer = bincode::Serializer::new(&mut buffer, varint_options());
+                serialize(&mut erased_serde::Serializer::erase(&mut serializer))
+            }
+            WireCodec::MessagePack => {
+                let mut serializer = rmp_serde::Serializer::new(&mut buffer);
+                serialize(&mut erased_serde::Serializer::erase(&mut serializer))
+            }
+            WireCodec::Packed => {
+                let mut serializer = PackedSerializer::new(None);
+                let result = serialize(&mut erased_serde::Serializer::erase(&mut serializer));
+                buffer = serializer.into_bytes();
+                result
+            }
+        };
+
+        (result, buffer)
+    }
+
+    /// Decodes the given bytes with the given function.
+    pub(crate) fn deserialize_erased<R>(
+        &self,
+        bytes: &[u8],
+        deserialize: impl FnOnce(&mut dyn erased_serde::Deserializer) -> R,
+    ) -> R {
+        match *self {
+            WireCodec::BincodeFixint => {
+                let mut deserializer = bincode::Deserializer::from_slice(bytes, fixint_options());
+                deserialize(&mut erased_serde::Deserializer::erase(&mut deserializer))
+            }
+            WireCodec::BincodeVarint => {
+                let mut deserializer = bincode::Deserializer::from_slice(bytes, varint_options());
+                deserialize(&mut erased_serde::Deserializer::erase(&mut deserializer))
+            }
+            WireCodec::MessagePack => {
+                let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+                deserialize(&mut erased_serde::Deserializer::erase(&mut deserializer))
+            }
+            WireCodec::Packed => {
+                let mut deserializer = PackedDeserializer::new(bytes, None);
+                deserialize(&mut erased_serde::Deserializer::erase(&mut deserializer))
+            }
+        }
+    }
+}
+
+impl Default for WireCodec {
+    fn default() -> Self {
+        WireCodec::BincodeFixint
+    }
+}
+
+/// How the data and differences of a registered component are encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Encoded with the [WireCodec](WireCodec) of the world.
+    Codec,
+    /// Bit-packed with variable length integers, floats are quantized if a quantization is given.
+    Packed(Option<Quantization>),
+}
+
+impl Encoding {
+    /// Encodes whatever the given function serializes.
+    pub(crate) fn serialize<R>(
+        &self,
+        codec: WireCodec,
+        serialize: impl FnOnce(&mut dyn erased_serde::Serializer) -> R,
+    ) -> (R, Vec<u8>) {
+        match *self {
+            Encoding::Codec => codec.serialize_erased(serialize),
+            Encoding::Packed(quantization) => {
+                let mut serializer = PackedSerializer::new(quantization);
+                let result = serialize(&mut erased_serde::Serializer::erase(&mut serializer));
+                (result, serializer.into_bytes())
+            }
+        }
+    }
+
+    /// Decodes the given bytes with the given function.
+    pub(crate) fn deserialize<R>(
+        &self,
+        codec: WireCodec,
+        bytes: &[u8],
+        deserialize: impl FnOnce(&mut dyn erased_serde::Deserializer) -> R,
+    ) -> R {
+        match *self {
+            Encoding::Codec => codec.deserialize_erased(bytes, deserialize),
+            Encoding::Packed(quantization) => {
+                let mut deserializer = PackedDeserializer::new(bytes, quantization);
+                deserialize(&mut erased_serde::Deserializer::erase(&mut deserializer))
+            }
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Codec
+    }
+}
+
+fn fixint_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+fn varint_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_varint_encoding()
+        .allow_trailing_bytes()
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde::{Deserialize, Serialize};
+
+    use crate::codec::WireCodec;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Position {
+        x: f32,
+        y: f32,
+        id: u64,
+    }
+
+    #[test]
+    fn codecs_round_trip_test() {
+        let position = Position {
+            x: 1.5,
+            y: -2.,
+            id: 3,
+        };
+
+        for codec in [
+            WireCodec::BincodeFixint,
+            WireCodec::BincodeVarint,
+            WireCodec::MessagePack,
+            WireCodec::Packed,
+        ]
+        .iter()
+        {
+            let bytes = codec.serialize(&position).unwrap();
+            assert_eq!(codec.deserialize::<Position>(&bytes).unwrap(), position);
+        }
+    }
+
+    #[test]
+    fn varint_is_smaller_than_fixint_test() {
+        let position = Position {
+            x: 0.,
+            y: 0.,
+            id: 3,
+        };
+
+        assert!(
+            WireCodec::BincodeVarint.serialize(&position).unwrap().len()
+                < WireCodec::BincodeFixint.serialize(&position).unwrap().len()
+        );
+    }
+}
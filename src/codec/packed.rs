@@ -0,0 +1,1045 @@
+//! A bit-packing serde format.
+//!
+//! Like bincode the format is not self-describing, the reader needs to know the type it reads.
+//! Unlike bincode values are not aligned to bytes:
+//!
+//! - booleans and option tags take a single bit;
+//! - integers wider than a byte are written as variable length integers, signed integers are zigzag encoded;
+//! - floats are written at full precision, or quantized to a range and precision when a [Quantization](Quantization) is given;
+//! - sequences and maps carry their length, or a continuation bit per element when the length is not known up front.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+};
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+    Deserialize,
+};
+
+/// Errors that can occur while packing or unpacking.
+#[derive(Debug)]
+pub enum PackedError {
+    UnexpectedEnd,
+    InvalidUtf8,
+    InvalidChar(u32),
+    /// A packed integer does not fit in the type it is read as.
+    OutOfRange(&'static str),
+    Unsupported(&'static str),
+    Custom(String),
+}
+
+impl Display for PackedError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PackedError::UnexpectedEnd => write!(fmt, "Unexpected end of packed data."),
+            PackedError::InvalidUtf8 => write!(fmt, "Packed string is not valid UTF-8."),
+            PackedError::InvalidChar(value) => write!(fmt, "Invalid packed char: {}.", value),
+            PackedError::OutOfRange(ty) => write!(fmt, "Packed integer does not fit in {}.", ty),
+            PackedError::Unsupported(what) => {
+                write!(fmt, "The packed format does not support {}.", what)
+            }
+            PackedError::Custom(message) => write!(fmt, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for PackedError {}
+
+impl ser::Error for PackedError {
+    fn custom<T: Display>(msg: T) -> Self {
+        PackedError::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for PackedError {
+    fn custom<T: Display>(msg: T) -> Self {
+        PackedError::Custom(msg.to_string())
+    }
+}
+
+/// Quantizes floats to a range with a fixed precision.
+///
+/// Values outside the range are clamped.
+/// For example a range of -1024 to 1024 with a precision of 0.01 takes 18 bits instead of 32.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantization {
+    min: f32,
+    max: f32,
+    precision: f32,
+}
+
+impl Quantization {
+    pub fn new(min: f32, max: f32, precision: f32) -> Quantization {
+        assert!(min < max, "The minimum should be smaller than the maximum.");
+        assert!(precision > 0., "The precision should be positive.");
+
+        Quantization {
+            min,
+            max,
+            precision,
+        }
+    }
+
+    fn steps(&self) -> u64 {
+        ((self.max - self.min) as f64 / self.precision as f64).ceil() as u64
+    }
+
+    /// Returns the number of bits a quantized value takes.
+    pub fn bits(&self) -> u32 {
+        64 - self.steps().leading_zeros()
+    }
+
+    fn quantize(&self, value: f64) -> u64 {
+        let clamped = value.max(self.min as f64).min(self.max as f64);
+        (((clamped - self.min as f64) / self.precision as f64).round() as u64).min(self.steps())
+    }
+
+    fn dequantize(&self, quantized: u64) -> f64 {
+        (self.min as f64 + quantized as f64 * self.precision as f64).min(self.max as f64)
+    }
+}
+
+/// Writes values bit by bit, starting at the least significant bit.
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_position: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            bit_position: 0,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        if self.bit_position % 8 == 0 {
+            self.bytes.push(0);
+        }
+
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 1 << (self.bit_position % 8);
+        }
+
+        self.bit_position += 1;
+    }
+
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        for bit in 0..count {
+            self.write_bit(value >> bit & 1 == 1);
+        }
+    }
+
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let group = value & 0x7f;
+            value >>= 7;
+
+            self.write_bits(group, 7);
+            self.write_bit(value != 0);
+
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Returns the written bytes, the last byte is padded with zeros.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads values written by a [BitWriter](BitWriter).
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit_position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            bit_position: 0,
+        }
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, PackedError> {
+        let byte = self
+            .bytes
+            .get(self.bit_position / 8)
+            .ok_or(PackedError::UnexpectedEnd)?;
+
+        let bit = byte >> (self.bit_position % 8) & 1 == 1;
+        self.bit_position += 1;
+
+        Ok(bit)
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> Result<u64, PackedError> {
+        let mut value = 0;
+
+        for bit in 0..count {
+            if self.read_bit()? {
+                value |= 1 << bit;
+            }
+        }
+
+        Ok(value)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, PackedError> {
+        let mut value = 0;
+        let mut shift = 0;
+
+        loop {
+            value |= self.read_bits(7)? << shift;
+            shift += 7;
+
+            if !self.read_bit()? {
+                return Ok(value);
+            }
+
+            if shift >= 64 {
+                return Err(PackedError::Custom("Varint is too long.".to_string()));
+            }
+        }
+    }
+}
+
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+// Converts a packed integer to the type it is read as, corrupt or malicious data must not be truncated silently.
+fn narrow<T: TryFrom<V>, V>(value: V, ty: &'static str) -> Result<T, PackedError> {
+    T::try_from(value).map_err(|_| PackedError::OutOfRange(ty))
+}
+
+/// Serializes values into the packed format.
+pub struct PackedSerializer {
+    writer: BitWriter,
+    quantization: Option<Quantization>,
+}
+
+impl PackedSerializer {
+    pub fn new(quantization: Option<Quantization>) -> PackedSerializer {
+        PackedSerializer {
+            writer: BitWriter::new(),
+            quantization,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.writer.into_bytes()
+    }
+
+    fn write_float(&mut self, value: f64, full_precision: impl FnOnce(&mut BitWriter)) {
+        match self.quantization {
+            Some(quantization) => self
+                .writer
+                .write_bits(quantization.quantize(value), quantization.bits()),
+            None => full_precision(&mut self.writer),
+        }
+    }
+
+    fn write_length(&mut self, len: Option<usize>) {
+        // A set bit means the length is known, otherwise each element is preceded by a continuation bit.
+        match len {
+            Some(len) => {
+                self.writer.write_bit(true);
+                self.writer.write_varint(len as u64);
+            }
+            None => self.writer.write_bit(false),
+        }
+    }
+}
+
+/// Serializes the given value into the packed format.
+pub fn to_bytes<T: Serialize + ?Sized>(
+    value: &T,
+    quantization: Option<Quantization>,
+) -> Result<Vec<u8>, PackedError> {
+    let mut serializer = PackedSerializer::new(quantization);
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_bytes())
+}
+
+/// Deserializes a value from the packed format.
+pub fn from_bytes<'de, T: Deserialize<'de>>(
+    bytes: &'de [u8],
+    quantization: Option<Quantization>,
+) -> Result<T, PackedError> {
+    T::deserialize(&mut PackedDeserializer::new(bytes, quantization))
+}
+
+/// Serializes a compound value, keeping track of whether its elements need a continuation bit.
+pub struct Compound<'a> {
+    serializer: &'a mut PackedSerializer,
+    continuation: bool,
+}
+
+impl<'a> Compound<'a> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        if self.continuation {
+            self.serializer.writer.write_bit(true);
+        }
+
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn finish(self) -> Result<(), PackedError> {
+        if self.continuation {
+            self.serializer.writer.write_bit(false);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut PackedSerializer {
+    type Ok = ();
+    type Error = PackedError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), PackedError> {
+        self.writer.write_bit(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), PackedError> {
+        self.writer.write_bits(v as u8 as u64, 8);
+        Ok(())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), PackedError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), PackedError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), PackedError> {
+        self.writer.write_varint(zigzag_encode(v));
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), PackedError> {
+        self.writer.write_bits(v as u64, 8);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), PackedError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), PackedError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), PackedError> {
+        self.writer.write_varint(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), PackedError> {
+        self.write_float(v as f64, |writer| writer.write_bits(v.to_bits() as u64, 32));
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), PackedError> {
+        self.write_float(v, |writer| writer.write_bits(v.to_bits(), 64));
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), PackedError> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), PackedError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), PackedError> {
+        self.writer.write_varint(v.len() as u64);
+
+        for byte in v {
+            self.writer.write_bits(*byte as u64, 8);
+        }
+
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), PackedError> {
+        self.writer.write_bit(false);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), PackedError> {
+        self.writer.write_bit(true);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), PackedError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), PackedError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), PackedError> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), PackedError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), PackedError> {
+        self.writer.write_varint(variant_index as u64);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a>, PackedError> {
+        self.write_length(len);
+
+        Ok(Compound {
+            serializer: self,
+            continuation: len.is_none(),
+        })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, PackedError> {
+        Ok(Compound {
+            serializer: self,
+            continuation: false,
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, PackedError> {
+        self.serialize_tuple(_len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, PackedError> {
+        self.writer.write_varint(variant_index as u64);
+        self.serialize_tuple(_len)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a>, PackedError> {
+        self.serialize_seq(len)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, PackedError> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, PackedError> {
+        self.writer.write_varint(variant_index as u64);
+        self.serialize_tuple(len)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), PackedError> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), PackedError> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = PackedError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), PackedError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), PackedError> {
+        self.finish()
+    }
+}
+
+/// Deserializes values from the packed format.
+pub struct PackedDeserializer<'de> {
+    reader: BitReader<'de>,
+    quantization: Option<Quantization>,
+}
+
+impl<'de> PackedDeserializer<'de> {
+    pub fn new(bytes: &'de [u8], quantization: Option<Quantization>) -> PackedDeserializer<'de> {
+        PackedDeserializer {
+            reader: BitReader::new(bytes),
+            quantization,
+        }
+    }
+
+    fn read_float(&mut self, full_precision: u32) -> Result<f64, PackedError> {
+        match self.quantization {
+            Some(quantization) => {
+                Ok(quantization.dequantize(self.reader.read_bits(quantization.bits())?))
+            }
+            None if full_precision == 32 => {
+                Ok(f32::from_bits(self.reader.read_bits(32)? as u32) as f64)
+            }
+            None => Ok(f64::from_bits(self.reader.read_bits(64)?)),
+        }
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>, PackedError> {
+        let len = narrow::<usize, _>(self.reader.read_varint()?, "usize")?;
+        let mut bytes = Vec::with_capacity(len.min(self.reader.bytes.len()));
+
+        for _ in 0..len {
+            bytes.push(self.reader.read_bits(8)? as u8);
+        }
+
+        Ok(bytes)
+    }
+
+    fn read_length(&mut self) -> Result<Option<usize>, PackedError> {
+        if self.reader.read_bit()? {
+            Ok(Some(narrow(self.reader.read_varint()?, "usize")?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Gives access to the elements of a compound value.
+struct Access<'a, 'de> {
+    deserializer: &'a mut PackedDeserializer<'de>,
+    // `None` when each element is preceded by a continuation bit.
+    remaining: Option<usize>,
+}
+
+impl<'a, 'de> Access<'a, 'de> {
+    fn has_next(&mut self) -> Result<bool, PackedError> {
+        match self.remaining.as_mut() {
+            Some(0) => Ok(false),
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(true)
+            }
+            None => self.deserializer.reader.read_bit(),
+        }
+    }
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Access<'a, 'de> {
+    type Error = PackedError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, PackedError> {
+        if self.has_next()? {
+            seed.deserialize(&mut *self.deserializer).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Access<'a, 'de> {
+    type Error = PackedError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, PackedError> {
+        if self.has_next()? {
+            seed.deserialize(&mut *self.deserializer).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, PackedError> {
+        seed.deserialize(&mut *self.deserializer)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for &'a mut PackedDeserializer<'de> {
+    type Error = PackedError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), PackedError> {
+        let variant_index = narrow::<u32, _>(self.reader.read_varint()?, "u32")?;
+        let value = seed.deserialize(variant_index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for &'a mut PackedDeserializer<'de> {
+    type Error = PackedError;
+
+    fn unit_variant(self) -> Result<(), PackedError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, PackedError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+impl<'a, 'de> de::Deserializer<'de> for &'a mut PackedDeserializer<'de> {
+    type Error = PackedError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, PackedError> {
+        Err(PackedError::Unsupported("self-describing deserialization"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_bool(self.reader.read_bit()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_i8(self.reader.read_bits(8)? as u8 as i8)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_i16(narrow(zigzag_decode(self.reader.read_varint()?), "i16")?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_i32(narrow(zigzag_decode(self.reader.read_varint()?), "i32")?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_i64(zigzag_decode(self.reader.read_varint()?))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_u8(self.reader.read_bits(8)? as u8)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_u16(narrow(self.reader.read_varint()?, "u16")?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_u32(narrow(self.reader.read_varint()?, "u32")?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_u64(self.reader.read_varint()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_f32(self.read_float(32)? as f32)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_f64(self.read_float(64)?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        let value = narrow::<u32, _>(self.reader.read_varint()?, "char")?;
+        visitor.visit_char(std::char::from_u32(value).ok_or(PackedError::InvalidChar(value))?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        let bytes = self.read_bytes()?;
+        visitor.visit_string(String::from_utf8(bytes).map_err(|_| PackedError::InvalidUtf8)?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_byte_buf(self.read_bytes()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        if self.reader.read_bit()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        let remaining = self.read_length()?;
+
+        visitor.visit_seq(Access {
+            deserializer: self,
+            remaining,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        visitor.visit_seq(Access {
+            deserializer: self,
+            remaining: Some(len),
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        let remaining = self.read_length()?;
+
+        visitor.visit_map(Access {
+            deserializer: self,
+            remaining,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PackedError> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        _visitor: V,
+    ) -> Result<V::Value, PackedError> {
+        Err(PackedError::Unsupported("ignoring values"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
+
+    use crate::{
+        codec::packed::{from_bytes, to_bytes, PackedError, Quantization},
+        tracking::re_exports::bincode,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Point,
+        Circle(f32),
+        Rectangle { width: f32, height: f32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        alive: bool,
+        health: u32,
+        offset: i64,
+        name: String,
+        target: Option<u16>,
+        shapes: Vec<Shape>,
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            alive: true,
+            health: 100,
+            offset: -3,
+            name: "player".to_string(),
+            target: Some(7),
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(1.5),
+                Shape::Rectangle {
+                    width: 2.,
+                    height: 4.,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let bytes = to_bytes(&sample(), None).unwrap();
+
+        assert_eq!(from_bytes::<Sample>(&bytes, None).unwrap(), sample());
+    }
+
+    #[test]
+    fn packed_is_smaller_than_bincode_test() {
+        let packed = to_bytes(&sample(), None).unwrap();
+        let bincode = bincode::serialize(&sample()).unwrap();
+
+        assert!(packed.len() < bincode.len());
+    }
+
+    #[test]
+    fn quantized_floats_keep_precision_test() {
+        let quantization = Quantization::new(-1024., 1024., 0.01);
+        assert_eq!(quantization.bits(), 18);
+
+        let bytes = to_bytes(&(12.345f32, -1000.5f32), Some(quantization)).unwrap();
+        assert_eq!(bytes.len(), 5);
+
+        let (x, y) = from_bytes::<(f32, f32)>(&bytes, Some(quantization)).unwrap();
+        assert!((x - 12.345).abs() <= 0.005);
+        assert!((y + 1000.5).abs() <= 0.005);
+    }
+
+    #[test]
+    fn quantized_floats_are_clamped_test() {
+        let quantization = Quantization::new(0., 10., 0.5);
+        let bytes = to_bytes(&(-5f32, 50f32), Some(quantization)).unwrap();
+
+        assert_eq!(
+            from_bytes::<(f32, f32)>(&bytes, Some(quantization)).unwrap(),
+            (0., 10.)
+        );
+    }
+
+    struct UnknownLength(Vec<u32>);
+
+    impl Serialize for UnknownLength {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(None)?;
+            for value in self.0.iter() {
+                seq.serialize_element(value)?;
+            }
+            seq.end()
+        }
+    }
+
+    #[test]
+    fn sequence_of_unknown_length_test() {
+        let bytes = to_bytes(&UnknownLength(vec![1, 300, 70000]), None).unwrap();
+
+        assert_eq!(
+            from_bytes::<Vec<u32>>(&bytes, None).unwrap(),
+            vec![1, 300, 70000]
+        );
+    }
+
+    #[test]
+    fn out_of_range_integer_is_an_error_test() {
+        let bytes = to_bytes(&70000u32, None).unwrap();
+
+        assert!(matches!(
+            from_bytes::<u16>(&bytes, None),
+            Err(PackedError::OutOfRange("u16"))
+        ));
+    }
+}
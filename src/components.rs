@@ -2,13 +2,16 @@
 
 use std::ops::{Deref, DerefMut};
 
+use legion::Entity;
 use serde::{Deserialize, Serialize};
 
 use net_sync::{
     track_attr::serde_diff::{self, *},
-    uid::Uid,
+    uid::{Uid, UidAllocator},
 };
 
+use crate::{protocol::ClientId, resources::UidRecycler};
+
 /// A component with a random `UUID`.
 ///
 /// If modifications are serialized we need to know from which component they came.
@@ -50,3 +53,219 @@ impl Default for UidComponent {
 }
 
 crate::register_component_type!(UidComponent);
+
+/// A component that marks which client owns an entity.
+///
+/// Components registered as `owner_only` are only replicated to this client.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Serialize, Deserialize, SerdeDiff)]
+pub struct OwnerComponent {
+    client_id: ClientId,
+}
+
+impl OwnerComponent {
+    pub fn new(client_id: ClientId) -> OwnerComponent {
+        OwnerComponent { client_id }
+    }
+
+    /// Returns the id of the client owning this entity.
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+}
+
+impl Default for OwnerComponent {
+    fn default() -> Self {
+        OwnerComponent { client_id: 0 }
+    }
+}
+
+crate::register_component_type!(OwnerComponent);
+
+/// A marker for the entities the server replicates to the clients.
+///
+/// Only entities with this component get a network id and are sent to the clients.
+/// Adding it to an entity spawns the entity on the clients and removing it despawns the entity and its children there.
+/// The server keeps the network id of an entity the marker is removed from, adding the marker again spawns the entity and its children again.
+/// The marker itself is not replicated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, SerdeDiff)]
+pub struct Replicated {}
+
+impl Replicated {
+    pub fn new() -> Replicated {
+        Replicated {}
+    }
+}
+
+impl Default for Replicated {
+    fn default() -> Self {
+        Replicated::new()
+    }
+}
+
+// Registered, such that snapshots keep the marker.
+crate::register_component_type!(Replicated, server_only);
+
+/// A component that scales how urgent the changes of an entity are when the bandwidth to a client is limited.
+///
+/// This component is not replicated.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Serialize, Deserialize)]
+pub struct PriorityComponent {
+    priority: f32,
+}
+
+impl PriorityComponent {
+    pub fn new(priority: f32) -> PriorityComponent {
+        PriorityComponent { priority }
+    }
+
+    /// Returns the weight of this entity.
+    pub fn priority(&self) -> f32 {
+        self.priority
+    }
+}
+
+impl Default for PriorityComponent {
+    fn default() -> Self {
+        PriorityComponent { priority: 1. }
+    }
+}
+
+/// A reference to a replicated entity, to be used in place of an `Entity` field of a replicated component.
+///
+/// An `Entity` only means something in the world it belongs to, therefore the reference holds the network id of the entity.
+/// It is resolved to the local entity when it is used, such that a reference to an entity
+/// that arrives at the client after the component resolves as soon as the entity is there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, SerdeDiff)]
+#[serde(transparent)]
+pub struct NetworkEntity {
+    entity_id: Uid,
+}
+
+impl NetworkEntity {
+    /// Returns the reference to the given entity, `None` when the entity has no network id.
+    ///
+    /// The server gives an inserted entity with the `Replicated` marker its network id at the next command frame,
+    /// the id is taken back when the entity is removed or loses the marker.
+    /// The live network ids are searched for the entity, hold on to the reference rather than looking it up every time.
+    pub fn of(
+        entity: Entity,
+        allocator: &UidAllocator<Entity>,
+        recycler: &UidRecycler,
+    ) -> Option<NetworkEntity> {
+        recycler
+            .live()
+            .find(|entity_id| *allocator.get_by_val(entity_id) == entity)
+            .map(NetworkEntity::from_entity_id)
+    }
+
+    pub fn from_entity_id(entity_id: Uid) -> NetworkEntity {
+        NetworkEntity { entity_id }
+    }
+
+    /// Returns the network id of the referred entity.
+    pub fn entity_id(&self) -> Uid {
+        self.entity_id
+    }
+
+    /// Returns the local entity, `None` while the entity has not arrived or after it was removed.
+    ///
+    /// A reference to a removed entity does not resolve to the entity that reuses its network id.
+    pub fn resolve(
+        &self,
+        allocator: &UidAllocator<Entity>,
+        recycler: &UidRecycler,
+    ) -> Option<Entity> {
+        if recycler.is_live(self.entity_id) {
+            Some(*allocator.get_by_val(&self.entity_id))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for NetworkEntity {
+    /// Refers to no entity, the zero network id is never given out.
+    fn default() -> Self {
+        NetworkEntity { entity_id: 0 }
+    }
+}
+
+/// A component that makes an entity the child of another replicated entity.
+///
+/// The server sends the insert of a parent before the inserts of its children.
+/// A child is only replicated along with its parent, and only to the clients that are sent its parent:
+/// removing a parent removes its children on the clients, while the server keeps them until their parent is replicated again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, SerdeDiff)]
+pub struct ParentComponent {
+    parent: NetworkEntity,
+}
+
+impl ParentComponent {
+    pub fn new(parent: NetworkEntity) -> ParentComponent {
+        ParentComponent { parent }
+    }
+
+    /// Returns the reference to the parent entity.
+    pub fn parent(&self) -> NetworkEntity {
+        self.parent
+    }
+}
+
+impl Default for ParentComponent {
+    fn default() -> Self {
+        ParentComponent {
+            parent: NetworkEntity::default(),
+        }
+    }
+}
+
+crate::register_component_type!(ParentComponent);
+
+#[cfg(test)]
+pub mod test {
+    use legion::{Entity, World};
+
+    use net_sync::uid::UidAllocator;
+
+    use crate::{components::NetworkEntity, resources::UidRecycler};
+
+    #[test]
+    fn reference_resolves_once_entity_arrived_test() {
+        let mut server = UidRecycler::new();
+        let mut client = UidRecycler::new();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut world = World::default();
+
+        let reference = NetworkEntity::from_entity_id(server.allocate().unwrap());
+        assert_eq!(reference.resolve(&allocator, &client), None);
+
+        let entity = world.push((0u8,));
+        allocator.allocate(entity, Some(reference.entity_id()));
+        client.claim(reference.entity_id());
+        assert_eq!(reference.resolve(&allocator, &client), Some(entity));
+
+        client.release(reference.entity_id());
+        assert_eq!(reference.resolve(&allocator, &client), None);
+        assert_eq!(NetworkEntity::default().resolve(&allocator, &client), None);
+    }
+
+    #[test]
+    fn entity_without_network_id_has_no_reference_test() {
+        let mut recycler = UidRecycler::new();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut world = World::default();
+
+        let entity = world.push((0u8,));
+        assert_eq!(NetworkEntity::of(entity, &allocator, &recycler), None);
+
+        let entity_id = recycler.allocate().unwrap();
+        allocator.allocate(entity, Some(entity_id));
+        assert_eq!(
+            NetworkEntity::of(entity, &allocator, &recycler),
+            Some(NetworkEntity::from_entity_id(entity_id))
+        );
+
+        recycler.release(entity_id);
+        assert_eq!(NetworkEntity::of(entity, &allocator, &recycler), None);
+    }
+}
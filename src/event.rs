@@ -4,7 +4,11 @@ use crossbeam_channel::Receiver;
 use legion::Entity;
 use serde::export::{fmt::Error, Formatter};
 
-use crate::{resources::RegisteredComponentsResource, world::WorldAbstraction};
+use crate::{
+    protocol::{ClientId, DisconnectReason},
+    resources::RegisteredComponentsResource,
+    world::WorldAbstraction,
+};
 use legion::world::Event;
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
@@ -209,6 +213,67 @@ impl LegionEventHandler {
     }
 }
 
+/// Events about the synchronisation that legion-sync reports next to the events of the transport.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncEvent {
+    /// State updates never arrived, the client requested a fresh initial state sync and applies no updates until it arrived.
+    StateUpdatesLost {
+        /// The sequence of the first missing state update.
+        first: u16,
+        count: u16,
+    },
+    /// A state update arrived after a newer state update was applied and has been dropped.
+    StaleStateUpdate { sequence: u16 },
+    /// A state update arrived twice and the copy has been dropped.
+    DuplicateStateUpdate { sequence: u16 },
+    /// Nothing arrived from the server for longer than the reconnect timeout.
+    ConnectionLost,
+    /// The server no longer serves this client, it does not reconnect.
+    Disconnected { reason: DisconnectReason },
+    /// A new connection to the server has been made after the connection was lost.
+    Reconnected { attempts: u32 },
+    /// A client connected to the server and has been accepted.
+    ClientConnected { client_id: ClientId },
+    /// The connection of an accepted client dropped, it can still resume its session.
+    ClientDisconnected { client_id: ClientId },
+    /// The session of a disconnected client expired, the entities it owned have been removed.
+    SessionExpired { client_id: ClientId },
+    /// A reconnected client resumed the session of its previous connection on the server.
+    SessionResumed {
+        client_id: ClientId,
+        previous_client_id: ClientId,
+    },
+}
+
+/// The synchronisation events of the last ticks, drained by the user.
+pub struct SyncEventQueue {
+    events: Vec<SyncEvent>,
+}
+
+impl SyncEventQueue {
+    pub fn new() -> SyncEventQueue {
+        SyncEventQueue { events: Vec::new() }
+    }
+
+    pub fn push(&mut self, event: SyncEvent) {
+        self.events.push(event);
+    }
+
+    pub fn drain(&mut self) -> std::vec::Drain<'_, SyncEvent> {
+        self.events.drain(..)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl Default for SyncEventQueue {
+    fn default() -> Self {
+        SyncEventQueue::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     struct Component;
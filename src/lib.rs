@@ -1,3 +1,4 @@
+pub mod codec;
 pub mod components;
 pub mod error;
 pub mod resources;
@@ -5,6 +6,8 @@ pub mod systems;
 #[macro_use]
 pub mod register;
 pub mod event;
+pub mod protocol;
+pub mod recording;
 pub mod world;
 
 pub mod tracking {
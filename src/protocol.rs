@@ -0,0 +1,417 @@
+//! Messages that legion-sync exchanges on top of the user defined messages.
+//!
+//! The transport only knows about state updates, initial state syncs, commands and user messages.
+//! Everything legion-sync needs on top of that travels in the user message slot of the transport,
+//! including the state updates as they need a sequence number the transport does not provide,
+//! wrapped in either a [ServerMessage](ServerMessage) or a [ClientMessage](ClientMessage).
+
+use std::{
+    fmt::{self, Display, Formatter},
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use net_sync::{
+    synchronisation::{CommandFrame, NetworkCommand, NetworkMessage},
+    transport::{self, PostBox, PostOffice},
+    uid::Uid,
+};
+
+use crate::{
+    codec::WireCodec,
+    resources::{RoundTripTimes, RttEstimator},
+};
+
+/// The identifier the transport gives to a connected client.
+pub type ClientId = usize;
+
+/// Identifies a message type on the wire, it is given when the type is registered with [register_message_type](register_message_type),
+/// see [MessageRegister::by_kind](crate::register::MessageRegister::by_kind).
+pub type MessageKind = u16;
+
+/// Identifies the session of a client across reconnections, the transport gives a reconnected client a new id.
+pub type SessionToken = u64;
+
+/// Why the server stopped serving a client.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// A connect hook did not accept the client.
+    Rejected(String),
+    Kicked(String),
+    /// The session of the client is banned, it cannot come back with it.
+    Banned(String),
+    /// The server shuts down.
+    Shutdown(String),
+    /// The client cannot decode the data of the server.
+    CodecMismatch {
+        server: WireCodec,
+        client: WireCodec,
+    },
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Rejected(reason) => write!(fmt, "Rejected: {}", reason),
+            DisconnectReason::Kicked(reason) => write!(fmt, "Kicked: {}", reason),
+            DisconnectReason::Banned(reason) => write!(fmt, "Banned: {}", reason),
+            DisconnectReason::Shutdown(reason) => write!(fmt, "Server shutdown: {}", reason),
+            DisconnectReason::CodecMismatch { server, client } => write!(
+                fmt,
+                "The server encodes with {:?} while the client decodes with {:?}",
+                server, client
+            ),
+        }
+    }
+}
+
+/// The data or the difference of a registered resource, encoded with the codec of the server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResourceState {
+    /// The kind the resource type is registered with, see [ResourceRegister::by_kind](crate::register::ResourceRegister::by_kind).
+    pub kind: MessageKind,
+    pub data: Vec<u8>,
+}
+
+/// Message send from the server to a client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ServerMessage<M> {
+    /// A user defined message.
+    User(M),
+    /// Greets a new client before its initial state sync.
+    Welcome {
+        /// The codec the server encodes its data with.
+        codec: WireCodec,
+        /// The sequence of the state update the initial state sync corresponds to.
+        state_update_sequence: u16,
+        /// The session of the client, a reconnected client sends it back to resume the session.
+        session: SessionToken,
+        /// The data of the replicated resources.
+        resources: Vec<ResourceState>,
+    },
+    /// The changes of a command frame, the wrapping sequence lets the client put the updates in order.
+    StateUpdate {
+        sequence: u16,
+        /// The highest command frame the server received commands for from this client.
+        command_ack: Option<CommandFrame>,
+        /// The world state encoded with the codec of the server, like the initial state sync.
+        state: Vec<u8>,
+        /// The differences of the replicated resources that changed.
+        resources: Vec<ResourceState>,
+    },
+    /// Asks the client to answer with a pong carrying the same id.
+    Ping(u16),
+    /// Answers the client ping with the given id.
+    Pong(u16),
+    /// The last message to a client the server no longer serves.
+    Disconnect(DisconnectReason),
+    /// A message of a registered message type, encoded with the codec of the server.
+    Typed { kind: MessageKind, data: Vec<u8> },
+    /// An event of a registered entity event type addressed to a replicated entity.
+    EntityEvent {
+        /// The kind the event type is registered with, see [EntityEventRegister::by_kind](crate::register::EntityEventRegister::by_kind).
+        kind: MessageKind,
+        entity_id: Uid,
+        /// The command frame of the state update the event is delivered after.
+        command_frame: CommandFrame,
+        data: Vec<u8>,
+    },
+}
+
+/// Message send from a client to the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientMessage<M> {
+    /// A user defined message.
+    User(M),
+    /// Acknowledges that the state update for the given command frame has been applied.
+    StateAck(CommandFrame),
+    /// The first message of a connection, the server welcomes the client once it arrived.
+    ///
+    /// A reconnected client sends the session of its earlier connection along to resume it.
+    Hello { session: Option<SessionToken> },
+    /// State updates were lost, asks for a welcome and a fresh initial state sync.
+    RequestSync,
+    /// The command frames of the commands sent along in the same tick,
+    /// the server acknowledges the commands up to the first one it did not receive.
+    CommandFrames(Vec<CommandFrame>),
+    /// The client leaves and does not come back with its session.
+    Disconnect(DisconnectReason),
+    /// Asks the server to answer with a pong carrying the same id.
+    Ping(u16),
+    /// Answers the server ping with the given id.
+    Pong(u16),
+    /// A message of a registered message type, encoded with the codec of the server.
+    Typed { kind: MessageKind, data: Vec<u8> },
+}
+
+impl<M: NetworkMessage> NetworkMessage for ServerMessage<M> {}
+
+impl<M: NetworkMessage> NetworkMessage for ClientMessage<M> {}
+
+/// A message on its way from the server world to a client world.
+pub type ServerToClient<ServerToClientMessage> =
+    transport::ServerToClientMessage<ServerMessage<ServerToClientMessage>>;
+
+/// A message or command on its way from a client world to the server world.
+pub type ClientToServer<ClientToServerMessage, ClientToServerCommand> =
+    transport::ClientToServerMessage<ClientMessage<ClientToServerMessage>, ClientToServerCommand>;
+
+/// The post office used by the server world,
+/// it dereferences to the post office of the transport and measures the round-trip time of each client.
+///
+/// The TCP systems answer pings and stamp pongs the moment they arrive,
+/// such that the round-trip times do not include the time the messages wait for the next command frame.
+/// A client that is no longer served is sent the reason, its connection stays open until the client or the server world drops it.
+pub struct ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    post_office: PostOffice<
+        ServerMessage<ServerToClientMessage>,
+        ClientMessage<ClientToServerMessage>,
+        ClientToServerCommand,
+    >,
+    round_trip_times: RoundTripTimes,
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+    ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    pub fn new() -> Self {
+        ServerPostOffice {
+            post_office: PostOffice::new(),
+            round_trip_times: RoundTripTimes::new(),
+        }
+    }
+
+    /// Sends the reason to the client as its last message.
+    pub(crate) fn disconnect(&mut self, client_id: ClientId, reason: DisconnectReason) {
+        if let Some((_, client)) = self.post_office.clients_mut().find(|x| *x.0 == client_id) {
+            client
+                .postbox_mut()
+                .send(transport::ServerToClientMessage::Message(
+                    ServerMessage::Disconnect(reason),
+                ));
+        }
+    }
+
+    /// Returns the smoothed round-trip time of the given client, `None` until it has been measured.
+    pub fn round_trip_time(&self, client_id: ClientId) -> Option<Duration> {
+        self.round_trip_times
+            .client(client_id)
+            .and_then(|estimator| estimator.rtt())
+    }
+
+    /// Returns the round-trip time estimators of the connected clients.
+    pub fn round_trip_times(&self) -> &RoundTripTimes {
+        &self.round_trip_times
+    }
+
+    /// Answers the pings and handles the pongs among the received messages, returns the other messages.
+    pub(crate) fn exchange_pings(
+        &mut self,
+        messages: Vec<(
+            ClientId,
+            ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+        )>,
+        now: Instant,
+    ) -> Vec<(
+        ClientId,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    )> {
+        let mut other = Vec::with_capacity(messages.len());
+
+        for (id, message) in messages {
+            match message {
+                transport::ClientToServerMessage::Message(ClientMessage::Ping(ping)) => {
+                    if let Some((_, client)) = self.post_office.clients_mut().find(|x| *x.0 == id) {
+                        client
+                            .postbox_mut()
+                            .send(transport::ServerToClientMessage::Message(
+                                ServerMessage::Pong(ping),
+                            ));
+                    }
+                }
+                transport::ClientToServerMessage::Message(ClientMessage::Pong(ping)) => {
+                    self.round_trip_times.client_mut(id).pong(ping, now);
+                }
+                message => other.push((id, message)),
+            }
+        }
+
+        other
+    }
+
+    /// Pings the clients that are due for a ping and forgets the clients that left.
+    pub(crate) fn send_pings(&mut self, now: Instant) {
+        let round_trip_times = &mut self.round_trip_times;
+        let post_office = &mut self.post_office;
+
+        round_trip_times.retain_clients(|id| post_office.clients().any(|x| x.0 == id));
+
+        for (id, client) in post_office.clients_mut() {
+            if let Some(ping) = round_trip_times.client_mut(*id).ping(now) {
+                client
+                    .postbox_mut()
+                    .send(transport::ServerToClientMessage::Message(
+                        ServerMessage::Ping(ping),
+                    ));
+            }
+        }
+    }
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand> Deref
+    for ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    type Target = PostOffice<
+        ServerMessage<ServerToClientMessage>,
+        ClientMessage<ClientToServerMessage>,
+        ClientToServerCommand,
+    >;
+
+    fn deref(&self) -> &Self::Target {
+        &self.post_office
+    }
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand> DerefMut
+    for ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.post_office
+    }
+}
+
+/// The post box used by the client world,
+/// it dereferences to the post box of the transport and measures the round-trip time to the server.
+///
+/// A new post box is created for every connection, so a reconnected client measures its new link from scratch.
+pub struct ClientPostBox<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    postbox: PostBox<
+        ServerToClient<ServerToClientMessage>,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    >,
+    rtt: RttEstimator,
+    /// When the latest message arrived, until the client world takes it.
+    received: Option<Instant>,
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+    ClientPostBox<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    pub fn new() -> Self {
+        ClientPostBox {
+            postbox: PostBox::new(),
+            rtt: RttEstimator::new(),
+            received: None,
+        }
+    }
+
+    /// Returns the smoothed round-trip time to the server, `None` until it has been measured.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.rtt.rtt()
+    }
+
+    /// Returns the round-trip time estimator of the connection to the server.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Answers the pings and handles the pongs among the received messages, returns the other messages.
+    pub(crate) fn exchange_pings(
+        &mut self,
+        messages: Vec<ServerToClient<ServerToClientMessage>>,
+        now: Instant,
+    ) -> Vec<ServerToClient<ServerToClientMessage>> {
+        let mut other = Vec::with_capacity(messages.len());
+
+        if !messages.is_empty() {
+            self.received = Some(now);
+        }
+
+        for message in messages {
+            match message {
+                transport::ServerToClientMessage::Message(ServerMessage::Ping(ping)) => {
+                    self.postbox.send(transport::ClientToServerMessage::Message(
+                        ClientMessage::Pong(ping),
+                    ));
+                }
+                transport::ServerToClientMessage::Message(ServerMessage::Pong(ping)) => {
+                    self.rtt.pong(ping, now);
+                }
+                message => other.push(message),
+            }
+        }
+
+        other
+    }
+
+    /// Returns when a message arrived since the previous call, pings and pongs included.
+    pub(crate) fn take_received(&mut self) -> Option<Instant> {
+        self.received.take()
+    }
+
+    /// Pings the server when a ping is due.
+    pub(crate) fn send_ping(&mut self, now: Instant) {
+        if let Some(ping) = self.rtt.ping(now) {
+            self.postbox.send(transport::ClientToServerMessage::Message(
+                ClientMessage::Ping(ping),
+            ));
+        }
+    }
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand> Deref
+    for ClientPostBox<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    type Target = PostBox<
+        ServerToClient<ServerToClientMessage>,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    >;
+
+    fn deref(&self) -> &Self::Target {
+        &self.postbox
+    }
+}
+
+impl<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand> DerefMut
+    for ClientPostBox<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>
+where
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.postbox
+    }
+}
@@ -0,0 +1,489 @@
+//! Recording of the replication stream, for bug reproduction, replays and offline analysis.
+//!
+//! A recording starts with a header that carries the codec and the component manifest of the recording world,
+//! followed by length framed records of every welcome, initial state sync, state update and entity event
+//! with the time since the recording started.
+//! The server records what it sends to each client, the client records what it receives before the updates are put in order.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use net_sync::{
+    synchronisation::{CommandFrame, WorldState},
+    uid::Uid,
+};
+
+use crate::{
+    codec::{CodecError, WireCodec},
+    protocol::{ClientId, MessageKind, ResourceState},
+    resources::RegisteredComponentsResource,
+};
+
+/// The bytes every recording starts with.
+const MAGIC: [u8; 4] = *b"LSRC";
+/// The version of the recording format.
+pub const RECORDING_VERSION: u16 = 3;
+/// The codec the header and the records are written with.
+const RECORDING_CODEC: WireCodec = WireCodec::BincodeVarint;
+
+/// Errors that can occur while writing or reading a recording.
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(io::Error),
+    Codec(CodecError),
+    /// The data does not start with the recording magic bytes.
+    NotARecording,
+    UnsupportedVersion(u16),
+    /// A component of the recording is not registered with the same id in the world that plays it back.
+    UnknownComponent(ManifestEntry),
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Io(e) => write!(fmt, "IO error occurred: {:?}", e),
+            RecordingError::Codec(e) => write!(fmt, "Codec error occurred: {}", e),
+            RecordingError::NotARecording => write!(fmt, "The data is not a recording."),
+            RecordingError::UnsupportedVersion(version) => {
+                write!(fmt, "Recording version {} is not supported.", version)
+            }
+            RecordingError::UnknownComponent(entry) => write!(
+                fmt,
+                "Component {} with id {} of the recording is not registered with that id.",
+                entry.type_name, entry.uid
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+impl From<io::Error> for RecordingError {
+    fn from(error: io::Error) -> Self {
+        RecordingError::Io(error)
+    }
+}
+
+impl From<CodecError> for RecordingError {
+    fn from(error: CodecError) -> Self {
+        RecordingError::Codec(error)
+    }
+}
+
+/// A registered component as known to the recording world.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub uid: Uid,
+    pub type_name: String,
+}
+
+/// The header of a recording.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    /// The codec the initial state syncs and component data are encoded with.
+    pub codec: WireCodec,
+    /// The registered components, the component ids in the recorded states refer to these.
+    pub components: Vec<ManifestEntry>,
+}
+
+impl RecordingHeader {
+    pub fn new(codec: WireCodec, components: &RegisteredComponentsResource) -> RecordingHeader {
+        RecordingHeader {
+            codec,
+            components: components
+                .slice_with_uid()
+                .iter()
+                .map(|(uid, registration)| ManifestEntry {
+                    uid: *uid,
+                    type_name: registration.type_name().to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A recorded message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RecordedMessage {
+    /// The data of the replicated resources the welcome before an initial state sync carried.
+    /// The sequence is the sequence of the state update the following initial state corresponds to.
+    Welcome {
+        sequence: u16,
+        resources: Vec<ResourceState>,
+    },
+    /// The encoded initial state, decode it with [decode_state](SessionReader::decode_state).
+    /// The sequence is the sequence of the state update the initial state corresponds to.
+    InitialStateSync { sequence: u16, state: Vec<u8> },
+    /// The encoded state update, decode it with [decode_state](SessionReader::decode_state),
+    /// along with the differences of the replicated resources.
+    StateUpdate {
+        sequence: u16,
+        state: Vec<u8>,
+        resources: Vec<ResourceState>,
+    },
+    /// An event of a registered entity event type, delivered after the state update of its command frame.
+    EntityEvent {
+        kind: MessageKind,
+        entity_id: Uid,
+        command_frame: CommandFrame,
+        data: Vec<u8>,
+    },
+}
+
+/// A recorded message with the time since the recording started.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub elapsed: Duration,
+    /// The client the message was sent to, `None` when recorded by a client.
+    pub client_id: Option<ClientId>,
+    pub message: RecordedMessage,
+}
+
+// Borrowed counterparts of the records, they serialize to the same bytes.
+#[derive(Serialize)]
+enum RecordedMessageRef<'a> {
+    Welcome {
+        sequence: u16,
+        resources: &'a [ResourceState],
+    },
+    InitialStateSync {
+        sequence: u16,
+        state: &'a [u8],
+    },
+    StateUpdate {
+        sequence: u16,
+        state: &'a [u8],
+        resources: &'a [ResourceState],
+    },
+    EntityEvent {
+        kind: MessageKind,
+        entity_id: Uid,
+        command_frame: CommandFrame,
+        data: &'a [u8],
+    },
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    elapsed: Duration,
+    client_id: Option<ClientId>,
+    message: RecordedMessageRef<'a>,
+}
+
+/// Writes the replication stream of a world to a recording.
+///
+/// The world builders create one with `with_recording`, which writes the header when the world is built.
+pub struct SessionRecorder {
+    writer: Box<dyn Write + Send + Sync>,
+    started: Instant,
+    records: usize,
+}
+
+impl SessionRecorder {
+    /// Writes the header and starts the clock of the recording.
+    pub fn new(
+        writer: impl Write + Send + Sync + 'static,
+        header: &RecordingHeader,
+    ) -> Result<SessionRecorder, RecordingError> {
+        let mut recorder = SessionRecorder {
+            writer: Box::new(writer),
+            started: Instant::now(),
+            records: 0,
+        };
+
+        recorder.writer.write_all(&MAGIC)?;
+        recorder
+            .writer
+            .write_all(&RECORDING_VERSION.to_le_bytes())?;
+        recorder.write_frame(&RECORDING_CODEC.serialize(header)?)?;
+
+        Ok(recorder)
+    }
+
+    /// Returns the number of recorded messages.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecordingError> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub(crate) fn record_welcome(
+        &mut self,
+        client_id: Option<ClientId>,
+        sequence: u16,
+        resources: &[ResourceState],
+    ) -> Result<(), RecordingError> {
+        self.record(
+            client_id,
+            RecordedMessageRef::Welcome {
+                sequence,
+                resources,
+            },
+        )
+    }
+
+    pub(crate) fn record_initial_state_sync(
+        &mut self,
+        client_id: Option<ClientId>,
+        sequence: u16,
+        state: &[u8],
+    ) -> Result<(), RecordingError> {
+        self.record(
+            client_id,
+            RecordedMessageRef::InitialStateSync { sequence, state },
+        )
+    }
+
+    pub(crate) fn record_state_update(
+        &mut self,
+        client_id: Option<ClientId>,
+        sequence: u16,
+        state: &[u8],
+        resources: &[ResourceState],
+    ) -> Result<(), RecordingError> {
+        self.record(
+            client_id,
+            RecordedMessageRef::StateUpdate {
+                sequence,
+                state,
+                resources,
+            },
+        )
+    }
+
+    pub(crate) fn record_entity_event(
+        &mut self,
+        client_id: Option<ClientId>,
+        kind: MessageKind,
+        entity_id: Uid,
+        command_frame: CommandFrame,
+        data: &[u8],
+    ) -> Result<(), RecordingError> {
+        self.record(
+            client_id,
+            RecordedMessageRef::EntityEvent {
+                kind,
+                entity_id,
+                command_frame,
+                data,
+            },
+        )
+    }
+
+    fn record(
+        &mut self,
+        client_id: Option<ClientId>,
+        message: RecordedMessageRef<'_>,
+    ) -> Result<(), RecordingError> {
+        let bytes = RECORDING_CODEC.serialize(&RecordRef {
+            elapsed: self.started.elapsed(),
+            client_id,
+            message,
+        })?;
+
+        self.write_frame(&bytes)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), RecordingError> {
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Reads the records of a recording.
+pub struct SessionReader<R> {
+    reader: R,
+    header: RecordingHeader,
+}
+
+impl<R: Read> SessionReader<R> {
+    /// Reads the header of the recording.
+    pub fn new(mut reader: R) -> Result<SessionReader<R>, RecordingError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if magic != MAGIC {
+            return Err(RecordingError::NotARecording);
+        }
+
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+
+        if version != RECORDING_VERSION {
+            return Err(RecordingError::UnsupportedVersion(version));
+        }
+
+        let header = match read_frame(&mut reader)? {
+            Some(bytes) => RECORDING_CODEC.deserialize(&bytes)?,
+            None => return Err(RecordingError::NotARecording),
+        };
+
+        Ok(SessionReader { reader, header })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    /// Reads the next record, `None` at the end of the recording.
+    pub fn next_record(&mut self) -> Result<Option<Record>, RecordingError> {
+        match read_frame(&mut self.reader)? {
+            Some(bytes) => Ok(Some(RECORDING_CODEC.deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes the state of a recorded initial state sync or state update with the codec of the recording.
+    pub fn decode_state(&self, bytes: &[u8]) -> Result<WorldState, RecordingError> {
+        Ok(self.header.codec.deserialize(bytes)?)
+    }
+}
+
+impl<R: Read> Iterator for SessionReader<R> {
+    type Item = Result<Record, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+// Read a length framed block, `None` when the reader ends before the next frame.
+fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, RecordingError> {
+    let mut length = [0; 4];
+
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut bytes = vec![0; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{
+        io::{self, Cursor, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use net_sync::synchronisation::WorldState;
+
+    use crate::{
+        codec::WireCodec,
+        protocol::ResourceState,
+        recording::{
+            RecordedMessage, RecordingError, RecordingHeader, SessionReader, SessionRecorder,
+        },
+    };
+
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn header() -> RecordingHeader {
+        RecordingHeader {
+            codec: WireCodec::BincodeFixint,
+            components: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn recording_round_trip_test() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = SessionRecorder::new(buffer.clone(), &header()).unwrap();
+
+        let initial = WireCodec::BincodeFixint
+            .serialize(&WorldState::new(3))
+            .unwrap();
+        recorder
+            .record_initial_state_sync(Some(1), 6, &initial)
+            .unwrap();
+        let update = WireCodec::BincodeFixint
+            .serialize(&WorldState::new(4))
+            .unwrap();
+        let resources = vec![ResourceState {
+            kind: 0,
+            data: vec![1, 2],
+        }];
+        recorder
+            .record_state_update(Some(1), 7, &update, &resources)
+            .unwrap();
+        recorder
+            .record_entity_event(Some(1), 2, 9, 4, &[3])
+            .unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut reader = SessionReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header(), &header());
+
+        match reader.next_record().unwrap().unwrap().message {
+            RecordedMessage::InitialStateSync { sequence, state } => {
+                assert_eq!(sequence, 6);
+                assert_eq!(reader.decode_state(&state).unwrap().command_frame, 3);
+            }
+            _ => panic!("Expected an initial state sync."),
+        }
+
+        let record = reader.next_record().unwrap().unwrap();
+        assert_eq!(record.client_id, Some(1));
+        match record.message {
+            RecordedMessage::StateUpdate {
+                sequence,
+                state,
+                resources: recorded_resources,
+            } => {
+                assert_eq!(sequence, 7);
+                assert_eq!(reader.decode_state(&state).unwrap().command_frame, 4);
+                assert_eq!(recorded_resources, resources);
+            }
+            _ => panic!("Expected a state update."),
+        }
+
+        match reader.next_record().unwrap().unwrap().message {
+            RecordedMessage::EntityEvent {
+                kind,
+                entity_id,
+                command_frame,
+                data,
+            } => {
+                assert_eq!((kind, entity_id, command_frame), (2, 9, 4));
+                assert_eq!(data, vec![3]);
+            }
+            _ => panic!("Expected an entity event."),
+        }
+
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn other_data_is_rejected_test() {
+        match SessionReader::new(Cursor::new(b"not a recording".to_vec())) {
+            Err(RecordingError::NotARecording) => {}
+            _ => panic!("Expected the data to be rejected."),
+        }
+    }
+}
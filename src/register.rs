@@ -1,13 +1,18 @@
-use std::{any::TypeId, collections::HashMap};
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
 
 use legion::{
     storage::{ComponentMeta, ComponentTypeId},
     systems::SystemBuilder,
     world::{EntityStore, SubWorld, World},
-    Entity,
+    Entity, Resources,
 };
 
 use serde::{
+    de::DeserializeOwned,
     export::{
         fmt::{Debug, Error},
         Formatter,
@@ -22,16 +27,167 @@ use net_sync::{
     uid::{Uid, UidAllocator},
 };
 
+use crate::{
+    codec::{Encoding, Quantization},
+    protocol::{ClientId, MessageKind},
+    resources::{EntityEventInbox, EntityEventOutbox, Inbox, MessageTarget, Outbox},
+};
+
 inventory::collect!(ComponentRegistration);
+inventory::collect!(MessageRegistration);
+inventory::collect!(EntityEventRegistration);
+inventory::collect!(ResourceRegistration);
 
 pub type ComponentRegistrationRef = &'static ComponentRegistration;
 pub type HashmapRegistry = HashMap<ComponentTypeId, ComponentRegistrationRef>;
 
+/// How the changes of a component should be delivered to the clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Every change must arrive.
+    Reliable,
+    /// A change deferred by the bandwidth budget is superseded by a later change of the same component.
+    Unreliable,
+}
+
+/// How the client treats the server state of a component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Simulation {
+    /// The client predicts the component and only corrects it when the server disagrees.
+    Predicted,
+    /// The client takes the server state as is.
+    Interpolated,
+}
+
+/// How the server finds out that a component changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tracking {
+    /// Only the changes made through the `#[sync]` tracking proxy are replicated.
+    Manual,
+    /// The server compares the component with the state it sent last, every command frame.
+    Automatic,
+}
+
+/// Describes how a registered component is replicated to the clients.
+///
+/// The settings are passed to [register_component_type](register_component_type) after the component type:
+///
+/// ```ignore
+/// register_component_type!(Health);
+/// register_component_type!(Inventory, owner_only, priority(2.0), max_update_rate(10.0));
+/// register_component_type!(AiState, server_only);
+/// register_component_type!(Position, quantize(-1024.0, 1024.0, 0.01));
+/// register_component_type!(Velocity, automatic);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplicationSettings {
+    /// The component is never replicated.
+    pub server_only: bool,
+    /// The component is only replicated to the client named in the `OwnerComponent` of the entity.
+    pub owner_only: bool,
+    pub delivery: Delivery,
+    pub simulation: Simulation,
+    /// Weight of the component when deciding which changes to send first.
+    pub priority: f32,
+    /// The maximum number of updates per second, `None` sends every change.
+    pub max_update_rate: Option<f32>,
+    /// How the data and differences of the component are encoded.
+    pub encoding: Encoding,
+    pub tracking: Tracking,
+}
+
+impl ReplicationSettings {
+    pub fn server_only(mut self) -> Self {
+        self.server_only = true;
+        self
+    }
+
+    pub fn owner_only(mut self) -> Self {
+        self.owner_only = true;
+        self
+    }
+
+    pub fn reliable(mut self) -> Self {
+        self.delivery = Delivery::Reliable;
+        self
+    }
+
+    pub fn unreliable(mut self) -> Self {
+        self.delivery = Delivery::Unreliable;
+        self
+    }
+
+    pub fn predicted(mut self) -> Self {
+        self.simulation = Simulation::Predicted;
+        self
+    }
+
+    pub fn interpolated(mut self) -> Self {
+        self.simulation = Simulation::Interpolated;
+        self
+    }
+
+    pub fn priority(mut self, priority: f32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn max_update_rate(mut self, updates_per_second: f32) -> Self {
+        self.max_update_rate = Some(updates_per_second);
+        self
+    }
+
+    /// Bit-packs the component data with variable length integers.
+    pub fn packed(mut self) -> Self {
+        self.encoding = Encoding::Packed(None);
+        self
+    }
+
+    /// Bit-packs the component data and quantizes its floats to the given range and precision.
+    pub fn quantize(mut self, min: f32, max: f32, precision: f32) -> Self {
+        self.encoding = Encoding::Packed(Some(Quantization::new(min, max, precision)));
+        self
+    }
+
+    pub fn manual(mut self) -> Self {
+        self.tracking = Tracking::Manual;
+        self
+    }
+
+    /// Detects the changes of the component without the tracking proxy, at the cost of comparing it every command frame.
+    pub fn automatic(mut self) -> Self {
+        self.tracking = Tracking::Automatic;
+        self
+    }
+
+    /// Returns the minimal time between two updates of the component.
+    pub fn update_interval(&self) -> Option<Duration> {
+        self.max_update_rate
+            .map(|rate| Duration::from_secs_f32(1. / rate))
+    }
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        ReplicationSettings {
+            server_only: false,
+            owner_only: false,
+            delivery: Delivery::Reliable,
+            simulation: Simulation::Predicted,
+            priority: 1.,
+            max_update_rate: None,
+            encoding: Encoding::Codec,
+            tracking: Tracking::Manual,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ComponentRegistration {
     pub(crate) component_type_id: ComponentTypeId,
     pub(crate) meta: ComponentMeta,
     pub(crate) type_name: &'static str,
+    pub(crate) replication: ReplicationSettings,
 
     pub(crate) components_clone: fn(*const u8, *mut u8, usize),
 
@@ -97,6 +253,15 @@ impl ComponentRegistration {
         self.type_name
     }
 
+    pub fn replication(&self) -> &ReplicationSettings {
+        &self.replication
+    }
+
+    pub fn with_replication(mut self, replication: ReplicationSettings) -> Self {
+        self.replication = replication;
+        self
+    }
+
     pub fn exists_in_subworld(&self, world: &SubWorld, entity: Entity) -> bool {
         (self.exists_in_subworld)(world, entity)
     }
@@ -186,6 +351,7 @@ impl ComponentRegistration {
             component_type_id: ComponentTypeId::of::<T>(),
             meta: ComponentMeta::of::<T>(),
             type_name: std::any::type_name::<T>(),
+            replication: ReplicationSettings::default(),
             components_clone: move |src, dst, num_components| unsafe {
                 for i in 0..num_components {
                     let src_ptr = (src as *const T).add(i);
@@ -319,9 +485,577 @@ impl ComponentRegister {
 
 #[macro_export]
 macro_rules! register_component_type {
-    ($component_type:ty) => {
+    ($component_type:ty $(, $setting:ident $(($($argument:expr),*))?)* $(,)?) => {
         inventory::submit! {
              $crate::register::ComponentRegistration::of::<$component_type>()
+                .with_replication(
+                    $crate::register::ReplicationSettings::default()
+                        $(.$setting($($($argument),*)?))*
+                )
+        }
+    };
+}
+
+/// A registration that is identified on the wire by the kind it was registered with.
+pub trait KindRegistration: Sync + 'static {
+    fn kind(&self) -> MessageKind;
+
+    fn type_name(&self) -> &'static str;
+}
+
+/// The registrations of a registry by their kind, built once and kept as a resource.
+///
+/// The kind is given at registration, such that a server and a client built differently agree on it.
+pub struct KindRegistry<R: KindRegistration> {
+    by_kind: BTreeMap<MessageKind, &'static R>,
+}
+
+impl<R: KindRegistration> KindRegistry<R> {
+    /// Panics when two registrations have the same kind.
+    pub fn new(registrations: impl Iterator<Item = &'static R>) -> KindRegistry<R> {
+        let mut by_kind = BTreeMap::new();
+
+        for registration in registrations {
+            if let Some(other) = by_kind.insert(registration.kind(), registration) {
+                panic!(
+                    "{} and {} are registered with the same kind {}.",
+                    other.type_name(),
+                    registration.type_name(),
+                    registration.kind()
+                );
+            }
+        }
+
+        KindRegistry { by_kind }
+    }
+
+    /// Returns the registration of the kind, `None` when nothing is registered with it.
+    pub fn get(&self, kind: MessageKind) -> Option<&'static R> {
+        self.by_kind.get(&kind).cloned()
+    }
+
+    /// Returns the registrations ordered by their kind.
+    pub fn iter(&self) -> impl Iterator<Item = (MessageKind, &'static R)> + '_ {
+        self.by_kind
+            .iter()
+            .map(|(kind, registration)| (*kind, *registration))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_kind.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_kind.is_empty()
+    }
+}
+
+pub type MessageRegistrationRef = &'static MessageRegistration;
+/// The registered message types by their kind, see [MessageRegister::by_kind](MessageRegister::by_kind).
+pub type RegisteredMessagesResource = KindRegistry<MessageRegistration>;
+
+/// A message type that is exchanged between the server and the clients next to the component state.
+///
+/// Each registered type gets an [Inbox](Inbox) and an [Outbox](Outbox) resource,
+/// such that systems read the received messages of a type and send messages of it.
+#[derive(Clone)]
+pub struct MessageRegistration {
+    pub(crate) ty: TypeId,
+    pub(crate) type_name: &'static str,
+    pub(crate) kind: MessageKind,
+
+    pub(crate) insert_resources: fn(resources: &mut Resources),
+
+    pub(crate) receive: fn(
+        resources: &Resources,
+        sender: Option<ClientId>,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error>,
+
+    pub(crate) drain_outbox: fn(
+        resources: &Resources,
+        send_fn: &mut dyn FnMut(MessageTarget, &dyn erased_serde::Serialize),
+    ),
+}
+
+impl Debug for MessageRegistration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.write_str(self.type_name)
+    }
+}
+
+impl KindRegistration for MessageRegistration {
+    fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl MessageRegistration {
+    pub fn ty(&self) -> TypeId {
+        self.ty
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn insert_resources(&self, resources: &mut Resources) {
+        (self.insert_resources)(resources)
+    }
+
+    /// Deserializes a message and puts it in the inbox of its type.
+    pub fn receive(
+        &self,
+        resources: &Resources,
+        sender: Option<ClientId>,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error> {
+        (self.receive)(resources, sender, data)
+    }
+
+    /// Passes the messages in the outbox of the type to the given function and empties the outbox.
+    pub fn drain_outbox(
+        &self,
+        resources: &Resources,
+        send_fn: &mut dyn FnMut(MessageTarget, &dyn erased_serde::Serialize),
+    ) {
+        (self.drain_outbox)(resources, send_fn)
+    }
+
+    pub fn of<T: Serialize + DeserializeOwned + Send + Sync + 'static>(kind: MessageKind) -> Self {
+        Self {
+            ty: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            kind,
+            insert_resources: |resources| {
+                resources.insert(Inbox::<T>::new());
+                resources.insert(Outbox::<T>::new());
+            },
+            receive: |resources, sender, data| {
+                let message = erased_serde::deserialize::<T>(data)?;
+
+                match resources.get_mut::<Inbox<T>>() {
+                    Some(mut inbox) => inbox.push(sender, message),
+                    None => log::warn!(
+                        "There is no inbox for message {}, the message is dropped.",
+                        std::any::type_name::<T>()
+                    ),
+                }
+
+                Ok(())
+            },
+            drain_outbox: |resources, send_fn| {
+                if let Some(mut outbox) = resources.get_mut::<Outbox<T>>() {
+                    for (target, message) in outbox.drain() {
+                        send_fn(target, &message);
+                    }
+                }
+            },
+        }
+    }
+}
+
+pub struct MessageRegister;
+
+impl MessageRegister {
+    /// Returns the registered message types by their [MessageKind](MessageKind).
+    ///
+    /// The worlds keep the result as a resource, panics when two types are registered with the same kind.
+    pub fn by_kind() -> KindRegistry<MessageRegistration> {
+        KindRegistry::new(MessageRegister.iter())
+    }
+
+    /// Returns the kind of the given message type, `None` when it is not registered.
+    pub fn kind_of<T: 'static>() -> Option<MessageKind> {
+        MessageRegister
+            .iter()
+            .find(|message| message.ty() == TypeId::of::<T>())
+            .map(|message| message.kind())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = MessageRegistrationRef> {
+        inventory::iter::<MessageRegistration>.into_iter()
+    }
+}
+
+/// Registers a message type, such that it can be sent with its [Outbox](crate::resources::Outbox)
+/// and received in its [Inbox](crate::resources::Inbox).
+///
+/// The message type is identified on the wire by the kind after it,
+/// the server and the clients must register each type with the same kind.
+///
+/// ```ignore
+/// register_message_type!(ChatMessage, 1);
+/// ```
+#[macro_export]
+macro_rules! register_message_type {
+    ($message_type:ty, $kind:expr $(,)?) => {
+        inventory::submit! {
+             $crate::register::MessageRegistration::of::<$message_type>($kind)
+        }
+    };
+}
+
+pub type EntityEventRegistrationRef = &'static EntityEventRegistration;
+/// The registered entity event types by their kind, see [EntityEventRegister::by_kind](EntityEventRegister::by_kind).
+pub type RegisteredEntityEventsResource = KindRegistry<EntityEventRegistration>;
+
+/// An event type that the server sends to the clients addressed to a replicated entity.
+///
+/// Each registered type gets an [EntityEventOutbox](EntityEventOutbox) and an [EntityEventInbox](EntityEventInbox) resource.
+#[derive(Clone)]
+pub struct EntityEventRegistration {
+    pub(crate) ty: TypeId,
+    pub(crate) type_name: &'static str,
+    pub(crate) kind: MessageKind,
+
+    pub(crate) insert_resources: fn(resources: &mut Resources),
+
+    pub(crate) receive: fn(
+        resources: &Resources,
+        entity: Entity,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error>,
+
+    pub(crate) drain_outbox: fn(
+        resources: &Resources,
+        send_fn: &mut dyn FnMut(Entity, MessageTarget, &dyn erased_serde::Serialize),
+    ),
+}
+
+impl Debug for EntityEventRegistration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.write_str(self.type_name)
+    }
+}
+
+impl KindRegistration for EntityEventRegistration {
+    fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl EntityEventRegistration {
+    pub fn ty(&self) -> TypeId {
+        self.ty
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn insert_resources(&self, resources: &mut Resources) {
+        (self.insert_resources)(resources)
+    }
+
+    /// Deserializes an event and puts it in the inbox of its type.
+    pub fn receive(
+        &self,
+        resources: &Resources,
+        entity: Entity,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error> {
+        (self.receive)(resources, entity, data)
+    }
+
+    /// Passes the events in the outbox of the type to the given function and empties the outbox.
+    pub fn drain_outbox(
+        &self,
+        resources: &Resources,
+        send_fn: &mut dyn FnMut(Entity, MessageTarget, &dyn erased_serde::Serialize),
+    ) {
+        (self.drain_outbox)(resources, send_fn)
+    }
+
+    pub fn of<T: Serialize + DeserializeOwned + Send + Sync + 'static>(kind: MessageKind) -> Self {
+        Self {
+            ty: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            kind,
+            insert_resources: |resources| {
+                resources.insert(EntityEventInbox::<T>::new());
+                resources.insert(EntityEventOutbox::<T>::new());
+            },
+            receive: |resources, entity, data| {
+                let event = erased_serde::deserialize::<T>(data)?;
+
+                match resources.get_mut::<EntityEventInbox<T>>() {
+                    Some(mut inbox) => inbox.push(entity, event),
+                    None => log::warn!(
+                        "There is no inbox for entity event {}, the event is dropped.",
+                        std::any::type_name::<T>()
+                    ),
+                }
+
+                Ok(())
+            },
+            drain_outbox: |resources, send_fn| {
+                if let Some(mut outbox) = resources.get_mut::<EntityEventOutbox<T>>() {
+                    for (entity, target, event) in outbox.drain() {
+                        send_fn(entity, target, &event);
+                    }
+                }
+            },
+        }
+    }
+}
+
+pub struct EntityEventRegister;
+
+impl EntityEventRegister {
+    /// Returns the registered entity event types by their [MessageKind](MessageKind).
+    ///
+    /// The worlds keep the result as a resource, panics when two types are registered with the same kind.
+    pub fn by_kind() -> KindRegistry<EntityEventRegistration> {
+        KindRegistry::new(EntityEventRegister.iter())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = EntityEventRegistrationRef> {
+        inventory::iter::<EntityEventRegistration>.into_iter()
+    }
+}
+
+/// Registers an entity event type, such that the server can send it with its [EntityEventOutbox](crate::resources::EntityEventOutbox)
+/// and the clients receive it in its [EntityEventInbox](crate::resources::EntityEventInbox).
+///
+/// Like a message type, the event type is identified on the wire by the kind after it:
+///
+/// ```ignore
+/// register_entity_event_type!(HitEffect, 1);
+/// ```
+#[macro_export]
+macro_rules! register_entity_event_type {
+    ($event_type:ty, $kind:expr $(,)?) => {
+        inventory::submit! {
+             $crate::register::EntityEventRegistration::of::<$event_type>($kind)
+        }
+    };
+}
+
+pub type ResourceRegistrationRef = &'static ResourceRegistration;
+/// The registered resource types by their kind, see [ResourceRegister::by_kind](ResourceRegister::by_kind).
+pub type RegisteredResourcesResource = KindRegistry<ResourceRegistration>;
+
+/// A resource type whose state the server replicates to the clients.
+///
+/// Like the changes of a component, the changes of a resource are sent as serde-diff differences.
+/// The client predicts a resource and only corrects it when the server disagrees,
+/// an interpolated resource takes the server state as is.
+#[derive(Clone)]
+pub struct ResourceRegistration {
+    pub(crate) ty: TypeId,
+    pub(crate) type_name: &'static str,
+    pub(crate) kind: MessageKind,
+    pub(crate) simulation: Simulation,
+
+    pub(crate) insert_resource: fn(resources: &mut Resources),
+
+    pub(crate) serialize_if_exists:
+        fn(resources: &Resources, serialize_fn: &mut dyn FnMut(&dyn erased_serde::Serialize)),
+
+    pub(crate) serialize_difference: fn(
+        unchanged: &mut dyn erased_serde::Deserializer,
+        changed: &mut dyn erased_serde::Deserializer,
+        serializer: &mut dyn erased_serde::Serializer,
+    ) -> Result<bool, ErrorKind>,
+
+    pub(crate) replace: fn(
+        resources: &Resources,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error>,
+
+    pub(crate) apply_changes: fn(
+        resources: &Resources,
+        changes: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error>,
+}
+
+impl Debug for ResourceRegistration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.write_str(self.type_name)
+    }
+}
+
+impl KindRegistration for ResourceRegistration {
+    fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl ResourceRegistration {
+    pub fn ty(&self) -> TypeId {
+        self.ty
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn simulation(&self) -> Simulation {
+        self.simulation
+    }
+
+    pub fn predicted(mut self) -> Self {
+        self.simulation = Simulation::Predicted;
+        self
+    }
+
+    pub fn interpolated(mut self) -> Self {
+        self.simulation = Simulation::Interpolated;
+        self
+    }
+
+    /// Inserts the default value of the resource, a resource inserted later replaces it.
+    pub fn insert_resource(&self, resources: &mut Resources) {
+        (self.insert_resource)(resources)
+    }
+
+    pub fn serialize_if_exists(
+        &self,
+        resources: &Resources,
+        serialize_fn: &mut dyn FnMut(&dyn erased_serde::Serialize),
+    ) {
+        (self.serialize_if_exists)(resources, serialize_fn)
+    }
+
+    pub fn serialize_difference(
+        &self,
+        unchanged: &mut dyn erased_serde::Deserializer,
+        changed: &mut dyn erased_serde::Deserializer,
+        serializer: &mut dyn erased_serde::Serializer,
+    ) -> Result<bool, ErrorKind> {
+        (self.serialize_difference)(unchanged, changed, serializer)
+    }
+
+    /// Replaces the resource with the deserialized data, the resource is left as is when the data cannot be deserialized.
+    pub fn replace(
+        &self,
+        resources: &Resources,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error> {
+        (self.replace)(resources, data)
+    }
+
+    /// Applies the deserialized difference to the resource.
+    pub fn apply_changes(
+        &self,
+        resources: &Resources,
+        data: &mut dyn erased_serde::Deserializer,
+    ) -> Result<(), erased_serde::Error> {
+        (self.apply_changes)(resources, data)
+    }
+
+    pub fn of<
+        T: Debug + Serialize + DeserializeOwned + Send + Sync + SerdeDiff + Default + 'static,
+    >(
+        kind: MessageKind,
+    ) -> Self {
+        Self {
+            ty: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            kind,
+            simulation: Simulation::Predicted,
+            insert_resource: |resources| {
+                resources.insert(T::default());
+            },
+            serialize_if_exists: |resources, serialize_fn| {
+                if let Some(resource) = resources.get::<T>() {
+                    serialize_fn(&*resource);
+                }
+            },
+            serialize_difference: |unchanged, changed, serializer| {
+                let unchanged = erased_serde::deserialize::<T>(unchanged)
+                    .expect("failed to deserialize resource");
+
+                let changed = erased_serde::deserialize::<T>(changed)
+                    .expect("failed to deserialize resource");
+
+                let diff = Config::new()
+                    .with_field_path_mode(FieldPathMode::Index)
+                    .serializable_diff(&unchanged, &changed);
+
+                <serde_diff::Diff<T> as serde::ser::Serialize>::serialize(&diff, serializer)
+                    .expect("failed to serialize diff");
+
+                Ok(diff.has_changes())
+            },
+            replace: |resources, data| {
+                let value = erased_serde::deserialize::<T>(data)?;
+
+                if let Some(mut resource) = resources.get_mut::<T>() {
+                    *resource = value;
+                }
+
+                Ok(())
+            },
+            apply_changes: |resources, data| {
+                if let Some(mut resource) = resources.get_mut::<T>() {
+                    <serde_diff::Apply<T> as serde::de::DeserializeSeed>::deserialize(
+                        serde_diff::Apply::deserializable(&mut *resource),
+                        data,
+                    )?;
+                }
+
+                Ok(())
+            },
+        }
+    }
+}
+
+pub struct ResourceRegister;
+
+impl ResourceRegister {
+    /// Returns the registered resource types by their [MessageKind](MessageKind).
+    ///
+    /// The worlds keep the result as a resource, panics when two types are registered with the same kind.
+    pub fn by_kind() -> KindRegistry<ResourceRegistration> {
+        KindRegistry::new(ResourceRegister.iter())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ResourceRegistrationRef> {
+        inventory::iter::<ResourceRegistration>.into_iter()
+    }
+}
+
+/// Registers a resource type, such that the server replicates its changes to the clients.
+///
+/// The resource type is identified on the wire by the kind after it,
+/// a resource is predicted by the client unless it is registered as interpolated:
+///
+/// ```ignore
+/// register_resource_type!(MatchTimer, 1);
+/// register_resource_type!(Weather, 2, interpolated);
+/// ```
+#[macro_export]
+macro_rules! register_resource_type {
+    ($resource_type:ty, $kind:expr $(, $setting:ident)* $(,)?) => {
+        inventory::submit! {
+             $crate::register::ResourceRegistration::of::<$resource_type>($kind)
+                $(.$setting())*
         }
     };
 }
@@ -330,31 +1064,60 @@ macro_rules! register_component_type {
 pub mod test {
     use std::any::TypeId;
 
-    use legion::storage::{ComponentMeta, ComponentTypeId};
+    use legion::{
+        storage::{ComponentMeta, ComponentTypeId},
+        Resources,
+    };
 
     use crate::{
+        codec::TRACKER_CODEC,
         components::UidComponent,
-        register::{ComponentRegister, ComponentRegistration, ComponentRegistrationRef},
+        register::{
+            ComponentRegister, ComponentRegistration, ComponentRegistrationRef, KindRegistry,
+            MessageRegister, MessageRegistration, ReplicationSettings, ResourceRegister,
+            Simulation, Tracking,
+        },
+        resources::{Inbox, MessageTarget, Outbox},
         tracking::{re_exports::serde_diff::*, track_attr::*},
     };
 
     #[derive(Clone, Default, Debug, Serialize, Deserialize, SerdeDiff)]
     struct Component {}
 
-    crate::register_component_type!(Component, Bincode);
+    crate::register_component_type!(Component, owner_only, interpolated, priority(2.0));
+
+    #[derive(Clone, Default, Debug, Serialize, Deserialize, SerdeDiff)]
+    struct AutomaticComponent {}
+
+    crate::register_component_type!(AutomaticComponent, automatic);
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct ChatMessage {
+        text: String,
+    }
+
+    crate::register_message_type!(ChatMessage, 1);
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize, SerdeDiff)]
+    struct Score {
+        points: u32,
+        round: u32,
+    }
+
+    crate::register_resource_type!(Score, 1);
 
     #[test]
     fn registered_by_component_id_should_be_filled_test() {
         let registered = ComponentRegister::by_component_id();
 
-        assert_eq!(registered.len(), 2);
+        assert_eq!(registered.len(), 7);
     }
 
     #[test]
     fn registered_by_uid_should_be_filled_test() {
         let registered = ComponentRegister::by_unique_uid();
 
-        assert_eq!(registered.len(), 2);
+        assert_eq!(registered.len(), 7);
     }
 
     #[test]
@@ -363,6 +1126,35 @@ pub mod test {
 
         assert!(registered.get(&1).is_some());
         assert!(registered.get(&2).is_some());
+        assert!(registered.get(&3).is_some());
+        assert!(registered.get(&4).is_some());
+        assert!(registered.get(&5).is_some());
+        assert!(registered.get(&6).is_some());
+        assert!(registered.get(&7).is_some());
+    }
+
+    #[test]
+    fn registered_component_has_replication_settings_test() {
+        let registered = ComponentRegister::by_component_id();
+
+        let component = registered
+            .get(&ComponentTypeId::of::<Component>())
+            .expect("Should be registered");
+        assert!(component.replication().owner_only);
+        assert_eq!(component.replication().simulation, Simulation::Interpolated);
+        assert_eq!(component.replication().priority, 2.0);
+        assert_eq!(component.replication().tracking, Tracking::Manual);
+
+        let automatic = registered
+            .get(&ComponentTypeId::of::<AutomaticComponent>())
+            .expect("Should be registered");
+        assert_eq!(automatic.replication().tracking, Tracking::Automatic);
+        assert!(!automatic.replication().owner_only);
+
+        let uid = registered
+            .get(&ComponentTypeId::of::<UidComponent>())
+            .expect("Should be registered");
+        assert_eq!(uid.replication(), &ReplicationSettings::default());
     }
 
     #[test]
@@ -391,4 +1183,132 @@ pub mod test {
             ComponentTypeId::of::<UidComponent>()
         );
     }
+
+    #[test]
+    fn registered_message_passes_from_outbox_to_inbox_test() {
+        let kind = MessageRegister::kind_of::<ChatMessage>().expect("Should be registered");
+        assert_eq!(kind, 1);
+        let registration = MessageRegister::by_kind().get(kind).unwrap();
+
+        let mut resources = Resources::default();
+        registration.insert_resources(&mut resources);
+
+        let message = ChatMessage {
+            text: String::from("Hello"),
+        };
+        resources
+            .get_mut::<Outbox<ChatMessage>>()
+            .unwrap()
+            .send(MessageTarget::All, message.clone());
+
+        let mut sent = Vec::new();
+        registration.drain_outbox(&resources, &mut |target, message| {
+            let (result, data) = TRACKER_CODEC
+                .serialize_erased(|serializer| erased_serde::serialize(message, serializer));
+            result.unwrap();
+            sent.push((target, data));
+        });
+
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, MessageTarget::All);
+        assert!(resources.get::<Outbox<ChatMessage>>().unwrap().is_empty());
+
+        TRACKER_CODEC
+            .deserialize_erased(&sent[0].1, |deserializer| {
+                registration.receive(&resources, Some(3), deserializer)
+            })
+            .unwrap();
+
+        let mut inbox = resources.get_mut::<Inbox<ChatMessage>>().unwrap();
+        assert_eq!(inbox.drain().collect::<Vec<_>>(), vec![(Some(3), message)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn registrations_with_same_kind_are_rejected_test() {
+        let chat: &'static MessageRegistration =
+            Box::leak(Box::new(MessageRegistration::of::<ChatMessage>(1)));
+        let text: &'static MessageRegistration =
+            Box::leak(Box::new(MessageRegistration::of::<String>(1)));
+
+        KindRegistry::new(vec![chat, text].into_iter());
+    }
+
+    #[test]
+    fn registered_resource_changes_are_applied_test() {
+        let registration = ResourceRegister
+            .iter()
+            .find(|registration| registration.ty() == TypeId::of::<Score>())
+            .expect("Should be registered");
+
+        let mut server = Resources::default();
+        let mut client = Resources::default();
+        registration.insert_resource(&mut server);
+        registration.insert_resource(&mut client);
+
+        let serialize = |resources: &Resources| {
+            let mut data = Vec::new();
+            registration.serialize_if_exists(resources, &mut |resource| {
+                data = TRACKER_CODEC
+                    .serialize_erased(|serializer| erased_serde::serialize(resource, serializer))
+                    .1;
+            });
+            data
+        };
+
+        let unchanged = serialize(&server);
+        server.get_mut::<Score>().unwrap().points = 3;
+        let changed = serialize(&server);
+
+        let (has_changes, difference) = TRACKER_CODEC.deserialize_erased(&unchanged, |unchanged| {
+            TRACKER_CODEC.deserialize_erased(&changed, |changed| {
+                TRACKER_CODEC.serialize_erased(|serializer| {
+                    registration.serialize_difference(unchanged, changed, serializer)
+                })
+            })
+        });
+        assert!(has_changes.unwrap());
+
+        client.get_mut::<Score>().unwrap().round = 2;
+        TRACKER_CODEC
+            .deserialize_erased(&difference, |deserializer| {
+                registration.apply_changes(&client, deserializer)
+            })
+            .unwrap();
+
+        // Only the changed field is overwritten.
+        assert_eq!(
+            *client.get::<Score>().unwrap(),
+            Score {
+                points: 3,
+                round: 2
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_resource_data_is_rejected_test() {
+        let registration = ResourceRegister
+            .iter()
+            .find(|registration| registration.ty() == TypeId::of::<Score>())
+            .expect("Should be registered");
+
+        let mut resources = Resources::default();
+        resources.insert(Score {
+            points: 1,
+            round: 1,
+        });
+
+        assert!(TRACKER_CODEC
+            .deserialize_erased(&[1], |deserializer| registration
+                .replace(&resources, deserializer))
+            .is_err());
+        assert_eq!(
+            *resources.get::<Score>().unwrap(),
+            Score {
+                points: 1,
+                round: 1
+            }
+        );
+    }
 }
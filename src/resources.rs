@@ -10,24 +10,44 @@ use net_sync::{
         ClientCommandBuffer, CommandFrameTicker, NetworkCommand, NetworkMessage, ResimulationBuffer,
     },
     tracker::TrackResource,
-    transport,
-    transport::{
-        tcp::{TcpClientResource, TcpListenerResource},
-        PostBox, PostOffice,
-    },
+    transport::tcp::{TcpClientResource, TcpListenerResource},
     uid::UidAllocator,
 };
 
 pub use self::{
     buffer::BufferResource,
+    command::{ServerCommandBuffer, UnackedCommands, DEFAULT_COMMAND_REDUNDANCY},
     component::{HashmapRegistry, RegisteredComponentsResource},
+    conditioner::{LinkConditioner, LinkConditions},
+    entity_event::{EntityEventInbox, EntityEventOutbox},
     event::EventResource,
+    message::{Inbox, MessageTarget, Outbox},
+    metrics::{ClientReplicationMetrics, ReplicationMetrics},
+    prediction::ResourcePredictionBuffer,
+    rtt::{RoundTripTimes, RttEstimator},
+    session::{BanList, Sessions, DEFAULT_SESSION_GRACE_PERIOD},
+    uid::{uid_generation, uid_index, UidRecycler},
+};
+use crate::{
+    codec::WireCodec,
+    event::SyncEventQueue,
+    protocol::{ClientId, ClientPostBox, ClientToServer, ServerPostOffice, ServerToClient},
+    register::{EntityEventRegister, MessageRegister, ResourceRegister},
 };
 use net_sync::event::NetworkEventQueue;
 
 mod buffer;
+mod command;
 mod component;
+mod conditioner;
+mod entity_event;
 mod event;
+mod message;
+mod metrics;
+mod prediction;
+mod rtt;
+mod session;
+mod uid;
 
 pub trait ResourcesExt {
     fn insert_server_resources<
@@ -71,11 +91,24 @@ impl ResourcesExt for Resources {
         &mut self,
         compression: C,
     ) {
-        self.insert(PostOffice::<
+        self.insert(ServerPostOffice::<
             ServerToClientMessage,
             ClientToServerMessage,
             ClientToServerCommand,
         >::new());
+        self.insert(ReplicationMetrics::new());
+        self.insert(ServerCommandBuffer::<ClientToServerCommand>::new());
+        self.insert(Sessions::new());
+        self.insert(BanList::new());
+        // Transparent until the conditions are set with `with_link_conditioner`.
+        self.insert(LinkConditioner::<(
+            ClientId,
+            ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+        )>::new(LinkConditions::perfect(), 0));
+        self.insert(LinkConditioner::<(
+            ClientId,
+            ServerToClient<ServerToClientMessage>,
+        )>::new(LinkConditions::perfect(), 0));
         self.insert_required(compression);
     }
 
@@ -90,6 +123,8 @@ impl ResourcesExt for Resources {
             10,
         ));
         self.insert(ResimulationBuffer::<ClientToServerCommand>::new());
+        self.insert(ResourcePredictionBuffer::new());
+        self.insert(UnackedCommands::<ClientToServerCommand>::default());
         self.insert_required(compression);
     }
 
@@ -97,12 +132,30 @@ impl ResourcesExt for Resources {
         self.insert(BufferResource::from_capacity(5000));
         self.insert(RegisteredComponentsResource::new());
         self.insert(UidAllocator::<Entity>::new());
+        self.insert(UidRecycler::new());
         self.insert(TrackResource::new());
         self.insert(CommandFrameTicker::new(30.));
         self.insert(NetworkEventQueue::new());
+        self.insert(SyncEventQueue::new());
+        self.insert(WireCodec::default());
 
         let registered_components = RegisteredComponentsResource::new();
         self.insert(registered_components);
+
+        for registration in MessageRegister.iter() {
+            registration.insert_resources(self);
+        }
+        self.insert(MessageRegister::by_kind());
+
+        for registration in EntityEventRegister.iter() {
+            registration.insert_resources(self);
+        }
+        self.insert(EntityEventRegister::by_kind());
+
+        for registration in ResourceRegister.iter() {
+            registration.insert_resource(self);
+        }
+        self.insert(ResourceRegister::by_kind());
     }
 
     fn insert_tcp_client_resources<
@@ -113,11 +166,29 @@ impl ResourcesExt for Resources {
         &mut self,
         addr: SocketAddr,
     ) {
-        self.insert(PostBox::<
-            transport::ServerToClientMessage<ServerToClientMessage>,
-            transport::ClientToServerMessage<ClientToServerMessage, ClientToServerCommand>,
+        self.insert(ClientPostBox::<
+            ServerToClientMessage,
+            ClientToServerMessage,
+            ClientToServerCommand,
         >::new());
         self.insert(TcpClientResource::new(addr).unwrap());
+
+        // Transparent until the conditions are set with `with_link_conditioner`.
+        if !self.contains::<LinkConditioner<ServerToClient<ServerToClientMessage>>>() {
+            self.insert(
+                LinkConditioner::<ServerToClient<ServerToClientMessage>>::new(
+                    LinkConditions::perfect(),
+                    0,
+                ),
+            );
+        }
+        if !self
+            .contains::<LinkConditioner<ClientToServer<ClientToServerMessage, ClientToServerCommand>>>()
+        {
+            self.insert(LinkConditioner::<
+                ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+            >::new(LinkConditions::perfect(), 0));
+        }
     }
 
     fn insert_tcp_listener_resources(&mut self, listener: TcpListener) {
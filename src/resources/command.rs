@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use net_sync::synchronisation::CommandFrame;
+
+use crate::protocol::ClientId;
+
+/// The number of commands a client resends until the server acknowledged them.
+pub const DEFAULT_COMMAND_REDUNDANCY: usize = 8;
+
+/// The commands a client has sent but the server did not yet acknowledge.
+///
+/// Every packet to the server carries the unacknowledged commands again,
+/// such that a lost packet does not lose the input as long as one of the next packets arrives.
+/// The `ClientCommandBuffer` keeps the predicted changes for the reconciliation independently,
+/// it drops its oldest entries once it is full.
+pub struct UnackedCommands<C> {
+    commands: VecDeque<(CommandFrame, C)>,
+    redundancy: usize,
+    acknowledged: Option<CommandFrame>,
+}
+
+impl<C> UnackedCommands<C> {
+    /// Creates a buffer that holds at most `redundancy` commands, older commands are no longer resent.
+    pub fn new(redundancy: usize) -> UnackedCommands<C> {
+        UnackedCommands {
+            commands: VecDeque::with_capacity(redundancy),
+            redundancy,
+            acknowledged: None,
+        }
+    }
+
+    pub fn redundancy(&self) -> usize {
+        self.redundancy
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Returns the command frame up to which the server received all commands, `None` until it received a command.
+    pub fn acknowledged(&self) -> Option<CommandFrame> {
+        self.acknowledged
+    }
+
+    /// Adds a command that is about to be sent for the first time.
+    pub(crate) fn push(&mut self, command_frame: CommandFrame, command: C) {
+        self.commands.push_back((command_frame, command));
+
+        while self.commands.len() > self.redundancy {
+            self.commands.pop_front();
+        }
+    }
+
+    /// Forgets the commands up to and including the acknowledged command frame.
+    pub(crate) fn acknowledge(&mut self, command_frame: CommandFrame) {
+        self.acknowledged = Some(
+            self.acknowledged
+                .map_or(command_frame, |x| x.max(command_frame)),
+        );
+
+        while let Some((frame, _)) = self.commands.front() {
+            if *frame > command_frame {
+                break;
+            }
+
+            self.commands.pop_front();
+        }
+    }
+
+    /// Returns the commands to send, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &(CommandFrame, C)> {
+        self.commands.iter()
+    }
+}
+
+impl<C> Default for UnackedCommands<C> {
+    fn default() -> Self {
+        UnackedCommands::new(DEFAULT_COMMAND_REDUNDANCY)
+    }
+}
+
+struct ClientCommands<C> {
+    pending: BTreeMap<CommandFrame, Vec<C>>,
+    current: Vec<C>,
+    repeated: bool,
+    highest_received: Option<CommandFrame>,
+    /// The received command frames above the acknowledged frame.
+    received: BTreeSet<CommandFrame>,
+    acknowledged: Option<CommandFrame>,
+    offset: Option<i32>,
+}
+
+impl<C> ClientCommands<C> {
+    fn new() -> ClientCommands<C> {
+        ClientCommands {
+            pending: BTreeMap::new(),
+            current: Vec::new(),
+            repeated: false,
+            highest_received: None,
+            received: BTreeSet::new(),
+            acknowledged: None,
+            offset: None,
+        }
+    }
+}
+
+/// The commands the server received from the clients, without the copies that clients resend.
+///
+/// The buffer is a jitter buffer: commands arrive ahead of the command frame they are meant for and wait here
+/// until the server simulates that frame. The server world moves the commands from the post office into this buffer
+/// and advances it every command frame, after which systems read the commands of the current frame
+/// with [current_commands](ServerCommandBuffer::current_commands),
+/// or take the commands with [drain](ServerCommandBuffer::drain).
+/// When the commands of a client are missing for a frame, the current commands of the previous frame are repeated.
+/// Copies that clients resend and commands that arrive for a simulated or drained command frame are dropped.
+pub struct ServerCommandBuffer<C> {
+    clients: HashMap<ClientId, ClientCommands<C>>,
+    command_frame: Option<CommandFrame>,
+    drained_up_to: Option<CommandFrame>,
+}
+
+impl<C> ServerCommandBuffer<C> {
+    pub fn new() -> ServerCommandBuffer<C> {
+        ServerCommandBuffer {
+            clients: HashMap::new(),
+            command_frame: None,
+            drained_up_to: None,
+        }
+    }
+
+    /// Returns the command frame the current commands are for.
+    pub fn command_frame(&self) -> Option<CommandFrame> {
+        self.command_frame
+    }
+
+    /// Returns the commands of the client for the current command frame.
+    pub fn current_commands(&self, client_id: ClientId) -> &[C] {
+        self.clients
+            .get(&client_id)
+            .map_or(&[], |x| x.current.as_slice())
+    }
+
+    /// Returns the buffered commands of the client for the given command frame.
+    pub fn commands(&self, client_id: ClientId, command_frame: CommandFrame) -> &[C] {
+        let client = match self.clients.get(&client_id) {
+            Some(client) => client,
+            None => return &[],
+        };
+
+        if self.command_frame == Some(command_frame) {
+            return if client.repeated {
+                &[]
+            } else {
+                client.current.as_slice()
+            };
+        }
+
+        client
+            .pending
+            .get(&command_frame)
+            .map_or(&[], |x| x.as_slice())
+    }
+
+    /// Takes the commands of all clients up to and including the given command frame, ordered by command frame per client.
+    ///
+    /// The current commands are taken as well when they did not arrive in time, drained commands are not repeated.
+    pub fn drain(&mut self, command_frame: CommandFrame) -> Vec<(ClientId, CommandFrame, C)> {
+        let mut drained = Vec::new();
+        let current_frame = self.command_frame.filter(|x| *x <= command_frame);
+
+        for (client_id, client) in self.clients.iter_mut() {
+            if let Some(current_frame) = current_frame {
+                if !client.repeated {
+                    drained.extend(
+                        client
+                            .current
+                            .drain(..)
+                            .map(|x| (*client_id, current_frame, x)),
+                    );
+                }
+            }
+
+            let later = client.pending.split_off(&(command_frame + 1));
+            let earlier = std::mem::replace(&mut client.pending, later);
+
+            for (frame, commands) in earlier {
+                drained.extend(commands.into_iter().map(|x| (*client_id, frame, x)));
+            }
+        }
+
+        self.drained_up_to = Some(
+            self.drained_up_to
+                .map_or(command_frame, |x| x.max(command_frame)),
+        );
+
+        drained
+    }
+
+    /// Returns the current commands of all clients.
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &[C])> {
+        self.clients
+            .iter()
+            .map(|(client_id, client)| (*client_id, client.current.as_slice()))
+    }
+
+    /// Returns whether the commands of the client are repeated from an earlier frame because they did not arrive in time.
+    pub fn is_repeated(&self, client_id: ClientId) -> bool {
+        self.clients.get(&client_id).map_or(false, |x| x.repeated)
+    }
+
+    /// Returns the highest command frame received from the client.
+    pub fn highest_received(&self, client_id: ClientId) -> Option<CommandFrame> {
+        self.clients
+            .get(&client_id)
+            .and_then(|x| x.highest_received)
+    }
+
+    /// Returns the command frame up to which all commands the client sent have been received,
+    /// which is acknowledged in its state updates.
+    pub fn acknowledged(&self, client_id: ClientId) -> Option<CommandFrame> {
+        self.clients.get(&client_id).and_then(|x| x.acknowledged)
+    }
+
+    /// Acknowledges the commands the client sent for the given command frames up to the first one that did not arrive.
+    ///
+    /// The client lists the frames of the commands it sends, frames without commands are never missing.
+    pub(crate) fn acknowledge_sent(&mut self, client_id: ClientId, sent: &[CommandFrame]) {
+        let client = match self.clients.get_mut(&client_id) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let mut sent = sent.to_vec();
+        sent.sort();
+
+        for frame in sent {
+            if client.acknowledged.map_or(false, |x| frame <= x) {
+                continue;
+            }
+
+            if !client.received.contains(&frame) {
+                break;
+            }
+
+            client.acknowledged = Some(frame);
+        }
+
+        if let Some(acknowledged) = client.acknowledged {
+            client.received = client.received.split_off(&(acknowledged + 1));
+        }
+    }
+
+    /// Returns the fill of the buffer of the client as of the current command frame:
+    /// negative when the commands of that many frames ahead wait in the buffer,
+    /// positive when the commands of the client are that many frames behind. `None` until the client sent a command.
+    pub fn offset(&self, client_id: ClientId) -> Option<i32> {
+        self.clients.get(&client_id).and_then(|x| x.offset)
+    }
+
+    /// Makes the commands for the given command frame current, repeating the previous commands of clients that have none.
+    pub(crate) fn advance(&mut self, command_frame: CommandFrame) {
+        for client in self.clients.values_mut() {
+            client.offset = client
+                .highest_received
+                .map(|x| (command_frame as i64 - x as i64) as i32);
+
+            let later = client.pending.split_off(&(command_frame + 1));
+            let mut due = std::mem::replace(&mut client.pending, later);
+
+            match due.remove(&command_frame) {
+                Some(commands) => {
+                    client.current = commands;
+                    client.repeated = false;
+                }
+                None => client.repeated = true,
+            }
+        }
+
+        self.command_frame = Some(command_frame);
+    }
+
+    pub(crate) fn retain_clients(&mut self, is_connected: impl Fn(&ClientId) -> bool) {
+        self.clients.retain(|client_id, _| is_connected(client_id));
+    }
+}
+
+impl<C: PartialEq> ServerCommandBuffer<C> {
+    /// Adds a received command, returns `false` when it was a copy or arrived too late.
+    pub(crate) fn insert(
+        &mut self,
+        client_id: ClientId,
+        command_frame: CommandFrame,
+        command: C,
+    ) -> bool {
+        let client = self
+            .clients
+            .entry(client_id)
+            .or_insert_with(ClientCommands::new);
+
+        client.highest_received = Some(
+            client
+                .highest_received
+                .map_or(command_frame, |x| x.max(command_frame)),
+        );
+
+        // Commands that arrive too late are received nonetheless, resending them does not help.
+        if client.acknowledged.map_or(true, |x| command_frame > x) {
+            client.received.insert(command_frame);
+        }
+
+        if self.command_frame.map_or(false, |x| command_frame <= x)
+            || self.drained_up_to.map_or(false, |x| command_frame <= x)
+        {
+            return false;
+        }
+
+        let commands = client.pending.entry(command_frame).or_default();
+
+        if commands.contains(&command) {
+            return false;
+        }
+
+        commands.push(command);
+        true
+    }
+}
+
+impl<C> Default for ServerCommandBuffer<C> {
+    fn default() -> Self {
+        ServerCommandBuffer::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::resources::{ServerCommandBuffer, UnackedCommands};
+
+    #[test]
+    fn unacked_commands_are_trimmed_test() {
+        let mut unacked = UnackedCommands::new(3);
+
+        for frame in 0..5 {
+            unacked.push(frame, frame * 10);
+        }
+        assert_eq!(
+            unacked.iter().cloned().collect::<Vec<_>>(),
+            vec![(2, 20), (3, 30), (4, 40)]
+        );
+
+        unacked.acknowledge(3);
+        assert_eq!(unacked.iter().cloned().collect::<Vec<_>>(), vec![(4, 40)]);
+    }
+
+    #[test]
+    fn resent_commands_are_deduplicated_test() {
+        let mut buffer = ServerCommandBuffer::new();
+
+        assert!(buffer.insert(1, 5, 'a'));
+        assert!(!buffer.insert(1, 5, 'a'));
+        assert!(buffer.insert(1, 6, 'b'));
+        assert!(buffer.insert(2, 5, 'a'));
+
+        assert_eq!(buffer.commands(1, 5), &['a']);
+        assert_eq!(buffer.highest_received(1), Some(6));
+    }
+
+    #[test]
+    fn drained_frames_are_not_buffered_again_test() {
+        let mut buffer = ServerCommandBuffer::new();
+        buffer.insert(1, 5, 'a');
+        buffer.insert(1, 6, 'b');
+
+        assert_eq!(buffer.drain(5), vec![(1, 5, 'a')]);
+        assert!(!buffer.insert(1, 5, 'a'));
+        assert_eq!(buffer.drain(6), vec![(1, 6, 'b')]);
+    }
+
+    #[test]
+    fn acknowledgement_stops_at_missing_command_test() {
+        let mut buffer = ServerCommandBuffer::new();
+        buffer.insert(1, 5, 'a');
+        buffer.insert(1, 7, 'c');
+
+        buffer.acknowledge_sent(1, &[5, 6, 7]);
+        assert_eq!(buffer.acknowledged(1), Some(5));
+
+        buffer.insert(1, 6, 'b');
+        buffer.acknowledge_sent(1, &[6, 7]);
+        assert_eq!(buffer.acknowledged(1), Some(7));
+
+        // Frames without commands do not hold back the acknowledgement.
+        buffer.insert(1, 8, 'd');
+        buffer.insert(1, 10, 'e');
+        buffer.acknowledge_sent(1, &[10, 8]);
+        assert_eq!(buffer.acknowledged(1), Some(10));
+    }
+
+    #[test]
+    fn commands_wait_for_their_frame_test() {
+        let mut buffer = ServerCommandBuffer::new();
+        buffer.insert(1, 7, 'a');
+
+        buffer.advance(6);
+        assert!(buffer.current_commands(1).is_empty());
+        assert_eq!(buffer.offset(1), Some(-1));
+
+        buffer.advance(7);
+        assert_eq!(buffer.current_commands(1), &['a']);
+        assert_eq!(buffer.commands(1, 7), &['a']);
+        assert!(!buffer.is_repeated(1));
+        assert_eq!(buffer.offset(1), Some(0));
+    }
+
+    #[test]
+    fn missing_commands_repeat_last_input_test() {
+        let mut buffer = ServerCommandBuffer::new();
+        buffer.insert(1, 5, 'a');
+        buffer.advance(5);
+        buffer.advance(6);
+
+        assert_eq!(buffer.current_commands(1), &['a']);
+        assert!(buffer.is_repeated(1));
+        assert!(buffer.commands(1, 6).is_empty());
+
+        // Arrives after its frame was simulated.
+        assert!(!buffer.insert(1, 6, 'b'));
+        buffer.advance(7);
+        assert_eq!(buffer.offset(1), Some(1));
+    }
+}
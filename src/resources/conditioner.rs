@@ -0,0 +1,264 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use net_sync::synchronisation::CommandFrame;
+
+/// The least command frames a reordered message is held back, so that it is overtaken even on a link without latency.
+const MIN_REORDER_FRAMES: CommandFrame = 2;
+
+/// The network conditions a [LinkConditioner](LinkConditioner) simulates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConditions {
+    /// The time it takes a message to arrive.
+    pub latency: Duration,
+    /// The maximum deviation from the latency, in both directions.
+    pub jitter: Duration,
+    /// The chance a message is lost, between 0 and 1.
+    pub loss: f32,
+    /// The chance a message arrives twice, between 0 and 1.
+    pub duplication: f32,
+    /// The chance a message is held back until after later messages, between 0 and 1.
+    pub reordering: f32,
+}
+
+impl LinkConditions {
+    /// A link that delivers every message immediately.
+    pub fn perfect() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.,
+            duplication: 0.,
+            reordering: 0.,
+        }
+    }
+
+    /// A typical broadband link.
+    pub fn average() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(10),
+            loss: 0.01,
+            duplication: 0.,
+            reordering: 0.01,
+        }
+    }
+
+    /// A congested mobile link.
+    pub fn poor() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(150),
+            jitter: Duration::from_millis(50),
+            loss: 0.05,
+            duplication: 0.01,
+            reordering: 0.05,
+        }
+    }
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions::perfect()
+    }
+}
+
+/// Simulates bad network conditions for the messages that pass through it.
+///
+/// The TCP systems hand every message that passes through the post box to the conditioner,
+/// including user messages, and only put it in the post box once the simulated link delivers it.
+/// The random decisions are made by a seeded generator, therefore the same seed and the same messages give the same result.
+///
+/// Time is measured in command frames, which makes a simulation independent of the wall clock.
+/// The delays are rounded to whole command frames.
+pub struct LinkConditioner<M> {
+    conditions: LinkConditions,
+    rng: SmallRng,
+    // Messages by their arrival frame, the sequence keeps messages with the same arrival frame in order.
+    in_flight: BTreeMap<(CommandFrame, u64), M>,
+    sequence: u64,
+}
+
+impl<M: Clone> LinkConditioner<M> {
+    pub fn new(conditions: LinkConditions, seed: u64) -> LinkConditioner<M> {
+        LinkConditioner {
+            conditions,
+            rng: SmallRng::seed_from_u64(seed),
+            in_flight: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn conditions(&self) -> &LinkConditions {
+        &self.conditions
+    }
+
+    /// Changes the conditions for messages that are sent from now on.
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// Returns the number of messages that are on their way.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Puts a message on the simulated link at the given command frame.
+    pub fn send(&mut self, message: M, now: CommandFrame, ticks_per_second: f32) {
+        if self.chance(self.conditions.loss) {
+            return;
+        }
+
+        if self.chance(self.conditions.duplication) {
+            let arrival = self.arrival(now, ticks_per_second);
+            self.enqueue(arrival, message.clone());
+        }
+
+        let arrival = self.arrival(now, ticks_per_second);
+        self.enqueue(arrival, message);
+    }
+
+    /// Takes the messages that have arrived by the given command frame, in order of arrival.
+    pub fn receive(&mut self, now: CommandFrame) -> Vec<M> {
+        let mut arrived = Vec::new();
+
+        while let Some(key) = self.in_flight.keys().next().cloned() {
+            if key.0 > now {
+                break;
+            }
+
+            arrived.push(self.in_flight.remove(&key).expect("Key should exist."));
+        }
+
+        arrived
+    }
+
+    /// Sends the given messages and takes the messages that have arrived.
+    pub fn transmit(
+        &mut self,
+        messages: impl IntoIterator<Item = M>,
+        now: CommandFrame,
+        ticks_per_second: f32,
+    ) -> Vec<M> {
+        for message in messages {
+            self.send(message, now, ticks_per_second);
+        }
+
+        self.receive(now)
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0. && self.rng.gen::<f32>() < probability
+    }
+
+    fn arrival(&mut self, now: CommandFrame, ticks_per_second: f32) -> CommandFrame {
+        let frames = |seconds: f64| (seconds * ticks_per_second as f64).round() as CommandFrame;
+
+        let jitter = self.conditions.jitter.as_secs_f64();
+        let deviation = if jitter > 0. {
+            self.rng.gen_range(-jitter, jitter)
+        } else {
+            0.
+        };
+
+        let latency = self.conditions.latency.as_secs_f64();
+        let mut arrival = now + frames((latency + deviation).max(0.));
+
+        if self.chance(self.conditions.reordering) {
+            arrival += frames(latency).max(MIN_REORDER_FRAMES);
+        }
+
+        arrival
+    }
+
+    fn enqueue(&mut self, arrival: CommandFrame, message: M) {
+        self.in_flight.insert((arrival, self.sequence), message);
+        self.sequence += 1;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use crate::resources::{LinkConditioner, LinkConditions};
+
+    /// A command frame takes 100 milliseconds.
+    const TICKS_PER_SECOND: f32 = 10.;
+
+    fn conditions() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(200),
+            ..LinkConditions::perfect()
+        }
+    }
+
+    #[test]
+    fn message_arrives_after_latency_test() {
+        let mut conditioner = LinkConditioner::new(conditions(), 0);
+
+        assert!(conditioner
+            .transmit(vec![1], 5, TICKS_PER_SECOND)
+            .is_empty());
+        assert!(conditioner.receive(6).is_empty());
+        assert_eq!(conditioner.receive(7), vec![1]);
+    }
+
+    #[test]
+    fn lost_messages_never_arrive_test() {
+        let mut conditioner = LinkConditioner::new(
+            LinkConditions {
+                loss: 1.,
+                ..conditions()
+            },
+            0,
+        );
+
+        conditioner.transmit(vec![1, 2, 3], 0, TICKS_PER_SECOND);
+
+        assert_eq!(conditioner.in_flight(), 0);
+    }
+
+    #[test]
+    fn duplicated_messages_arrive_twice_test() {
+        let mut conditioner = LinkConditioner::new(
+            LinkConditions {
+                duplication: 1.,
+                ..conditions()
+            },
+            0,
+        );
+
+        conditioner.transmit(vec![1], 0, TICKS_PER_SECOND);
+
+        assert_eq!(conditioner.receive(10), vec![1, 1]);
+    }
+
+    #[test]
+    fn reordered_message_is_overtaken_test() {
+        let mut conditioner = LinkConditioner::new(
+            LinkConditions {
+                reordering: 1.,
+                ..conditions()
+            },
+            0,
+        );
+
+        conditioner.send(1, 0, TICKS_PER_SECOND);
+        conditioner.set_conditions(conditions());
+        conditioner.send(2, 1, TICKS_PER_SECOND);
+
+        assert_eq!(conditioner.receive(10), vec![2, 1]);
+    }
+
+    #[test]
+    fn same_seed_gives_same_result_test() {
+        let run = |seed| {
+            let mut conditioner = LinkConditioner::new(LinkConditions::poor(), seed);
+            conditioner.transmit(0..100, 0, TICKS_PER_SECOND);
+            conditioner.receive(100)
+        };
+
+        assert_eq!(run(7), run(7));
+    }
+}
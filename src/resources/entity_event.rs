@@ -0,0 +1,88 @@
+use std::vec::Drain;
+
+use legion::Entity;
+
+use crate::resources::MessageTarget;
+
+/// The events of a type registered with [register_entity_event_type](register_entity_event_type)
+/// that the server sends at the next command frame.
+///
+/// An event is addressed to a replicated entity,
+/// the clients receive it after they applied the state update of the command frame it was sent in.
+pub struct EntityEventOutbox<T> {
+    events: Vec<(Entity, MessageTarget, T)>,
+}
+
+impl<T> EntityEventOutbox<T> {
+    pub fn new() -> EntityEventOutbox<T> {
+        EntityEventOutbox { events: Vec::new() }
+    }
+
+    /// Sends the event to all clients.
+    pub fn send(&mut self, entity: Entity, event: T) {
+        self.send_to(entity, MessageTarget::All, event);
+    }
+
+    pub fn send_to(&mut self, entity: Entity, target: MessageTarget, event: T) {
+        self.events.push((entity, target, event));
+    }
+
+    pub(crate) fn drain(&mut self) -> Drain<'_, (Entity, MessageTarget, T)> {
+        self.events.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl<T> Default for EntityEventOutbox<T> {
+    fn default() -> Self {
+        EntityEventOutbox::new()
+    }
+}
+
+/// The received events of a type registered with [register_entity_event_type](register_entity_event_type),
+/// with the local entity they are addressed to.
+///
+/// The events stay in the inbox until they are drained.
+pub struct EntityEventInbox<T> {
+    events: Vec<(Entity, T)>,
+}
+
+impl<T> EntityEventInbox<T> {
+    pub fn new() -> EntityEventInbox<T> {
+        EntityEventInbox { events: Vec::new() }
+    }
+
+    pub(crate) fn push(&mut self, entity: Entity, event: T) {
+        self.events.push((entity, event));
+    }
+
+    /// Takes the received events in command frame order.
+    pub fn drain(&mut self) -> Drain<'_, (Entity, T)> {
+        self.events.drain(..)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Entity, T)> {
+        self.events.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl<T> Default for EntityEventInbox<T> {
+    fn default() -> Self {
+        EntityEventInbox::new()
+    }
+}
@@ -0,0 +1,131 @@
+use std::vec::Drain;
+
+use crate::protocol::ClientId;
+
+/// The clients a typed message is sent to.
+///
+/// A client world sends all its messages to the server, whatever their target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageTarget {
+    Server,
+    Client(ClientId),
+    All,
+    /// All clients except the given one, e.g. the client the message originates from.
+    AllBut(ClientId),
+}
+
+impl MessageTarget {
+    /// Returns whether a message with this target is sent to the given client.
+    pub fn includes(&self, client_id: ClientId) -> bool {
+        match *self {
+            MessageTarget::Server => false,
+            MessageTarget::Client(target) => target == client_id,
+            MessageTarget::All => true,
+            MessageTarget::AllBut(excluded) => excluded != client_id,
+        }
+    }
+}
+
+/// The received messages of a type registered with [register_message_type](register_message_type).
+///
+/// Each message comes with its sender, which is `None` for a message from the server.
+/// The messages stay in the inbox until they are drained.
+pub struct Inbox<T> {
+    messages: Vec<(Option<ClientId>, T)>,
+}
+
+impl<T> Inbox<T> {
+    pub fn new() -> Inbox<T> {
+        Inbox {
+            messages: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, sender: Option<ClientId>, message: T) {
+        self.messages.push((sender, message));
+    }
+
+    /// Takes the received messages in the order they arrived.
+    pub fn drain(&mut self) -> Drain<'_, (Option<ClientId>, T)> {
+        self.messages.drain(..)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Option<ClientId>, T)> {
+        self.messages.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl<T> Default for Inbox<T> {
+    fn default() -> Self {
+        Inbox::new()
+    }
+}
+
+/// The messages of a type registered with [register_message_type](register_message_type) that are sent at the next command frame.
+pub struct Outbox<T> {
+    messages: Vec<(MessageTarget, T)>,
+}
+
+impl<T> Outbox<T> {
+    pub fn new() -> Outbox<T> {
+        Outbox {
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn send(&mut self, target: MessageTarget, message: T) {
+        self.messages.push((target, message));
+    }
+
+    pub fn send_to_server(&mut self, message: T) {
+        self.send(MessageTarget::Server, message);
+    }
+
+    pub(crate) fn drain(&mut self) -> Drain<'_, (MessageTarget, T)> {
+        self.messages.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl<T> Default for Outbox<T> {
+    fn default() -> Self {
+        Outbox::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::resources::MessageTarget;
+
+    #[test]
+    fn target_includes_clients_test() {
+        let clients = [1, 2, 3];
+        let included = |target: MessageTarget| {
+            clients
+                .iter()
+                .cloned()
+                .filter(|client_id| target.includes(*client_id))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(included(MessageTarget::Server), vec![]);
+        assert_eq!(included(MessageTarget::Client(2)), vec![2]);
+        assert_eq!(included(MessageTarget::All), vec![1, 2, 3]);
+        assert_eq!(included(MessageTarget::AllBut(2)), vec![1, 3]);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::{hash_map, HashMap};
+
+use crate::protocol::ClientId;
+
+/// Replication statistics of a single client, useful to tune the bandwidth budget.
+#[derive(Clone, Debug, Default)]
+pub struct ClientReplicationMetrics {
+    /// The budget of the last command frame in bytes, `None` without a bandwidth budget.
+    pub budget: Option<usize>,
+    /// The bytes of inserted entities and component changes sent in the last command frame.
+    pub sent_bytes: usize,
+    /// The inserted entities and component changes sent in the last command frame.
+    pub sent_updates: usize,
+    /// The inserted entities and component changes that are waiting to be sent.
+    pub deferred_updates: usize,
+    /// The component changes dropped in the last command frame because their entity or component was removed.
+    pub dropped_updates: usize,
+    /// The changes of unreliable components replaced by a later change in the last command frame.
+    pub superseded_updates: usize,
+
+    pub total_sent_bytes: u64,
+    pub total_dropped_updates: u64,
+    pub total_superseded_updates: u64,
+}
+
+impl ClientReplicationMetrics {
+    pub(crate) fn record(
+        &mut self,
+        budget: Option<usize>,
+        sent_bytes: usize,
+        sent_updates: usize,
+        deferred_updates: usize,
+        dropped_updates: usize,
+        superseded_updates: usize,
+    ) {
+        self.budget = budget;
+        self.sent_bytes = sent_bytes;
+        self.sent_updates = sent_updates;
+        self.deferred_updates = deferred_updates;
+        self.dropped_updates = dropped_updates;
+        self.superseded_updates = superseded_updates;
+
+        self.total_sent_bytes += sent_bytes as u64;
+        self.total_dropped_updates += dropped_updates as u64;
+        self.total_superseded_updates += superseded_updates as u64;
+    }
+}
+
+/// Replication statistics of all connected clients.
+pub struct ReplicationMetrics {
+    clients: HashMap<ClientId, ClientReplicationMetrics>,
+}
+
+impl ReplicationMetrics {
+    pub fn new() -> ReplicationMetrics {
+        ReplicationMetrics {
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<&ClientReplicationMetrics> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn clients(&self) -> hash_map::Iter<'_, ClientId, ClientReplicationMetrics> {
+        self.clients.iter()
+    }
+
+    pub(crate) fn client_mut(&mut self, client_id: ClientId) -> &mut ClientReplicationMetrics {
+        self.clients.entry(client_id).or_default()
+    }
+
+    pub(crate) fn retain_clients(&mut self, is_connected: impl Fn(&ClientId) -> bool) {
+        self.clients.retain(|client_id, _| is_connected(client_id));
+    }
+}
+
+impl Default for ReplicationMetrics {
+    fn default() -> Self {
+        ReplicationMetrics::new()
+    }
+}
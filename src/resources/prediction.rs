@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use legion::Resources;
+
+use net_sync::synchronisation::CommandFrame;
+
+use crate::{
+    codec::{WireCodec, TRACKER_CODEC},
+    protocol::{MessageKind, ResourceState},
+    register::{ResourceRegister, ResourceRegistrationRef, Simulation},
+};
+
+// A change the client made to a predicted resource in a command frame.
+struct PredictedChange {
+    kind: MessageKind,
+    command_frame: CommandFrame,
+    unchanged: Vec<u8>,
+    changed: Vec<u8>,
+}
+
+/// The changes the client predicted for the replicated resources.
+///
+/// Every tick the client world compares the predicted resources with their previous state
+/// and keeps the changes under the current command frame,
+/// like the tracking proxy keeps the predicted component changes in the `ClientCommandBuffer`.
+/// Once the state update of a command frame is applied, the predicted difference is compared with the difference of the server.
+/// A resource that was predicted wrong is rolled back to its state before the command frame, the server difference is applied to it
+/// and the command frame is reported by [take_resimulation](ResourcePredictionBuffer::take_resimulation).
+pub struct ResourcePredictionBuffer {
+    states: HashMap<MessageKind, Vec<u8>>,
+    changes: Vec<PredictedChange>,
+    resimulate_from: Option<CommandFrame>,
+}
+
+impl ResourcePredictionBuffer {
+    pub fn new() -> ResourcePredictionBuffer {
+        ResourcePredictionBuffer {
+            states: HashMap::new(),
+            changes: Vec::new(),
+            resimulate_from: None,
+        }
+    }
+
+    /// Returns the number of predicted changes that wait for their state update.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the earliest command frame a resource was rolled back to since the last call,
+    /// the systems that change the predicted resources should simulate the command frames after it again.
+    pub fn take_resimulation(&mut self) -> Option<CommandFrame> {
+        self.resimulate_from.take()
+    }
+
+    /// Keeps the changes of the predicted resources since the previous call as changes of the command frame.
+    pub(crate) fn track(&mut self, resources: &Resources, command_frame: CommandFrame) {
+        for registration in ResourceRegister.iter() {
+            if registration.simulation() != Simulation::Predicted {
+                continue;
+            }
+
+            let current = match serialize(resources, registration) {
+                Some(current) => current,
+                None => continue,
+            };
+
+            let kind = registration.kind();
+
+            match self.states.insert(kind, current.clone()) {
+                Some(unchanged) if unchanged != current => self.changes.push(PredictedChange {
+                    kind,
+                    command_frame,
+                    unchanged,
+                    changed: current,
+                }),
+                _ => {}
+            }
+        }
+    }
+
+    /// Forgets the predictions and starts tracking from the current state, e.g. after the resources were replaced by a welcome.
+    pub(crate) fn reset(&mut self, resources: &Resources) {
+        self.changes.clear();
+        self.states.clear();
+        self.resimulate_from = None;
+
+        for registration in ResourceRegister.iter() {
+            if registration.simulation() == Simulation::Predicted {
+                if let Some(current) = serialize(resources, registration) {
+                    self.states.insert(registration.kind(), current);
+                }
+            }
+        }
+    }
+
+    /// Applies the resource differences of the state update of the command frame and corrects the wrong predictions.
+    pub(crate) fn reconcile(
+        &mut self,
+        resources: &Resources,
+        differences: &[ResourceState],
+        command_frame: CommandFrame,
+        codec: WireCodec,
+    ) {
+        for registration in ResourceRegister.iter() {
+            let kind = registration.kind();
+            let server_difference = differences.iter().find(|x| x.kind == kind);
+
+            // The oldest unchanged and the latest changed state up to the command frame,
+            // the server sends no update for command frames without changes.
+            let predicted = {
+                let mut frame_changes = self
+                    .changes
+                    .iter()
+                    .filter(|x| x.kind == kind && x.command_frame <= command_frame);
+
+                frame_changes.next().map(|oldest| {
+                    let latest = frame_changes.last().unwrap_or(oldest);
+                    (oldest.unchanged.clone(), latest.changed.clone())
+                })
+            };
+
+            let (unchanged, changed) = match predicted {
+                Some(predicted) => predicted,
+                None => {
+                    // Nothing was predicted up to this command frame, the server state is applied as is.
+                    if let Some(difference) = server_difference {
+                        apply_difference(resources, registration, difference, codec);
+                        self.refresh(resources, registration, kind);
+                    }
+                    continue;
+                }
+            };
+
+            self.changes
+                .retain(|x| x.kind != kind || x.command_frame > command_frame);
+
+            // The difference is encoded the same way as on the server, therefore equal differences have equal bytes.
+            let (result, predicted_difference) =
+                TRACKER_CODEC.deserialize_erased(&unchanged, |unchanged| {
+                    TRACKER_CODEC.deserialize_erased(&changed, |changed| {
+                        codec.serialize_erased(|serializer| {
+                            registration.serialize_difference(unchanged, changed, serializer)
+                        })
+                    })
+                });
+
+            let is_correct = match (result, server_difference) {
+                (Ok(true), Some(difference)) => difference.data == predicted_difference,
+                (Ok(false), None) => true,
+                (Ok(_), _) => false,
+                (Err(e), _) => {
+                    log::error!(
+                        "Cannot serialize the predicted difference of resource {}: {:?}",
+                        registration.type_name(),
+                        e
+                    );
+                    false
+                }
+            };
+
+            if is_correct {
+                continue;
+            }
+
+            // Roll back to the state before the command frame and continue from the server state.
+            if let Err(e) = TRACKER_CODEC
+                .deserialize_erased(&unchanged, |data| registration.replace(resources, data))
+            {
+                log::error!(
+                    "Cannot roll back resource {}: {}",
+                    registration.type_name(),
+                    e
+                );
+            }
+            if let Some(difference) = server_difference {
+                apply_difference(resources, registration, difference, codec);
+            }
+
+            // The later predictions build on the wrong state.
+            self.changes.retain(|x| x.kind != kind);
+            self.refresh(resources, registration, kind);
+            self.resimulate_from = Some(
+                self.resimulate_from
+                    .map_or(command_frame, |frame| frame.min(command_frame)),
+            );
+        }
+    }
+
+    // Take the state the resource was given as its previous state, such that it is not tracked as a predicted change.
+    fn refresh(
+        &mut self,
+        resources: &Resources,
+        registration: ResourceRegistrationRef,
+        kind: MessageKind,
+    ) {
+        if registration.simulation() != Simulation::Predicted {
+            return;
+        }
+
+        if let Some(current) = serialize(resources, registration) {
+            self.states.insert(kind, current);
+        }
+    }
+}
+
+impl Default for ResourcePredictionBuffer {
+    fn default() -> Self {
+        ResourcePredictionBuffer::new()
+    }
+}
+
+fn serialize(resources: &Resources, registration: ResourceRegistrationRef) -> Option<Vec<u8>> {
+    let mut current = None;
+
+    registration.serialize_if_exists(resources, &mut |resource| {
+        let (result, data) = TRACKER_CODEC
+            .serialize_erased(|serializer| erased_serde::serialize(resource, serializer));
+
+        match result {
+            Ok(_) => current = Some(data),
+            Err(e) => log::error!(
+                "Cannot serialize resource {}: {}",
+                registration.type_name(),
+                e
+            ),
+        }
+    });
+
+    current
+}
+
+fn apply_difference(
+    resources: &Resources,
+    registration: ResourceRegistrationRef,
+    difference: &ResourceState,
+    codec: WireCodec,
+) {
+    let result = codec.deserialize_erased(&difference.data, |deserializer| {
+        registration.apply_changes(resources, deserializer)
+    });
+
+    if let Err(e) = result {
+        log::error!(
+            "Dropping resource {} from the server: {}",
+            registration.type_name(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use legion::Resources;
+
+    use crate::{
+        codec::{WireCodec, TRACKER_CODEC},
+        protocol::{MessageKind, ResourceState},
+        register::ResourceRegister,
+        resources::ResourcePredictionBuffer,
+        tracking::{re_exports::serde_diff::*, track_attr::*},
+    };
+
+    #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize, SerdeDiff)]
+    struct Timer {
+        ticks: u32,
+        paused: bool,
+    }
+
+    const TIMER_KIND: MessageKind = 2;
+
+    crate::register_resource_type!(Timer, TIMER_KIND);
+
+    const CODEC: WireCodec = WireCodec::BincodeFixint;
+
+    // The difference the server sends when the timer changes from `unchanged` to `changed`.
+    fn server_difference(unchanged: &Timer, changed: &Timer) -> ResourceState {
+        let registration = ResourceRegister::by_kind()
+            .get(TIMER_KIND)
+            .expect("Should be registered");
+        let unchanged = TRACKER_CODEC.serialize(unchanged).unwrap();
+        let changed = TRACKER_CODEC.serialize(changed).unwrap();
+
+        let (result, data) = TRACKER_CODEC.deserialize_erased(&unchanged, |unchanged| {
+            TRACKER_CODEC.deserialize_erased(&changed, |changed| {
+                CODEC.serialize_erased(|serializer| {
+                    registration.serialize_difference(unchanged, changed, serializer)
+                })
+            })
+        });
+        assert!(result.unwrap());
+
+        ResourceState {
+            kind: TIMER_KIND,
+            data,
+        }
+    }
+
+    fn resources() -> (Resources, ResourcePredictionBuffer) {
+        let mut resources = Resources::default();
+        resources.insert(Timer::default());
+
+        let mut predictions = ResourcePredictionBuffer::new();
+        predictions.reset(&resources);
+        (resources, predictions)
+    }
+
+    #[test]
+    fn correct_prediction_is_kept_test() {
+        let (resources, mut predictions) = resources();
+
+        resources.get_mut::<Timer>().unwrap().ticks = 1;
+        predictions.track(&resources, 1);
+        resources.get_mut::<Timer>().unwrap().ticks = 2;
+        predictions.track(&resources, 2);
+        assert_eq!(predictions.len(), 2);
+
+        let difference = server_difference(
+            &Timer::default(),
+            &Timer {
+                ticks: 1,
+                paused: false,
+            },
+        );
+        predictions.reconcile(&resources, &[difference], 1, CODEC);
+
+        assert_eq!(resources.get::<Timer>().unwrap().ticks, 2);
+        assert_eq!(predictions.len(), 1);
+        assert_eq!(predictions.take_resimulation(), None);
+    }
+
+    #[test]
+    fn wrong_prediction_is_rolled_back_test() {
+        let (resources, mut predictions) = resources();
+
+        resources.get_mut::<Timer>().unwrap().ticks = 1;
+        predictions.track(&resources, 1);
+        resources.get_mut::<Timer>().unwrap().ticks = 2;
+        predictions.track(&resources, 2);
+
+        // The server paused the timer instead.
+        let difference = server_difference(
+            &Timer::default(),
+            &Timer {
+                ticks: 0,
+                paused: true,
+            },
+        );
+        predictions.reconcile(&resources, &[difference], 1, CODEC);
+
+        assert_eq!(
+            *resources.get::<Timer>().unwrap(),
+            Timer {
+                ticks: 0,
+                paused: true
+            }
+        );
+        assert!(predictions.is_empty());
+        assert_eq!(predictions.take_resimulation(), Some(1));
+
+        // The correction is not tracked as a prediction.
+        predictions.track(&resources, 3);
+        assert!(predictions.is_empty());
+    }
+
+    #[test]
+    fn change_the_server_did_not_make_is_rolled_back_test() {
+        let (resources, mut predictions) = resources();
+
+        resources.get_mut::<Timer>().unwrap().ticks = 1;
+        predictions.track(&resources, 1);
+
+        predictions.reconcile(&resources, &[], 1, CODEC);
+
+        assert_eq!(*resources.get::<Timer>().unwrap(), Timer::default());
+        assert_eq!(predictions.take_resimulation(), Some(1));
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{hash_map, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::ClientId;
+
+/// The time between two pings.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+/// The number of unanswered pings that are remembered, older pings are considered lost.
+const MAX_PENDING_PINGS: usize = 16;
+
+/// Estimates the round-trip time of a connection from ping/pong exchanges.
+///
+/// The smoothed round-trip time and its variation are calculated like TCP does (RFC 6298).
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+    smoothed: Option<Duration>,
+    variation: Duration,
+    last_sample: Option<Duration>,
+    pending: VecDeque<(u16, Instant)>,
+    next_ping: u16,
+    last_ping: Option<Instant>,
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        RttEstimator {
+            smoothed: None,
+            variation: Duration::from_millis(0),
+            last_sample: None,
+            pending: VecDeque::new(),
+            next_ping: 0,
+            last_ping: None,
+        }
+    }
+
+    /// Returns the smoothed round-trip time, `None` until the first pong arrived.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// Returns the variation of the round-trip time.
+    pub fn jitter(&self) -> Duration {
+        self.variation
+    }
+
+    /// Returns the round-trip time of the latest ping.
+    pub fn last_sample(&self) -> Option<Duration> {
+        self.last_sample
+    }
+
+    /// Returns the id of a new ping if it is time to send one.
+    pub(crate) fn ping(&mut self, now: Instant) -> Option<u16> {
+        if let Some(last_ping) = self.last_ping {
+            if now.duration_since(last_ping) < PING_INTERVAL {
+                return None;
+            }
+        }
+
+        let id = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.last_ping = Some(now);
+
+        self.pending.push_back((id, now));
+        if self.pending.len() > MAX_PENDING_PINGS {
+            self.pending.pop_front();
+        }
+
+        Some(id)
+    }
+
+    /// Handles the pong for the given ping, unknown and duplicate pongs are ignored.
+    pub(crate) fn pong(&mut self, id: u16, now: Instant) {
+        if let Some(index) = self.pending.iter().position(|x| x.0 == id) {
+            let (_, sent_at) = self.pending.remove(index).expect("Index should exist.");
+            self.sample(now.duration_since(sent_at));
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        self.last_sample = Some(rtt);
+
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variation = rtt / 2;
+            }
+            Some(smoothed) => {
+                let deviation = if smoothed > rtt {
+                    smoothed - rtt
+                } else {
+                    rtt - smoothed
+                };
+
+                self.variation = self.variation * 3 / 4 + deviation / 4;
+                self.smoothed = Some(smoothed * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        RttEstimator::new()
+    }
+}
+
+/// The round-trip times of all connected clients, kept by the [ServerPostOffice](crate::protocol::ServerPostOffice).
+pub struct RoundTripTimes {
+    clients: HashMap<ClientId, RttEstimator>,
+}
+
+impl RoundTripTimes {
+    pub fn new() -> RoundTripTimes {
+        RoundTripTimes {
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn client(&self, client_id: ClientId) -> Option<&RttEstimator> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn clients(&self) -> hash_map::Iter<'_, ClientId, RttEstimator> {
+        self.clients.iter()
+    }
+
+    pub(crate) fn client_mut(&mut self, client_id: ClientId) -> &mut RttEstimator {
+        self.clients.entry(client_id).or_default()
+    }
+
+    pub(crate) fn retain_clients(&mut self, is_connected: impl Fn(&ClientId) -> bool) {
+        self.clients.retain(|client_id, _| is_connected(client_id));
+    }
+}
+
+impl Default for RoundTripTimes {
+    fn default() -> Self {
+        RoundTripTimes::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::resources::RttEstimator;
+
+    #[test]
+    fn first_sample_sets_rtt_test() {
+        let now = Instant::now();
+        let mut estimator = RttEstimator::new();
+
+        let id = estimator.ping(now).unwrap();
+        estimator.pong(id, now + Duration::from_millis(100));
+
+        assert_eq!(estimator.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(estimator.jitter(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn rtt_is_smoothed_test() {
+        let now = Instant::now();
+        let mut estimator = RttEstimator::new();
+
+        let id = estimator.ping(now).unwrap();
+        estimator.pong(id, now + Duration::from_millis(100));
+
+        let later = now + Duration::from_secs(1);
+        let id = estimator.ping(later).unwrap();
+        estimator.pong(id, later + Duration::from_millis(180));
+
+        assert_eq!(estimator.rtt(), Some(Duration::from_millis(110)));
+        assert_eq!(estimator.last_sample(), Some(Duration::from_millis(180)));
+    }
+
+    #[test]
+    fn pings_are_rate_limited_test() {
+        let now = Instant::now();
+        let mut estimator = RttEstimator::new();
+
+        assert!(estimator.ping(now).is_some());
+        assert!(estimator.ping(now + Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn unknown_pong_is_ignored_test() {
+        let mut estimator = RttEstimator::new();
+        estimator.pong(3, Instant::now());
+
+        assert_eq!(estimator.rtt(), None);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::{ClientId, SessionToken};
+
+/// The time a disconnected client has to resume its session before its owned entities are removed.
+pub const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+struct ClosedSession {
+    client_id: ClientId,
+    closed_at: Instant,
+}
+
+/// The sessions of the clients of the server.
+///
+/// A session is opened when a new client sent its hello and its token is sent along with the welcome.
+/// When the connection drops the session is closed but kept for a grace period,
+/// a client that reconnects in time sends its token with its hello, resumes the session and takes over the entities of its previous connection.
+pub struct Sessions {
+    open: HashMap<ClientId, SessionToken>,
+    closed: HashMap<SessionToken, ClosedSession>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            open: HashMap::new(),
+            closed: HashMap::new(),
+        }
+    }
+
+    /// Returns the session token of the connected client.
+    pub fn token(&self, client_id: ClientId) -> Option<SessionToken> {
+        self.open.get(&client_id).cloned()
+    }
+
+    /// Returns the connected client with the given session.
+    pub fn client(&self, token: SessionToken) -> Option<ClientId> {
+        self.open
+            .iter()
+            .find(|(_, x)| **x == token)
+            .map(|(client_id, _)| *client_id)
+    }
+
+    /// Returns the number of closed sessions that can still be resumed.
+    pub fn closed(&self) -> usize {
+        self.closed.len()
+    }
+
+    /// Opens a session for a new client and returns its token.
+    pub(crate) fn open(&mut self, client_id: ClientId) -> SessionToken {
+        let token = loop {
+            let token = rand::random::<SessionToken>();
+
+            if !self.closed.contains_key(&token) && self.client(token).is_none() {
+                break token;
+            }
+        };
+
+        self.open.insert(client_id, token);
+        token
+    }
+
+    /// Closes the sessions of the clients that are no longer connected.
+    pub(crate) fn close_gone(&mut self, is_connected: impl Fn(&ClientId) -> bool, now: Instant) {
+        let gone = self
+            .open
+            .keys()
+            .filter(|client_id| !is_connected(client_id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for client_id in gone {
+            if let Some(token) = self.open.remove(&client_id) {
+                self.closed.insert(
+                    token,
+                    ClosedSession {
+                        client_id,
+                        closed_at: now,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Ends the session of the client without a grace period, it can no longer be resumed.
+    pub(crate) fn end(&mut self, client_id: ClientId) -> Option<SessionToken> {
+        self.open.remove(&client_id)
+    }
+
+    /// Moves the closed session with the given token to the client.
+    ///
+    /// Returns the client id of the previous connection, `None` when there is no such closed session.
+    /// A session that is still open cannot be taken over, the client continues as a new client.
+    pub(crate) fn resume(&mut self, client_id: ClientId, token: SessionToken) -> Option<ClientId> {
+        let closed = self.closed.remove(&token)?;
+
+        self.open.insert(client_id, token);
+        Some(closed.client_id)
+    }
+
+    /// Forgets the sessions that were closed longer than the grace period ago,
+    /// returns the client ids of their last connection.
+    pub(crate) fn expire(&mut self, grace_period: Duration, now: Instant) -> Vec<ClientId> {
+        let mut expired = Vec::new();
+
+        self.closed.retain(|_, closed| {
+            if now.duration_since(closed.closed_at) < grace_period {
+                true
+            } else {
+                expired.push(closed.client_id);
+                false
+            }
+        });
+
+        expired
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions::new()
+    }
+}
+
+/// The sessions that are banned from the server.
+///
+/// The transport does not tell the address a connection comes from,
+/// a client is therefore refused with its hello, once it presents the session it resumes.
+pub struct BanList {
+    sessions: HashSet<SessionToken>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList {
+            sessions: HashSet::new(),
+        }
+    }
+
+    pub fn ban(&mut self, token: SessionToken) {
+        self.sessions.insert(token);
+    }
+
+    /// Lifts the ban, returns `false` when the session was not banned.
+    pub fn unban(&mut self, token: SessionToken) -> bool {
+        self.sessions.remove(&token)
+    }
+
+    pub fn is_banned(&self, token: SessionToken) -> bool {
+        self.sessions.contains(&token)
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        BanList::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::resources::{BanList, Sessions};
+
+    #[test]
+    fn closed_session_is_resumed_test() {
+        let mut sessions = Sessions::new();
+        let now = Instant::now();
+
+        let token = sessions.open(1);
+        sessions.close_gone(|client_id| *client_id != 1, now);
+        assert_eq!(sessions.token(1), None);
+        assert_eq!(sessions.closed(), 1);
+
+        assert_eq!(sessions.resume(2, token), Some(1));
+        assert_eq!(sessions.token(2), Some(token));
+        assert_eq!(sessions.closed(), 0);
+    }
+
+    #[test]
+    fn open_session_is_not_resumed_test() {
+        let mut sessions = Sessions::new();
+
+        let token = sessions.open(1);
+        let other = sessions.open(2);
+
+        assert_eq!(sessions.resume(2, token), None);
+        assert_eq!(sessions.token(1), Some(token));
+        assert_eq!(sessions.token(2), Some(other));
+    }
+
+    #[test]
+    fn session_expires_after_grace_period_test() {
+        let mut sessions = Sessions::new();
+        let now = Instant::now();
+
+        let token = sessions.open(1);
+        sessions.close_gone(|_| false, now);
+
+        assert!(sessions
+            .expire(Duration::from_secs(5), now + Duration::from_secs(4))
+            .is_empty());
+        assert_eq!(
+            sessions.expire(Duration::from_secs(5), now + Duration::from_secs(5)),
+            vec![1]
+        );
+        assert_eq!(sessions.resume(2, token), None);
+    }
+
+    #[test]
+    fn session_ban_is_lifted_test() {
+        let mut bans = BanList::new();
+        let mut sessions = Sessions::new();
+        let token = sessions.open(1);
+
+        bans.ban(token);
+        assert!(bans.is_banned(token));
+        assert!(!bans.is_banned(sessions.open(2)));
+
+        assert!(bans.unban(token));
+        assert!(!bans.is_banned(token));
+        assert!(!bans.unban(token));
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use net_sync::{synchronisation::CommandFrame, uid::Uid};
+
+const INDEX_BITS: u32 = 24;
+const INDEX_MASK: Uid = (1 << INDEX_BITS) - 1;
+
+/// Returns the index part of a network id.
+pub fn uid_index(uid: Uid) -> u32 {
+    uid & INDEX_MASK
+}
+
+/// Returns the generation part of a network id.
+pub fn uid_generation(uid: Uid) -> u8 {
+    (uid >> INDEX_BITS) as u8
+}
+
+fn compose_uid(index: u32, generation: u8) -> Uid {
+    ((generation as Uid) << INDEX_BITS) | (index & INDEX_MASK)
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Slot {
+    generation: u8,
+    alive: bool,
+}
+
+/// Hands out network ids for entities and recycles them once they are no longer referenced.
+///
+/// A network id is made up of an index and a generation.
+/// Each time an index is reused its generation is bumped,
+/// therefore a stale id still known to a client never resolves to the entity that reuses the index.
+///
+/// On the server a freed id is kept in quarantine until every client acknowledged the state update that removed it.
+/// On the client the ids issued by the server are claimed and released to detect stale references.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UidRecycler {
+    slots: Vec<Slot>,
+    free: VecDeque<u32>,
+    quarantine: VecDeque<(Uid, CommandFrame)>,
+}
+
+impl UidRecycler {
+    pub fn new() -> UidRecycler {
+        UidRecycler {
+            // Index zero is reserved, it is the id of an unidentified entity.
+            slots: vec![Slot::default()],
+            free: VecDeque::new(),
+            quarantine: VecDeque::new(),
+        }
+    }
+
+    /// Allocates a new network id, reusing a released index if there is one.
+    ///
+    /// Returns `None` when all indices are in use or waiting for acknowledgement.
+    pub fn allocate(&mut self) -> Option<Uid> {
+        let index = match self.free.pop_front() {
+            Some(index) => index,
+            // A higher index would alias a lower one once the generation is put in front of it.
+            None if self.slots.len() > INDEX_MASK as usize => return None,
+            None => {
+                self.slots.push(Slot::default());
+                (self.slots.len() - 1) as u32
+            }
+        };
+
+        let slot = &mut self.slots[index as usize];
+        slot.alive = true;
+
+        Some(compose_uid(index, slot.generation))
+    }
+
+    /// Frees the given network id which was removed in the state update of the given command frame.
+    ///
+    /// The id is not reused before [release_acknowledged](UidRecycler::release_acknowledged) is called with that command frame.
+    pub fn free(&mut self, uid: Uid, command_frame: CommandFrame) {
+        if self.is_live(uid) {
+            self.slots[uid_index(uid) as usize].alive = false;
+            self.quarantine.push_back((uid, command_frame));
+        }
+    }
+
+    /// Makes all ids freed up to and including the given command frame available for reuse.
+    ///
+    /// Returns the number of released ids.
+    pub fn release_acknowledged(&mut self, acknowledged_frame: CommandFrame) -> usize {
+        let mut released = 0;
+
+        while let Some((uid, command_frame)) = self.quarantine.front().cloned() {
+            if command_frame > acknowledged_frame {
+                break;
+            }
+
+            self.quarantine.pop_front();
+
+            let index = uid_index(uid);
+            let slot = &mut self.slots[index as usize];
+            slot.generation = slot.generation.wrapping_add(1);
+            self.free.push_back(index);
+
+            released += 1;
+        }
+
+        released
+    }
+
+    /// Marks an id issued by the server as live.
+    pub fn claim(&mut self, uid: Uid) {
+        let index = uid_index(uid) as usize;
+
+        if self.slots.len() <= index {
+            self.slots.resize(index + 1, Slot::default());
+        }
+
+        self.slots[index] = Slot {
+            generation: uid_generation(uid),
+            alive: true,
+        };
+    }
+
+    /// Marks an id issued by the server as no longer live.
+    pub fn release(&mut self, uid: Uid) {
+        if self.is_live(uid) {
+            self.slots[uid_index(uid) as usize].alive = false;
+        }
+    }
+
+    /// Returns whether the id refers to a live entity of the current generation.
+    pub fn is_live(&self, uid: Uid) -> bool {
+        match self.slots.get(uid_index(uid) as usize) {
+            Some(slot) => slot.alive && slot.generation == uid_generation(uid),
+            None => false,
+        }
+    }
+
+    /// Returns the number of ids waiting for acknowledgement before they can be reused.
+    pub fn quarantined(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    /// Returns the live ids.
+    pub fn live(&self) -> impl Iterator<Item = Uid> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.alive)
+            .map(|(index, slot)| compose_uid(index as u32, slot.generation))
+    }
+}
+
+impl Default for UidRecycler {
+    fn default() -> Self {
+        UidRecycler::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::resources::{
+        uid::{Slot, INDEX_MASK},
+        uid_generation, uid_index, UidRecycler,
+    };
+
+    #[test]
+    fn allocation_should_start_at_one_test() {
+        let mut recycler = UidRecycler::new();
+
+        assert_eq!(uid_index(recycler.allocate().unwrap()), 1);
+        assert_eq!(uid_index(recycler.allocate().unwrap()), 2);
+    }
+
+    #[test]
+    fn freed_id_is_not_reused_before_acknowledged_test() {
+        let mut recycler = UidRecycler::new();
+
+        let uid = recycler.allocate().unwrap();
+        recycler.free(uid, 10);
+
+        assert_ne!(uid_index(recycler.allocate().unwrap()), uid_index(uid));
+        assert_eq!(recycler.quarantined(), 1);
+    }
+
+    #[test]
+    fn reused_id_has_new_generation_test() {
+        let mut recycler = UidRecycler::new();
+
+        let uid = recycler.allocate().unwrap();
+        recycler.free(uid, 10);
+
+        assert_eq!(recycler.release_acknowledged(9), 0);
+        assert_eq!(recycler.release_acknowledged(10), 1);
+
+        let reused = recycler.allocate().unwrap();
+
+        assert_eq!(uid_index(reused), uid_index(uid));
+        assert_eq!(uid_generation(reused), uid_generation(uid) + 1);
+        assert!(!recycler.is_live(uid));
+        assert!(recycler.is_live(reused));
+    }
+
+    #[test]
+    fn claimed_ids_detect_stale_references_test() {
+        let mut server = UidRecycler::new();
+        let mut client = UidRecycler::new();
+
+        let uid = server.allocate().unwrap();
+        client.claim(uid);
+        assert!(client.is_live(uid));
+
+        server.free(uid, 1);
+        server.release_acknowledged(1);
+        let reused = server.allocate().unwrap();
+
+        client.release(uid);
+        client.claim(reused);
+
+        assert!(!client.is_live(uid));
+        assert!(client.is_live(reused));
+    }
+
+    #[test]
+    fn allocation_stops_at_last_index_test() {
+        let mut recycler = UidRecycler::new();
+        recycler.slots.resize(INDEX_MASK as usize, Slot::default());
+
+        let last = recycler.allocate().unwrap();
+        assert_eq!(uid_index(last), INDEX_MASK);
+        assert_eq!(recycler.allocate(), None);
+
+        // A released index can still be reused.
+        recycler.free(last, 1);
+        recycler.release_acknowledged(1);
+        assert_eq!(uid_index(recycler.allocate().unwrap()), INDEX_MASK);
+    }
+}
@@ -1,17 +1,22 @@
+use std::time::Instant;
+
 use legion::systems::{Builder, SystemBuilder};
 
 use net_sync::{
     synchronisation::{CommandFrameTicker, NetworkCommand, NetworkMessage},
-    transport,
-    transport::{
-        tcp::{TcpClientResource, TcpListenerResource},
-        PostBox, PostOffice,
-    },
+    transport::tcp::{TcpClientResource, TcpListenerResource},
 };
 
-use crate::resources::BufferResource;
+use crate::{
+    protocol::{ClientId, ClientPostBox, ClientToServer, ServerPostOffice, ServerToClient},
+    resources::{BufferResource, LinkConditioner},
+};
 use net_sync::event::NetworkEventQueue;
 
+// The received and sent messages pass through the link conditioner, which is transparent with perfect conditions.
+// Messages that arrived earlier and wait in the inbox are not conditioned again.
+// Pings are answered and pongs are stamped when they arrive, they never wait in the inbox.
+
 pub fn tcp_connection_listener<
     ServerToClientMessage: NetworkMessage,
     ClientToServerMessage: NetworkMessage,
@@ -21,7 +26,7 @@ pub fn tcp_connection_listener<
 ) -> Builder {
     builder.add_system(SystemBuilder::new("tcp_connection_listener")
         .write_resource::<TcpListenerResource>()
-        .write_resource::<PostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
+        .write_resource::<ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
         .write_resource::<NetworkEventQueue>()
         .build(|_, _, resources, _| {
             net_sync::transport::tcp::tcp_connection_listener(&mut resources.0, &mut resources.1, &mut resources.2);
@@ -39,19 +44,36 @@ pub fn tcp_client_receive_system<
     builder.add_system(
         SystemBuilder::new("tcp_client_receive_system")
             .write_resource::<TcpClientResource>()
-            .write_resource::<PostBox<
-                transport::ServerToClientMessage<ServerToClientMessage>,
-                transport::ClientToServerMessage<ClientToServerMessage, ClientToServerCommand>,
+            .write_resource::<ClientPostBox<
+                ServerToClientMessage,
+                ClientToServerMessage,
+                ClientToServerCommand,
             >>()
             .write_resource::<BufferResource>()
             .write_resource::<NetworkEventQueue>()
+            .write_resource::<LinkConditioner<ServerToClient<ServerToClientMessage>>>()
+            .read_resource::<CommandFrameTicker>()
             .build(|_, _, resources, _| {
+                let waiting = resources.1.drain_inbox(|_| true);
+
                 net_sync::transport::tcp::tcp_client_receive_system(
                     &mut resources.0,
                     &mut resources.1,
                     &mut resources.3,
                     &mut resources.2.recv_buffer,
-                )
+                );
+
+                let received = resources.1.drain_inbox(|_| true);
+                let arrived = resources.4.transmit(
+                    received,
+                    resources.5.command_frame(),
+                    resources.5.default_simulation_speed() as f32,
+                );
+                let arrived = resources.1.exchange_pings(arrived, Instant::now());
+
+                for message in waiting.into_iter().chain(arrived) {
+                    resources.1.add_to_inbox(message);
+                }
             }),
     )
 }
@@ -67,12 +89,28 @@ pub fn tcp_client_sent_system<
     builder.add_system(
         SystemBuilder::new("tcp_client_sent_system")
             .write_resource::<TcpClientResource>()
-            .write_resource::<PostBox<
-                transport::ServerToClientMessage<ServerToClientMessage>,
-                transport::ClientToServerMessage<ClientToServerMessage, ClientToServerCommand>,
+            .write_resource::<ClientPostBox<
+                ServerToClientMessage,
+                ClientToServerMessage,
+                ClientToServerCommand,
             >>()
             .write_resource::<NetworkEventQueue>()
+            .write_resource::<LinkConditioner<ClientToServer<ClientToServerMessage, ClientToServerCommand>>>()
+            .read_resource::<CommandFrameTicker>()
             .build(|_, _, resources, _| {
+                resources.1.send_ping(Instant::now());
+
+                let outgoing = resources.1.drain_outgoing(|_| true);
+                let arrived = resources.3.transmit(
+                    outgoing,
+                    resources.4.command_frame(),
+                    resources.4.default_simulation_speed() as f32,
+                );
+
+                for message in arrived {
+                    resources.1.send(message);
+                }
+
                 net_sync::transport::tcp::tcp_client_sent_system(
                     &mut resources.0,
                     &mut resources.1,
@@ -92,12 +130,33 @@ pub fn tcp_server_receive_system<
 ) -> Builder {
     builder.add_system(SystemBuilder::new("tcp_server_receive_system")
         .write_resource::<TcpListenerResource>()
-        .write_resource::<PostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
+        .write_resource::<ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
         .write_resource::<BufferResource>()
         .write_resource::<NetworkEventQueue>()
         .read_resource::<CommandFrameTicker>()
+        .write_resource::<LinkConditioner<(ClientId, ClientToServer<ClientToServerMessage, ClientToServerCommand>)>>()
         .build(|_, _, resources, _| {
-            net_sync::transport::tcp::tcp_server_receive_system(&mut resources.0, &mut resources.1, resources.4.command_frame(), &mut resources.3, &mut resources.2.recv_buffer)
+            let mut waiting = Vec::new();
+            for (id, client) in resources.1.clients_mut() {
+                waiting.extend(client.postbox_mut().drain_inbox(|_| true).into_iter().map(|message| (*id, message)));
+            }
+
+            net_sync::transport::tcp::tcp_server_receive_system(&mut resources.0, &mut resources.1, resources.4.command_frame(), &mut resources.3, &mut resources.2.recv_buffer);
+
+            let mut received = Vec::new();
+            for (id, client) in resources.1.clients_mut() {
+                received.extend(client.postbox_mut().drain_inbox(|_| true).into_iter().map(|message| (*id, message)));
+            }
+
+            let arrived = resources.5.transmit(received, resources.4.command_frame(), resources.4.default_simulation_speed() as f32);
+            let arrived = resources.1.exchange_pings(arrived, Instant::now());
+
+            // Messages of clients that are gone in the meantime are dropped.
+            for (id, message) in waiting.into_iter().chain(arrived) {
+                if let Some((_, client)) = resources.1.clients_mut().find(|x| *x.0 == id) {
+                    client.postbox_mut().add_to_inbox(message);
+                }
+            }
         }))
 }
 
@@ -111,9 +170,26 @@ pub fn tcp_server_sent_system<
 ) -> Builder {
     builder.add_system(SystemBuilder::new("tcp_server_sent_system")
         .write_resource::<TcpListenerResource>()
-        .write_resource::<PostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
+        .write_resource::<ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
         .write_resource::<NetworkEventQueue>()
+        .write_resource::<LinkConditioner<(ClientId, ServerToClient<ServerToClientMessage>)>>()
+        .read_resource::<CommandFrameTicker>()
         .build(|_, _, resources, _| {
+            resources.1.send_pings(Instant::now());
+
+            let mut outgoing = Vec::new();
+            for (id, client) in resources.1.clients_mut() {
+                outgoing.extend(client.postbox_mut().drain_outgoing(|_| true).into_iter().map(|message| (*id, message)));
+            }
+
+            let arrived = resources.3.transmit(outgoing, resources.4.command_frame(), resources.4.default_simulation_speed() as f32);
+
+            for (id, message) in arrived {
+                if let Some((_, client)) = resources.1.clients_mut().find(|x| *x.0 == id) {
+                    client.postbox_mut().send(message);
+                }
+            }
+
             net_sync::transport::tcp::tcp_server_sent_system(&mut resources.0, &mut resources.1, &mut resources.2);
         }))
 }
@@ -4,8 +4,19 @@ use crate::register::ComponentRegistration;
 use legion::{world::SubWorld, Entity, World};
 use net_sync::compression::CompressionStrategy;
 
+pub mod bandwidth;
+pub mod change_detection;
 pub mod client;
+pub mod clock;
+pub mod entity_event;
+pub mod interest;
+pub mod lag_compensation;
+pub mod lifecycle;
+pub mod reconnect;
+pub mod replay;
+pub mod sequence;
 pub mod server;
+pub mod snapshot;
 pub mod world_instance;
 
 pub trait WorldBuilder {
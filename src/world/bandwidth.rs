@@ -0,0 +1,618 @@
+//! Limits the component changes that are sent to a client each command frame.
+//!
+//! Changes that do not fit in the budget are deferred to a later command frame.
+//! A deferred change of an unreliable component is superseded by a later change of the same component.
+//! Entities are ordered by an accumulating priority, every command frame an entity waits,
+//! its priority grows with the weight of its pending changes.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use net_sync::{
+    synchronisation::{ComponentData, WorldState},
+    uid::Uid,
+};
+
+use crate::resources::ClientReplicationMetrics;
+
+/// Estimated size of a change next to its component data: the entity id, component id and data length.
+const CHANGE_OVERHEAD: usize = 16;
+
+/// The amount of component changes that may be sent to a single client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandwidthBudget {
+    BytesPerSecond(usize),
+    BytesPerTick(usize),
+}
+
+impl BandwidthBudget {
+    /// Returns the budget for a single command frame.
+    pub fn bytes_per_tick(&self, ticks_per_second: f32) -> usize {
+        match *self {
+            BandwidthBudget::BytesPerSecond(bytes) => (bytes as f32 / ticks_per_second) as usize,
+            BandwidthBudget::BytesPerTick(bytes) => bytes,
+        }
+    }
+}
+
+struct PendingEntity {
+    priority: f32,
+    weight: f32,
+    // The entity is unknown to the client until its insert is sent, the number orders the inserts.
+    insert: Option<(u64, Vec<ComponentData>)>,
+    changes: Vec<PendingChange>,
+}
+
+struct PendingChange {
+    data: ComponentData,
+    // Whether the component is added to the entity, instead of changed.
+    added: bool,
+    // The state the client knows of an unreliable component, a later change is compared against it.
+    baseline: Option<Vec<u8>>,
+}
+
+/// The changes that are selected to be sent this command frame.
+pub(crate) struct Selection {
+    pub(crate) inserted: Vec<(Uid, Vec<ComponentData>)>,
+    pub(crate) added: Vec<(Uid, ComponentData)>,
+    pub(crate) changes: Vec<(Uid, ComponentData)>,
+    pub(crate) bytes: usize,
+}
+
+impl Selection {
+    fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.added.is_empty() && self.changes.is_empty()
+    }
+}
+
+/// The changes waiting to be sent to a single client.
+#[derive(Default)]
+pub(crate) struct PriorityAccumulator {
+    entities: HashMap<Uid, PendingEntity>,
+    inserts: u64,
+}
+
+impl PriorityAccumulator {
+    pub(crate) fn new() -> PriorityAccumulator {
+        PriorityAccumulator {
+            entities: HashMap::new(),
+            inserts: 0,
+        }
+    }
+
+    /// Queues the insert of an entity, its later changes wait until the insert is sent.
+    pub(crate) fn insert(&mut self, entity_id: Uid, components: Vec<ComponentData>, weight: f32) {
+        let order = self.inserts;
+        self.inserts += 1;
+
+        self.pending_entity(entity_id, weight).insert = Some((order, components));
+    }
+
+    /// Queues a change, the weight is the importance of the change relative to other changes.
+    pub(crate) fn push(&mut self, entity_id: Uid, change: ComponentData, weight: f32) {
+        self.pending_entity(entity_id, weight)
+            .changes
+            .push(PendingChange {
+                data: change,
+                added: false,
+                baseline: None,
+            });
+    }
+
+    /// Queues a component that is added to an entity.
+    pub(crate) fn push_added(&mut self, entity_id: Uid, component: ComponentData, weight: f32) {
+        self.pending_entity(entity_id, weight)
+            .changes
+            .push(PendingChange {
+                data: component,
+                added: true,
+                baseline: None,
+            });
+    }
+
+    /// Queues a change of an unreliable component, the baseline is the state the client knows of the component.
+    ///
+    /// A pending change of the same component is superseded instead of sent after the other,
+    /// `difference` compares the baseline of the pending change with the current state.
+    /// Returns whether a pending change was superseded.
+    pub(crate) fn push_unreliable(
+        &mut self,
+        entity_id: Uid,
+        change: ComponentData,
+        baseline: Vec<u8>,
+        weight: f32,
+        difference: impl FnOnce(&[u8]) -> Option<ComponentData>,
+    ) -> bool {
+        let component_id = change.component_id();
+        let pending = self.pending_entity(entity_id, weight);
+
+        let superseded = pending.changes.iter().position(|pending| {
+            pending.data.component_id() == component_id && pending.baseline.is_some()
+        });
+
+        match superseded {
+            Some(index) => {
+                let baseline = pending.changes[index]
+                    .baseline
+                    .take()
+                    .expect("Unreliable change should have a baseline.");
+
+                match difference(&baseline) {
+                    Some(data) => {
+                        pending.changes[index] = PendingChange {
+                            data,
+                            added: false,
+                            baseline: Some(baseline),
+                        };
+                    }
+                    // The component is back at the state the client knows.
+                    None => {
+                        pending.changes.remove(index);
+
+                        if pending.is_empty() {
+                            self.entities.remove(&entity_id);
+                        }
+                    }
+                }
+
+                true
+            }
+            None => {
+                pending.changes.push(PendingChange {
+                    data: change,
+                    added: false,
+                    baseline: Some(baseline),
+                });
+
+                false
+            }
+        }
+    }
+
+    fn pending_entity(&mut self, entity_id: Uid, weight: f32) -> &mut PendingEntity {
+        let pending = self.entities.entry(entity_id).or_insert(PendingEntity {
+            priority: 0.,
+            weight,
+            insert: None,
+            changes: Vec::new(),
+        });
+
+        pending.weight = pending.weight.max(weight);
+        pending
+    }
+
+    /// Returns whether the insert of the entity waits to be sent.
+    pub(crate) fn is_inserting(&self, entity_id: Uid) -> bool {
+        self.entities
+            .get(&entity_id)
+            .map_or(false, |pending| pending.insert.is_some())
+    }
+
+    /// Returns whether a component that is added to the entity waits to be sent.
+    pub(crate) fn is_adding(&self, entity_id: Uid, component_id: Uid) -> bool {
+        self.entities.get(&entity_id).map_or(false, |pending| {
+            pending
+                .changes
+                .iter()
+                .any(|change| change.added && change.data.component_id() == component_id)
+        })
+    }
+
+    /// Raises the priority of all entities that wait to be sent.
+    pub(crate) fn accumulate(&mut self) {
+        for pending in self.entities.values_mut() {
+            pending.priority += pending.weight;
+        }
+    }
+
+    /// Drops the pending insert and changes of an entity, returns the number of dropped updates.
+    pub(crate) fn remove_entity(&mut self, entity_id: Uid) -> usize {
+        self.entities
+            .remove(&entity_id)
+            .map_or(0, |pending| pending.len())
+    }
+
+    /// Drops the pending changes of a component, returns the number of dropped changes.
+    ///
+    /// The component is removed from a pending insert as well.
+    pub(crate) fn remove_component(&mut self, entity_id: Uid, component_id: Uid) -> usize {
+        let mut removed = 0;
+
+        if let Some(pending) = self.entities.get_mut(&entity_id) {
+            let count = pending.changes.len();
+            pending
+                .changes
+                .retain(|change| change.data.component_id() != component_id);
+            removed = count - pending.changes.len();
+
+            if let Some((_, components)) = &mut pending.insert {
+                components.retain(|component| component.component_id() != component_id);
+            }
+
+            if pending.is_empty() {
+                self.entities.remove(&entity_id);
+            }
+        }
+
+        removed
+    }
+
+    /// Returns the number of inserts and changes waiting to be sent.
+    pub(crate) fn pending(&self) -> usize {
+        self.entities.values().map(|pending| pending.len()).sum()
+    }
+
+    /// Takes the changes of the entities with the highest priority that fit in the budget.
+    ///
+    /// The entity with the highest priority is always taken, otherwise an entity with changes bigger than the budget would never be sent.
+    pub(crate) fn take_within_budget(&mut self, budget: usize) -> Selection {
+        let mut order = self
+            .entities
+            .iter()
+            .map(|(entity_id, pending)| (*entity_id, pending.priority))
+            .collect::<Vec<(Uid, f32)>>();
+
+        order.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+        let mut selection = Selection {
+            inserted: Vec::new(),
+            added: Vec::new(),
+            changes: Vec::new(),
+            bytes: 0,
+        };
+        let mut inserts = Vec::new();
+
+        for (entity_id, _) in order {
+            let pending = self
+                .entities
+                .get_mut(&entity_id)
+                .expect("Entity should be pending.");
+
+            // The changes within a single state update are not ordered,
+            // therefore only the oldest change of each component is taken.
+            let mut components = HashSet::new();
+            let to_take = pending
+                .changes
+                .iter()
+                .enumerate()
+                .filter(|(_, change)| components.insert((change.added, change.data.component_id())))
+                .map(|(index, _)| index)
+                .collect::<Vec<usize>>();
+
+            let insert_cost = pending.insert.as_ref().map_or(0, |(_, components)| {
+                components
+                    .iter()
+                    .map(|component| component.data().len() + CHANGE_OVERHEAD)
+                    .sum::<usize>()
+            });
+
+            let cost = insert_cost
+                + to_take
+                    .iter()
+                    .map(|index| pending.changes[*index].data.data().len() + CHANGE_OVERHEAD)
+                    .sum::<usize>();
+
+            if selection.bytes + cost > budget && !selection.is_empty() {
+                continue;
+            }
+
+            if let Some((order, components)) = pending.insert.take() {
+                inserts.push((order, entity_id, components));
+            }
+
+            for index in to_take.into_iter().rev() {
+                let change = pending.changes.remove(index);
+
+                if change.added {
+                    selection.added.push((entity_id, change.data));
+                } else {
+                    selection.changes.push((entity_id, change.data));
+                }
+            }
+
+            selection.bytes += cost;
+            pending.priority = 0.;
+
+            if pending.is_empty() {
+                self.entities.remove(&entity_id);
+            }
+        }
+
+        // Entities are inserted in the order they were inserted on the server, which puts parents first.
+        inserts.sort_by_key(|(order, _, _)| *order);
+        selection.inserted = inserts
+            .into_iter()
+            .map(|(_, entity_id, components)| (entity_id, components))
+            .collect();
+
+        selection
+    }
+}
+
+impl PendingEntity {
+    fn len(&self) -> usize {
+        self.changes.len() + self.insert.as_ref().map_or(0, |_| 1)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Moves the inserts and changes of the world state into the accumulator and puts back those that fit in the budget.
+///
+/// `baselines` holds the unchanged state of the changed unreliable components by entity and component id,
+/// `difference` compares such a state with the current state of the component.
+///
+/// Changes of predicted components are never deferred, the client reconciles them with its prediction of the same command frame.
+/// They are taken from the budget first.
+pub(crate) fn limit_bandwidth(
+    world_state: &mut WorldState,
+    accumulator: &mut PriorityAccumulator,
+    budget: usize,
+    weight: impl Fn(Uid, Uid) -> f32,
+    is_predicted: impl Fn(Uid) -> bool,
+    baselines: &HashMap<(Uid, Uid), Vec<u8>>,
+    difference: impl Fn(Uid, Uid, &[u8]) -> Option<ComponentData>,
+    metrics: &mut ClientReplicationMetrics,
+) {
+    // Pending changes of removed entities and components can no longer be applied.
+    let mut dropped = 0;
+    // The client does not know the entities of which the insert is still pending.
+    let mut unknown = HashSet::new();
+
+    for entity_id in world_state.removed.iter() {
+        if accumulator.is_inserting(*entity_id) {
+            unknown.insert(*entity_id);
+        }
+
+        dropped += accumulator.remove_entity(*entity_id);
+    }
+
+    world_state
+        .removed
+        .retain(|entity_id| !unknown.contains(entity_id));
+
+    for removed in world_state.component_removed.iter() {
+        dropped += accumulator.remove_component(removed.entity_id(), removed.component_id());
+    }
+
+    world_state
+        .component_removed
+        .retain(|removed| !accumulator.is_inserting(removed.entity_id()));
+
+    for inserted in std::mem::take(&mut world_state.inserted).into_iter() {
+        let entity_id = inserted.entity_id();
+        let weight = inserted
+            .components()
+            .iter()
+            .map(|component| weight(entity_id, component.component_id()))
+            .fold(0., f32::max);
+
+        accumulator.insert(entity_id, inserted.components().to_vec(), weight);
+    }
+
+    for added in std::mem::take(&mut world_state.component_added).iter() {
+        let entity_id = added.entity_id();
+        let component_data = added.component_data();
+
+        accumulator.push_added(
+            entity_id,
+            component_data.clone(),
+            weight(entity_id, component_data.component_id()),
+        );
+    }
+
+    let mut superseded = 0;
+    let mut predicted_bytes = 0;
+    let mut predicted = 0;
+
+    for changed in std::mem::take(&mut world_state.changed).iter() {
+        let entity_id = changed.entity_id();
+        let component_data = changed.component_data();
+        let component_id = component_data.component_id();
+
+        // A change can only be applied once the client knows the entity and component.
+        if is_predicted(component_id)
+            && !accumulator.is_inserting(entity_id)
+            && !accumulator.is_adding(entity_id, component_id)
+        {
+            predicted_bytes += component_data.data().len() + CHANGE_OVERHEAD;
+            predicted += 1;
+            world_state.change(entity_id, component_data.clone());
+            continue;
+        }
+
+        let weight = weight(entity_id, component_id);
+
+        match baselines.get(&(entity_id, component_id)) {
+            Some(baseline) => {
+                if accumulator.push_unreliable(
+                    entity_id,
+                    component_data.clone(),
+                    baseline.clone(),
+                    weight,
+                    |baseline| difference(entity_id, component_id, baseline),
+                ) {
+                    superseded += 1;
+                }
+            }
+            None => accumulator.push(entity_id, component_data.clone(), weight),
+        }
+    }
+
+    accumulator.accumulate();
+
+    let selection = accumulator.take_within_budget(budget.saturating_sub(predicted_bytes));
+    let sent =
+        predicted + selection.inserted.len() + selection.added.len() + selection.changes.len();
+
+    for (entity_id, components) in selection.inserted {
+        world_state.insert_entity(entity_id, components);
+    }
+
+    for (entity_id, component_data) in selection.added {
+        world_state.add_component(entity_id, component_data);
+    }
+
+    for (entity_id, component_data) in selection.changes {
+        world_state.change(entity_id, component_data);
+    }
+
+    metrics.record(
+        Some(budget),
+        predicted_bytes + selection.bytes,
+        sent,
+        accumulator.pending(),
+        dropped,
+        superseded,
+    );
+}
+
+/// Records the size of a world state that is sent without a budget.
+pub(crate) fn measure_bandwidth(world_state: &WorldState, metrics: &mut ClientReplicationMetrics) {
+    let inserted = world_state
+        .inserted
+        .iter()
+        .flat_map(|inserted| inserted.components().iter());
+    let added = world_state
+        .component_added
+        .iter()
+        .map(|added| added.component_data());
+    let changed = world_state
+        .changed
+        .iter()
+        .map(|changed| changed.component_data());
+
+    let bytes = inserted
+        .chain(added)
+        .chain(changed)
+        .map(|component| component.data().len() + CHANGE_OVERHEAD)
+        .sum();
+    let sent =
+        world_state.inserted.len() + world_state.component_added.len() + world_state.changed.len();
+
+    metrics.record(None, bytes, sent, 0, 0, 0);
+}
+
+#[cfg(test)]
+pub mod test {
+    use net_sync::synchronisation::ComponentData;
+
+    use crate::world::bandwidth::{BandwidthBudget, PriorityAccumulator};
+
+    fn change(component_id: u32, size: usize) -> ComponentData {
+        ComponentData::new(component_id, vec![0; size])
+    }
+
+    #[test]
+    fn budget_per_second_is_divided_over_ticks_test() {
+        assert_eq!(
+            BandwidthBudget::BytesPerSecond(3000).bytes_per_tick(30.),
+            100
+        );
+        assert_eq!(BandwidthBudget::BytesPerTick(100).bytes_per_tick(30.), 100);
+    }
+
+    #[test]
+    fn highest_priority_is_sent_first_test() {
+        let mut accumulator = PriorityAccumulator::new();
+        accumulator.push(1, change(1, 10), 1.);
+        accumulator.push(2, change(1, 10), 2.);
+        accumulator.accumulate();
+
+        let selection = accumulator.take_within_budget(30);
+
+        assert_eq!(selection.changes.len(), 1);
+        assert_eq!(selection.changes[0].0, 2);
+        assert_eq!(accumulator.pending(), 1);
+    }
+
+    #[test]
+    fn deferred_entity_gains_priority_test() {
+        let mut accumulator = PriorityAccumulator::new();
+        accumulator.push(1, change(1, 10), 1.);
+        accumulator.push(2, change(1, 10), 1.5);
+        accumulator.accumulate();
+        accumulator.take_within_budget(30);
+
+        // Entity 1 waited a frame and now outweighs the new change of entity 2.
+        accumulator.push(2, change(1, 10), 1.5);
+        accumulator.accumulate();
+
+        let selection = accumulator.take_within_budget(30);
+        assert_eq!(selection.changes[0].0, 1);
+    }
+
+    #[test]
+    fn only_oldest_change_per_component_is_taken_test() {
+        let mut accumulator = PriorityAccumulator::new();
+        accumulator.push(1, change(1, 1), 1.);
+        accumulator.push(1, change(1, 2), 1.);
+        accumulator.accumulate();
+
+        let selection = accumulator.take_within_budget(1000);
+
+        assert_eq!(selection.changes.len(), 1);
+        assert_eq!(selection.changes[0].1.data().len(), 1);
+        assert_eq!(accumulator.pending(), 1);
+    }
+
+    #[test]
+    fn unreliable_change_supersedes_pending_change_test() {
+        let mut accumulator = PriorityAccumulator::new();
+
+        assert!(!accumulator.push_unreliable(1, change(1, 1), vec![0], 1., |_| None));
+        assert!(
+            accumulator.push_unreliable(1, change(1, 2), vec![1], 1., |baseline| {
+                // The replacement is compared with the baseline of the first change.
+                assert_eq!(baseline, &[0]);
+                Some(change(1, 3))
+            })
+        );
+        accumulator.accumulate();
+
+        let selection = accumulator.take_within_budget(1000);
+
+        assert_eq!(selection.changes.len(), 1);
+        assert_eq!(selection.changes[0].1.data().len(), 3);
+        assert_eq!(accumulator.pending(), 0);
+    }
+
+    #[test]
+    fn changes_wait_for_pending_insert_test() {
+        let mut accumulator = PriorityAccumulator::new();
+        accumulator.insert(1, vec![change(1, 10)], 1.);
+        accumulator.insert(2, vec![change(1, 10)], 2.);
+        accumulator.push(1, change(1, 1), 1.);
+        accumulator.accumulate();
+
+        assert!(accumulator.is_inserting(1));
+
+        let selection = accumulator.take_within_budget(30);
+        assert_eq!(selection.inserted.len(), 1);
+        assert_eq!(selection.inserted[0].0, 2);
+        assert!(selection.changes.is_empty());
+
+        accumulator.accumulate();
+
+        // The insert is sent together with the changes that waited for it.
+        let selection = accumulator.take_within_budget(1000);
+        assert_eq!(selection.inserted[0].0, 1);
+        assert_eq!(selection.changes.len(), 1);
+        assert_eq!(accumulator.pending(), 0);
+    }
+
+    #[test]
+    fn removed_entity_drops_pending_changes_test() {
+        let mut accumulator = PriorityAccumulator::new();
+        accumulator.push(1, change(1, 1), 1.);
+        accumulator.push(1, change(2, 1), 1.);
+
+        assert_eq!(accumulator.remove_component(1, 2), 1);
+        assert_eq!(accumulator.remove_entity(1), 1);
+        assert_eq!(accumulator.pending(), 0);
+    }
+}
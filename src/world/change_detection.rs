@@ -0,0 +1,171 @@
+//! Change detection for the components registered with automatic tracking.
+//!
+//! The server keeps a copy of the state it sent last of each such component.
+//! Every command frame the components are compared with their copy,
+//! a component that differs is handed to the same pipeline as the changes of the tracking proxy.
+
+use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet},
+};
+
+use legion::{Entity, World};
+
+use net_sync::uid::{Uid, UidAllocator};
+
+use crate::{codec::TRACKER_CODEC, register::ComponentRegistration};
+
+/// The copies of the automatically tracked components.
+pub(crate) struct ChangeDetector {
+    sent: HashMap<(Uid, TypeId), Vec<u8>>,
+}
+
+impl ChangeDetector {
+    pub(crate) fn new() -> ChangeDetector {
+        ChangeDetector {
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Returns the changed components of the given entities with their previous state,
+    /// and keeps their current state as the state that is sent.
+    ///
+    /// A component that is seen for the first time is not reported, it is sent along with the insert of its entity.
+    pub(crate) fn detect<'a>(
+        &mut self,
+        world: &World,
+        entities: impl Iterator<Item = &'a Entity>,
+        allocator: &UidAllocator<Entity>,
+        registrations: &[&ComponentRegistration],
+    ) -> Vec<((Uid, TypeId), Vec<u8>)> {
+        let sent = &mut self.sent;
+        let mut changed = Vec::new();
+        let mut seen = HashSet::new();
+
+        for entity in entities {
+            serialize_components(world, *entity, allocator, registrations, |key, current| {
+                seen.insert(key);
+
+                if let Some(previous) = sent.insert(key, current.clone()) {
+                    if previous != current {
+                        changed.push((key, previous));
+                    }
+                }
+            });
+        }
+
+        // Forget the components and entities that are gone.
+        sent.retain(|key, _| seen.contains(key));
+
+        changed
+    }
+
+    /// Keeps the current state of the given entities as the state that is sent, e.g. for the entities inserted this command frame.
+    pub(crate) fn seed<'a>(
+        &mut self,
+        world: &World,
+        entities: impl Iterator<Item = &'a Entity>,
+        allocator: &UidAllocator<Entity>,
+        registrations: &[&ComponentRegistration],
+    ) {
+        let sent = &mut self.sent;
+
+        for entity in entities {
+            serialize_components(world, *entity, allocator, registrations, |key, current| {
+                sent.insert(key, current);
+            });
+        }
+    }
+}
+
+// Serialize the registered components of the entity with the tracker codec.
+fn serialize_components(
+    world: &World,
+    entity: Entity,
+    allocator: &UidAllocator<Entity>,
+    registrations: &[&ComponentRegistration],
+    mut f: impl FnMut((Uid, TypeId), Vec<u8>),
+) {
+    let entity_id = allocator.get(&entity);
+
+    for registration in registrations.iter() {
+        registration.serialize_if_exists_in_world(world, entity, &mut |component| {
+            let (result, current) = TRACKER_CODEC
+                .serialize_erased(|serializer| erased_serde::serialize(component, serializer));
+
+            match result {
+                Ok(_) => f((entity_id, registration.ty()), current),
+                Err(e) => log::error!(
+                    "Cannot serialize component {}: {}",
+                    registration.type_name(),
+                    e
+                ),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::any::TypeId;
+
+    use legion::{Entity, World};
+
+    use net_sync::uid::UidAllocator;
+
+    use crate::{
+        components::UidComponent, register::ComponentRegistration,
+        world::change_detection::ChangeDetector,
+    };
+
+    #[test]
+    fn changed_component_is_detected_test() {
+        let registration = ComponentRegistration::of::<UidComponent>();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut detector = ChangeDetector::new();
+        let mut world = World::default();
+
+        let entity = world.push((UidComponent::new(1),));
+        allocator.allocate(entity, Some(5));
+
+        assert!(detector
+            .detect(&world, [entity].iter(), &allocator, &[&registration])
+            .is_empty());
+
+        *world
+            .entry(entity)
+            .unwrap()
+            .get_component_mut::<UidComponent>()
+            .unwrap() = UidComponent::new(2);
+
+        let changed = detector.detect(&world, [entity].iter(), &allocator, &[&registration]);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, (5, TypeId::of::<UidComponent>()));
+
+        // The change has been taken as the state that is sent.
+        assert!(detector
+            .detect(&world, [entity].iter(), &allocator, &[&registration])
+            .is_empty());
+    }
+
+    #[test]
+    fn seeded_component_is_compared_with_seed_test() {
+        let registration = ComponentRegistration::of::<UidComponent>();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut detector = ChangeDetector::new();
+        let mut world = World::default();
+
+        let entity = world.push((UidComponent::new(1),));
+        allocator.allocate(entity, Some(5));
+        detector.seed(&world, [entity].iter(), &allocator, &[&registration]);
+
+        *world
+            .entry(entity)
+            .unwrap()
+            .get_component_mut::<UidComponent>()
+            .unwrap() = UidComponent::new(2);
+
+        let changed = detector.detect(&world, [entity].iter(), &allocator, &[&registration]);
+        assert_eq!(changed.len(), 1);
+    }
+}
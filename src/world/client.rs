@@ -1,8 +1,14 @@
-use std::{marker::PhantomData, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    marker::PhantomData,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use itertools::Itertools;
 use legion::{
-    any,
+    query::{IntoQuery, Read},
     systems::{Builder, Resource},
     world::{Entity, Universe, World},
     Resources,
@@ -15,20 +21,34 @@ use net_sync::{
         ComponentChanged, ComponentData, NetworkCommand, NetworkMessage, ResimulationBuffer,
         WorldState,
     },
-    transport,
-    transport::PostBox,
-    uid::UidAllocator,
+    transport::{self, tcp::TcpClientResource},
+    uid::{Uid, UidAllocator},
 };
 
 use crate::{
-    resources::{EventResource, RegisteredComponentsResource, ResourcesExt},
+    codec::{WireCodec, TRACKER_CODEC},
+    components::ParentComponent,
+    event::{SyncEvent, SyncEventQueue},
+    protocol::{
+        ClientMessage, ClientPostBox, ClientToServer, DisconnectReason, ResourceState,
+        ServerMessage, ServerToClient, SessionToken,
+    },
+    recording::{RecordingHeader, SessionRecorder},
+    register::{RegisteredMessagesResource, RegisteredResourcesResource, Simulation},
+    resources::{
+        EventResource, LinkConditioner, LinkConditions, RegisteredComponentsResource,
+        ResourcePredictionBuffer, ResourcesExt, UidRecycler, UnackedCommands,
+    },
     systems::BuilderExt,
-    tracking::re_exports::bincode,
-    world::{world_instance::WorldInstance, WorldBuilder},
+    world::{
+        clock::{ClockSample, ClockSync, ClockSyncState, PidClockSync},
+        entity_event::{EntityEventBuffer, PendingEntityEvent, DEFAULT_ENTITY_EVENT_WAIT},
+        reconnect::{ReconnectAction, ReconnectConfig, Reconnector},
+        sequence::StateUpdateSequencer,
+        world_instance::WorldInstance,
+        WorldBuilder,
+    },
 };
-use bincode::Options;
-use serde::de::DeserializeSeed;
-use std::{borrow::BorrowMut, ops::DerefMut};
 
 pub struct ClientWorldBuilder<
     ServerToClientMessage: NetworkMessage,
@@ -38,6 +58,10 @@ pub struct ClientWorldBuilder<
 > {
     resources: Resources,
     system_builder: Builder,
+    clock_sync: Box<dyn ClockSync>,
+    recording: Option<Box<dyn Write + Send + Sync>>,
+    server_addr: Option<SocketAddr>,
+    reconnect: Option<ReconnectConfig>,
 
     cs: PhantomData<CompressionStrategy>,
     stcm: PhantomData<ServerToClientMessage>,
@@ -62,6 +86,10 @@ impl<
         ClientWorldBuilder {
             resources: Default::default(),
             system_builder: Builder::default(),
+            clock_sync: Box::new(PidClockSync::default()),
+            recording: None,
+            server_addr: None,
+            reconnect: None,
 
             cs: PhantomData,
             stcm: PhantomData,
@@ -128,7 +156,23 @@ impl<
 
         let main_world = WorldInstance::new(main_world, s.system_builder.build());
 
-        ClientWorld::new(s.resources, main_world)
+        let resources = &s.resources;
+        let recorder = s.recording.take().map(|writer| {
+            let header = RecordingHeader::new(
+                *resources.get::<WireCodec>().unwrap(),
+                &resources.get::<RegisteredComponentsResource>().unwrap(),
+            );
+            SessionRecorder::new(writer, &header).expect("Cannot write the recording header.")
+        });
+
+        let mut client = ClientWorld::new(s.resources, main_world);
+        client.clock_sync = s.clock_sync;
+        client.recorder = recorder;
+        client.server_addr = s.server_addr;
+        client.reconnector = s
+            .reconnect
+            .map(|config| Reconnector::new(config, Instant::now()));
+        client
     }
 }
 
@@ -148,6 +192,51 @@ impl<
     pub fn with_tcp(mut self, addr: SocketAddr) -> Self {
         self.system_builder = self.system_builder.add_tcp_client_systems::<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>();
         self.resources.insert_tcp_client_resources::<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>(addr);
+        self.server_addr = Some(addr);
+        self
+    }
+
+    /// Reconnects to the server when the TCP connection is lost, and resumes the session of the previous connection.
+    pub fn with_reconnect(mut self, config: ReconnectConfig) -> Self {
+        self.reconnect = Some(config);
+        self
+    }
+
+    /// Replaces the default strategy that keeps the client command frame ahead of the server.
+    pub fn with_clock_sync(mut self, clock_sync: impl ClockSync) -> Self {
+        self.clock_sync = Box::new(clock_sync);
+        self
+    }
+
+    /// Simulates the given network conditions for the messages exchanged with the server.
+    ///
+    /// The seed makes the simulation reproducible.
+    pub fn with_link_conditioner(mut self, conditions: LinkConditions, seed: u64) -> Self {
+        self.resources.insert(
+            LinkConditioner::<ServerToClient<ServerToClientMessage>>::new(conditions, seed),
+        );
+        self.resources.insert(LinkConditioner::<
+            ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+        >::new(conditions, seed.wrapping_add(1)));
+        self
+    }
+
+    /// Sets the number of unacknowledged commands that are resent with every packet to the server.
+    pub fn with_command_redundancy(mut self, redundancy: usize) -> Self {
+        self.resources
+            .insert(UnackedCommands::<ClientToServerCommand>::new(redundancy));
+        self
+    }
+
+    /// Sets the codec the client decodes the server data with, it should be the codec of the server.
+    pub fn with_wire_codec(mut self, codec: WireCodec) -> Self {
+        self.resources.insert(codec);
+        self
+    }
+
+    /// Records the initial state syncs and state updates received from the server to the given writer.
+    pub fn with_recording(mut self, writer: impl Write + Send + Sync + 'static) -> Self {
+        self.recording = Some(Box::new(writer));
         self
     }
 }
@@ -162,6 +251,24 @@ pub struct ClientWorld<
     pub(crate) resources: Resources,
     // TODO: HACK, REMOVE!
     has_received_first_message: bool,
+    /// The codec of the server when it differs from ours, the client disconnects in that case.
+    codec_mismatch: Option<WireCodec>,
+    clock_sync: Box<dyn ClockSync>,
+    /// Puts the state updates, along with the resource differences of each, in order.
+    sequencer: StateUpdateSequencer<(WorldState, Vec<ResourceState>)>,
+    recorder: Option<SessionRecorder>,
+    /// The session given by the server in its welcome.
+    session: Option<SessionToken>,
+    /// Whether the next packet to the server starts a new connection with a hello.
+    pending_hello: bool,
+    server_addr: Option<SocketAddr>,
+    reconnector: Option<Reconnector>,
+    /// Why the server stopped serving this client.
+    disconnect_reason: Option<DisconnectReason>,
+    /// The entity events waiting for their state update or entity.
+    entity_events: EntityEventBuffer,
+    /// The command frame of the latest applied state.
+    applied_frame: Option<CommandFrame>,
 
     c: PhantomData<CompressionStrategy>,
     stcm: PhantomData<ServerToClientMessage>,
@@ -195,6 +302,17 @@ impl<
             world,
             resources,
             has_received_first_message: false,
+            codec_mismatch: None,
+            clock_sync: Box::new(PidClockSync::default()),
+            sequencer: StateUpdateSequencer::new(),
+            recorder: None,
+            session: None,
+            pending_hello: true,
+            server_addr: None,
+            reconnector: None,
+            disconnect_reason: None,
+            entity_events: EntityEventBuffer::new(DEFAULT_ENTITY_EVENT_WAIT),
+            applied_frame: None,
 
             c: PhantomData,
             stcm: PhantomData,
@@ -207,24 +325,61 @@ impl<
         &mut self.world.world
     }
 
+    /// Returns the smoothed round-trip time to the server, `None` until it has been measured.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.resources
+            .get::<ClientPostBox<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
+            .and_then(|postbox| postbox.round_trip_time())
+    }
+
+    /// Returns the state of the strategy that keeps the client command frame ahead of the server.
+    pub fn clock_sync_state(&self) -> ClockSyncState {
+        self.clock_sync.state()
+    }
+
+    /// Returns the codec of the server if it differs from the codec of this client.
+    pub fn codec_mismatch(&self) -> Option<WireCodec> {
+        self.codec_mismatch
+    }
+
+    /// Returns why the server disconnected this client, `None` while the client is served.
+    pub fn disconnect_reason(&self) -> Option<&DisconnectReason> {
+        self.disconnect_reason.as_ref()
+    }
+
+    /// Returns the session the server gave this client, `None` until the server welcomed the client.
+    pub fn session(&self) -> Option<SessionToken> {
+        self.session
+    }
+
     pub fn tick(&mut self) {
+        self.reconnect_if_lost();
+
         let resources = &mut self.resources;
 
         self.world.execute(resources);
 
         let mut command_ticker = resources.get_mut::<CommandFrameTicker>().unwrap();
 
+        // The systems changed the predicted resources in the current command frame.
+        resources
+            .get_mut::<ResourcePredictionBuffer>()
+            .unwrap()
+            .track(resources, command_ticker.command_frame());
+
         if command_ticker.try_tick() {
-            let mut postbox = resources
-                .get_mut::<PostBox<
-                    transport::ServerToClientMessage<ServerToClientMessage>,
-                    transport::ClientToServerMessage<ClientToServerMessage, ClientToServerCommand>,
-                >>()
-                .unwrap();
+            let mut postbox =
+                resources
+                    .get_mut::<ClientPostBox<
+                        ServerToClientMessage,
+                        ClientToServerMessage,
+                        ClientToServerCommand,
+                    >>()
+                    .unwrap();
 
             let mut uid_allocator = resources.get_mut::<UidAllocator<Entity>>().unwrap();
+            let mut uid_recycler = resources.get_mut::<UidRecycler>().unwrap();
             let registered = resources.get_mut::<RegisteredComponentsResource>().unwrap();
-            let universe = resources.get_mut::<Universe>().unwrap();
 
             let mut client_buffer = resources
                 .get_mut::<ClientCommandBuffer<ClientToServerCommand>>()
@@ -232,74 +387,175 @@ impl<
             let mut resimulation_buffer = resources
                 .get_mut::<ResimulationBuffer<ClientToServerCommand>>()
                 .unwrap();
+            let mut unacked_commands = resources
+                .get_mut::<UnackedCommands<ClientToServerCommand>>()
+                .unwrap();
+            let mut resource_predictions = resources.get_mut::<ResourcePredictionBuffer>().unwrap();
+
+            let codec = *resources.get::<WireCodec>().unwrap();
 
             let inbox = postbox.drain_inbox(|m| match m {
-                transport::ServerToClientMessage::StateUpdate(_) => true,
                 transport::ServerToClientMessage::InitialStateSync(_) => true,
+                transport::ServerToClientMessage::Message(ServerMessage::StateUpdate {
+                    ..
+                }) => true,
+                transport::ServerToClientMessage::Message(ServerMessage::Welcome { .. }) => true,
+                transport::ServerToClientMessage::Message(ServerMessage::Disconnect(_)) => true,
+                transport::ServerToClientMessage::Message(ServerMessage::Typed { .. }) => true,
+                transport::ServerToClientMessage::Message(ServerMessage::EntityEvent {
+                    ..
+                }) => true,
                 _ => false,
             });
 
-            for packet in inbox {
-                match packet {
-                    transport::ServerToClientMessage::StateUpdate(mut update) => {
-                        adjust_simulation_speed(
-                            update.command_frame_offset,
-                            update.command_frame,
-                            &mut command_ticker,
-                        );
+            let mut outgoing = Vec::new();
+            let mut sync_events = Vec::new();
+            let message_registrations = resources.get::<RegisteredMessagesResource>().unwrap();
 
-                        if !self.has_received_first_message {
-                            self.has_received_first_message = true;
-                            command_ticker.set_command_frame(update.command_frame + 3);
-                        }
+            if let Some(reconnector) = &mut self.reconnector {
+                if let Some(received) = postbox.take_received() {
+                    reconnector.received(received);
+                }
+            }
 
-                        let mut state_updater = StateUpdater::new(
-                            &mut uid_allocator,
+            // The server welcomes the client once it knows whether the client resumes a session.
+            if std::mem::replace(&mut self.pending_hello, false) {
+                outgoing.push(transport::ClientToServerMessage::Message(
+                    ClientMessage::Hello {
+                        session: self.session,
+                    },
+                ));
+            }
+
+            for packet in inbox {
+                if let Some(recorder) = &mut self.recorder {
+                    record_incoming(recorder, &packet, &self.sequencer);
+                }
+
+                match packet {
+                    transport::ServerToClientMessage::Message(ServerMessage::Welcome {
+                        codec: server_codec,
+                        state_update_sequence,
+                        session,
+                        resources: resource_states,
+                    }) => {
+                        self.sequencer.reset(state_update_sequence);
+                        self.session = Some(session);
+
+                        // A welcome after a reconnect or resume is followed by a fresh initial state.
+                        clear_replicated(
                             &mut self.world.world,
-                            &registered,
-                            &mut update,
-                            &mut client_buffer,
-                            &mut resimulation_buffer,
-                            command_ticker.command_frame(),
-                            Lz4,
+                            &mut uid_allocator,
+                            &mut uid_recycler,
                         );
+                        self.has_received_first_message = false;
+                        self.entity_events.clear();
+                        self.applied_frame = None;
+
+                        if server_codec != codec {
+                            let reason = DisconnectReason::CodecMismatch {
+                                server: server_codec,
+                                client: codec,
+                            };
+                            log::error!("Disconnecting from the server: {}", reason);
+
+                            // The server stops serving this client once it receives the reason.
+                            postbox.send(transport::ClientToServerMessage::Message(
+                                ClientMessage::Disconnect(reason.clone()),
+                            ));
+
+                            self.codec_mismatch = Some(server_codec);
+                            self.reconnector = None;
+                            self.disconnect_reason = Some(reason.clone());
+                            sync_events.push(SyncEvent::Disconnected { reason });
+                        } else {
+                            self.codec_mismatch = None;
+                            apply_resources(resources, &resource_states, codec, false);
+                            resource_predictions.reset(resources);
+                        }
+                    }
+                    transport::ServerToClientMessage::Message(ServerMessage::Disconnect(
+                        reason,
+                    )) if self.codec_mismatch.is_none() => {
+                        log::warn!("The server disconnected this client: {}", reason);
+
+                        // Coming back would not be welcome.
+                        self.reconnector = None;
+                        self.disconnect_reason = Some(reason.clone());
+                        sync_events.push(SyncEvent::Disconnected { reason });
+                    }
+                    _ if self.codec_mismatch.is_some() => {}
+                    transport::ServerToClientMessage::Message(ServerMessage::Typed {
+                        kind,
+                        data,
+                    }) => match message_registrations.get(kind) {
+                        Some(registration) => {
+                            if let Err(e) = codec.deserialize_erased(&data, |deserializer| {
+                                registration.receive(resources, None, deserializer)
+                            }) {
+                                log::error!(
+                                    "Cannot deserialize message {} from the server: {}",
+                                    registration.type_name(),
+                                    e
+                                );
+                            }
+                        }
+                        None => log::warn!("The server sent a message of unknown kind {}.", kind),
+                    },
+                    transport::ServerToClientMessage::Message(ServerMessage::EntityEvent {
+                        kind,
+                        entity_id,
+                        command_frame,
+                        data,
+                    }) => self.entity_events.push(PendingEntityEvent {
+                        kind,
+                        entity_id,
+                        command_frame,
+                        data,
+                    }),
+                    transport::ServerToClientMessage::Message(ServerMessage::StateUpdate {
+                        sequence,
+                        command_ack,
+                        state,
+                        resources: resource_differences,
+                    }) => {
+                        // Acknowledgements only grow, so stale updates may still carry a useful one.
+                        if let Some(command_frame) = command_ack {
+                            unacked_commands.acknowledge(command_frame);
+                        }
 
-                        state_updater.apply_entity_removals();
-                        state_updater.apply_entity_inserts();
-                        state_updater.apply_removed_components();
-                        state_updater.apply_added_components();
-                        state_updater.apply_changed_components();
+                        // An update that cannot be decoded is a missing sequence for the sequencer.
+                        match codec.deserialize::<WorldState>(&state) {
+                            Ok(state) => {
+                                if let Some(event) = self
+                                    .sequencer
+                                    .receive(sequence, (state, resource_differences))
+                                {
+                                    sync_events.push(event);
+                                }
+                            }
+                            Err(e) => log::error!("Cannot decode state update {}: {}", sequence, e),
+                        }
                     }
                     transport::ServerToClientMessage::InitialStateSync(world_state) => {
-                        let registry = registered.legion_registry();
-                        match registry.as_deserialize(&universe).deserialize(
-                            &mut bincode::Deserializer::from_slice(
-                                &world_state,
-                                bincode::DefaultOptions::new()
-                                    .with_fixint_encoding()
-                                    .allow_trailing_bytes(),
-                            ),
-                        ) {
-                            Ok(world) => {
-                                let mutex = registered.legion_merger();
-                                let mut merger = mutex.lock().unwrap();
-                                let merge_result = self
-                                    .world
-                                    .world
-                                    .clone_from(&world, &any(), merger.deref_mut())
-                                    .expect("Should have merged");
-
-                                for merge in merge_result.iter() {
-                                    let entry = self.world.world.entry(*merge.1).unwrap();
-                                    let uid = entry.get_component::<net_sync::uid::Uid>();
-                                }
-
-                                let serialized = serde_json::to_value(
-                                    self.world
-                                        .world
-                                        .as_serializable(any(), registered.legion_registry()),
-                                )
-                                .unwrap();
+                        match codec.deserialize::<WorldState>(&world_state) {
+                            Ok(mut initial_state) => {
+                                self.applied_frame = Some(initial_state.command_frame);
+
+                                let mut state_updater = StateUpdater::new(
+                                    &mut uid_allocator,
+                                    &mut uid_recycler,
+                                    &mut self.world.world,
+                                    &registered,
+                                    &mut initial_state,
+                                    &mut client_buffer,
+                                    &mut resimulation_buffer,
+                                    command_ticker.command_frame(),
+                                    codec,
+                                    Lz4,
+                                );
+
+                                state_updater.apply_entity_inserts();
                             }
                             Err(e) => {
                                 panic!("{:?}", e);
@@ -310,15 +566,135 @@ impl<
                 }
             }
 
-            // Sent commands to server
-            for command in client_buffer.iter_history(1) {
-                postbox.send(transport::ClientToServerMessage::Command(
-                    command.command_frame.clone(),
-                    command.command.clone(),
+            // Apply the state updates that are next in sequence.
+            let ready = self.sequencer.ready(&mut sync_events);
+
+            // Lost updates cannot be applied on top of each other, the server starts this client over.
+            if self.sequencer.take_sync_request() {
+                outgoing.push(transport::ClientToServerMessage::Message(
+                    ClientMessage::RequestSync,
+                ));
+            }
+
+            for (mut update, resource_differences) in ready {
+                // The server only knows how early the commands arrive once it received some.
+                if unacked_commands.acknowledged().is_some() {
+                    let default_tick_rate = command_ticker.default_simulation_speed() as f32;
+                    let adjustment = self.clock_sync.update(ClockSample {
+                        offset: update.command_frame_offset,
+                        target_offset: -target_command_frame_cushion(
+                            postbox.rtt().jitter(),
+                            default_tick_rate,
+                        ),
+                        server_command_frame: update.command_frame,
+                        client_command_frame: command_ticker.command_frame(),
+                        default_tick_rate,
+                    });
+
+                    if let Some(command_frame) = adjustment.reset_command_frame {
+                        command_ticker.set_command_frame(command_frame);
+                    }
+                    command_ticker.adjust_simulation(adjustment.tick_rate);
+                }
+
+                if !self.has_received_first_message {
+                    self.has_received_first_message = true;
+
+                    let lead = target_command_frame_lead(
+                        postbox.round_trip_time(),
+                        command_ticker.default_simulation_speed() as f32,
+                    );
+                    command_ticker.set_command_frame(update.command_frame + lead as u32);
+                }
+
+                let mut state_updater = StateUpdater::new(
+                    &mut uid_allocator,
+                    &mut uid_recycler,
+                    &mut self.world.world,
+                    &registered,
+                    &mut update,
+                    &mut client_buffer,
+                    &mut resimulation_buffer,
+                    command_ticker.command_frame(),
+                    codec,
+                    Lz4,
+                );
+
+                state_updater.apply();
+                resource_predictions.reconcile(
+                    resources,
+                    &resource_differences,
+                    update.command_frame,
+                    codec,
+                );
+                self.applied_frame = Some(update.command_frame);
+
+                // Let the server know it can reuse the ids removed in this update.
+                outgoing.push(transport::ClientToServerMessage::Message(
+                    ClientMessage::StateAck(update.command_frame),
                 ));
+            }
+
+            // Deliver the entity events of the applied state updates.
+            if let Some(applied_frame) = self.applied_frame {
+                self.entity_events.deliver(
+                    applied_frame,
+                    &uid_allocator,
+                    &uid_recycler,
+                    resources,
+                    codec,
+                );
+            }
+
+            if !sync_events.is_empty() {
+                let mut event_queue = resources.get_mut::<SyncEventQueue>().unwrap();
+                for event in sync_events {
+                    event_queue.push(event);
+                }
+            }
 
+            // Sent commands to server, together with the commands the server did not yet acknowledge.
+            for command in client_buffer.iter_history(1) {
+                unacked_commands.push(command.command_frame.clone(), command.command.clone());
                 command.is_sent = true;
             }
+
+            for (command_frame, command) in unacked_commands.iter() {
+                outgoing.push(transport::ClientToServerMessage::Command(
+                    command_frame.clone(),
+                    command.clone(),
+                ));
+            }
+
+            if !unacked_commands.is_empty() {
+                outgoing.push(transport::ClientToServerMessage::Message(
+                    ClientMessage::CommandFrames(unacked_commands.iter().map(|x| x.0).collect()),
+                ));
+            }
+
+            // All typed messages go to the server, whatever their target.
+            for (kind, registration) in message_registrations.iter() {
+                registration.drain_outbox(resources, &mut |_, message| {
+                    let (result, data) = codec.serialize_erased(|serializer| {
+                        erased_serde::serialize(message, serializer)
+                    });
+
+                    match result {
+                        Ok(_) => outgoing.push(transport::ClientToServerMessage::Message(
+                            ClientMessage::Typed { kind, data },
+                        )),
+                        Err(e) => {
+                            log::error!("Cannot send message {}: {}", registration.type_name(), e)
+                        }
+                    }
+                });
+            }
+
+            if self.disconnect_reason.is_none() {
+                for message in outgoing {
+                    postbox.send(message);
+                }
+            }
         }
     }
 
@@ -329,62 +705,164 @@ impl<
     pub fn resources_mut(&mut self) -> &mut Resources {
         &mut self.resources
     }
+
+    // Connect again once the connection is lost, the session is resumed with the first packet.
+    fn reconnect_if_lost(&mut self) {
+        let (reconnector, addr) = match (&mut self.reconnector, self.server_addr) {
+            (Some(reconnector), Some(addr)) => (reconnector, addr),
+            _ => return,
+        };
+
+        let event = match reconnector.poll(Instant::now()) {
+            ReconnectAction::Wait => return,
+            ReconnectAction::ConnectionLost => {
+                log::warn!("Lost the connection to the server at {}.", addr);
+                SyncEvent::ConnectionLost
+            }
+            ReconnectAction::Connect => match TcpClientResource::new(addr) {
+                Ok(tcp_client) => {
+                    self.resources.insert(tcp_client);
+                    self.resources.insert(ClientPostBox::<
+                        ServerToClientMessage,
+                        ClientToServerMessage,
+                        ClientToServerCommand,
+                    >::new());
+                    self.pending_hello = true;
+
+                    SyncEvent::Reconnected {
+                        attempts: reconnector.attempts(),
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Cannot reconnect to the server at {}: {:?}", addr, e);
+                    return;
+                }
+            },
+        };
+
+        self.resources
+            .get_mut::<SyncEventQueue>()
+            .unwrap()
+            .push(event);
+    }
 }
 
-/// Adjust the simulation speed based on the client offset with the server.
-/// The client offset is calculated by subtracting the `server command frame` from the `client command frame`.
-/// The result indicates the client offset from the server command frame.
-/// In normal situations the client should run a few command frames ahead of the server.
-/// However, the client should run not to far ahead nor to far behind.
-///
-/// In cases the offset is to big either negative or positive we should tune the simulation speed.
-///
-/// If the client command frame is to far ahead of the server command frame slow down the simulation speed.
-/// If the client command frame is behind the server command frame then increase the simulation speed.
-fn adjust_simulation_speed(
-    offset: i32,
-    server_command_frame: CommandFrame,
-    current_command_frame: &mut CommandFrameTicker,
+// Remove the replicated entities, the initial state sync that follows a welcome inserts them again.
+pub(crate) fn clear_replicated(
+    world: &mut World,
+    allocator: &mut UidAllocator<Entity>,
+    recycler: &mut UidRecycler,
 ) {
-    static DEFAULT_LAG: i32 = 200; // TODO: replace with real lag distance from server to client.
+    for uid in recycler.live().collect::<Vec<_>>() {
+        let entity = *allocator.get_by_val(&uid);
 
-    if DEFAULT_LAG == offset {
-        return;
+        world.remove(entity);
+        allocator.deallocate(entity);
+        recycler.release(uid);
     }
+}
 
-    let mut speed_factor = 0.;
+// Apply the resource data of a welcome or the resource differences of a state update.
+pub(crate) fn apply_resources(
+    resources: &Resources,
+    states: &[ResourceState],
+    codec: WireCodec,
+    are_differences: bool,
+) {
+    let registrations = resources.get::<RegisteredResourcesResource>().unwrap();
+
+    for state in states {
+        match registrations.get(state.kind) {
+            Some(registration) => {
+                let result = codec.deserialize_erased(&state.data, |deserializer| {
+                    if are_differences {
+                        registration.apply_changes(resources, deserializer)
+                    } else {
+                        registration.replace(resources, deserializer)
+                    }
+                });
 
-    if offset < -30 || offset > 30 {
-        speed_factor = 1 as f32;
-        current_command_frame.set_command_frame(server_command_frame + DEFAULT_LAG as u32);
-    } else if offset < -15 {
-        speed_factor = 0.875;
-    } else if offset < 0 {
-        speed_factor = 0.9375;
-    } else if offset > 15 {
-        speed_factor = 1.125;
-    } else if offset > 8 {
-        speed_factor = 1.0625;
-    } else {
-        speed_factor = 1 as f32;
+                if let Err(e) = result {
+                    log::error!(
+                        "Dropping resource {} from the server: {}",
+                        registration.type_name(),
+                        e
+                    );
+                }
+            }
+            None => log::warn!("The server sent a resource of unknown kind {}.", state.kind),
+        }
+    }
+}
+
+// Record the received welcomes, initial state syncs, state updates and entity events.
+fn record_incoming<ServerToClientMessage: NetworkMessage, S>(
+    recorder: &mut SessionRecorder,
+    message: &ServerToClient<ServerToClientMessage>,
+    sequencer: &StateUpdateSequencer<S>,
+) {
+    let result = match message {
+        transport::ServerToClientMessage::Message(ServerMessage::Welcome {
+            state_update_sequence,
+            resources,
+            ..
+        }) => recorder.record_welcome(None, *state_update_sequence, resources),
+        // The welcome that precedes the initial state reset the sequencer to its sequence.
+        transport::ServerToClientMessage::InitialStateSync(state) => {
+            recorder.record_initial_state_sync(None, sequencer.last_applied().unwrap_or(0), state)
+        }
+        transport::ServerToClientMessage::Message(ServerMessage::StateUpdate {
+            sequence,
+            state,
+            resources,
+            ..
+        }) => recorder.record_state_update(None, *sequence, state, resources),
+        transport::ServerToClientMessage::Message(ServerMessage::EntityEvent {
+            kind,
+            entity_id,
+            command_frame,
+            data,
+        }) => recorder.record_entity_event(None, *kind, *entity_id, *command_frame, data),
+        _ => Ok(()),
+    };
+
+    if let Err(e) = result {
+        log::error!("Failed to record the message from the server: {}", e);
     }
+}
+
+/// The command frames the client runs ahead on top of the frames that cover the trip to the server.
+const COMMAND_FRAME_LEAD_MARGIN: i32 = 2;
+/// The round-trip time assumed until the first measurement.
+const DEFAULT_RTT: Duration = Duration::from_millis(100);
+
+/// Returns the number of command frames the client should run ahead of the server,
+/// such that its commands arrive before the server simulates their command frame.
+fn target_command_frame_lead(rtt: Option<Duration>, ticks_per_second: f32) -> i32 {
+    let one_way = rtt.unwrap_or(DEFAULT_RTT).as_secs_f32() / 2.;
+    (one_way * ticks_per_second).ceil() as i32 + COMMAND_FRAME_LEAD_MARGIN
+}
 
-    let new_rate = current_command_frame.default_simulation_speed() as f32 * speed_factor;
-    current_command_frame.adjust_simulation(new_rate);
+/// Returns the number of command frames the commands of the client should wait in the jitter buffer of the server,
+/// such that a command that is delayed by the jitter of the connection still arrives in time.
+fn target_command_frame_cushion(jitter: Duration, ticks_per_second: f32) -> i32 {
+    (jitter.as_secs_f32() * ticks_per_second).ceil() as i32 + COMMAND_FRAME_LEAD_MARGIN
 }
 
-struct StateUpdater<
+pub(crate) struct StateUpdater<
     'a,
     C: NetworkCommand,
     CompressionStrategy: compression::CompressionStrategy = Lz4,
 > {
     allocator: &'a mut UidAllocator<Entity>,
+    recycler: &'a mut UidRecycler,
     world: &'a mut World,
     registry: &'a RegisteredComponentsResource,
     update: &'a mut WorldState,
     client_buffer: &'a mut ClientCommandBuffer<C>,
     resimmulation_buffer: &'a mut ResimulationBuffer<C>,
     current_command_frame: CommandFrame,
+    codec: WireCodec,
 
     phantom: PhantomData<CompressionStrategy>,
 }
@@ -392,45 +870,122 @@ struct StateUpdater<
 impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrategy>
     StateUpdater<'a, C, CompressionStrategy>
 {
-    pub fn new(
+    pub(crate) fn new(
         allocator: &'a mut UidAllocator<Entity>,
+        recycler: &'a mut UidRecycler,
         world: &'a mut World,
         registry: &'a RegisteredComponentsResource,
         update: &'a mut WorldState,
         client_buffer: &'a mut ClientCommandBuffer<C>,
         resimmulation_buffer: &'a mut ResimulationBuffer<C>,
         current_command_frame: CommandFrame,
+        codec: WireCodec,
         _compression: CompressionStrategy,
     ) -> StateUpdater<'a, C, CompressionStrategy> {
         StateUpdater {
             allocator,
+            recycler,
             world,
             registry,
             update,
             client_buffer,
             current_command_frame,
             resimmulation_buffer,
+            codec,
             phantom: PhantomData,
         }
     }
 
+    /// Applies all changes of the state update.
+    pub(crate) fn apply(&mut self) {
+        self.apply_entity_removals();
+        self.apply_entity_inserts();
+        self.apply_removed_components();
+        self.apply_added_components();
+        self.apply_changed_components();
+    }
+
+    // Returns whether the id refers to an entity we know, updates for stale ids are ignored.
+    fn is_live(&self, uid: Uid) -> bool {
+        let is_live = self.recycler.is_live(uid);
+
+        if !is_live {
+            log::warn!("Ignoring update for unknown or stale entity id {}.", uid);
+        }
+
+        is_live
+    }
+
     // Handle remove events, and clear mappings to prevent merge of removed entities and delete entity from worlds.
+    // The children of a removed entity are removed with it, including the children that only exist on this client.
     fn apply_entity_removals(&mut self) {
+        let mut removed_ids = HashSet::new();
+        let mut removed = HashSet::new();
+
         for to_remove_entity in self.update.removed.iter() {
-            let entity = self.allocator.get_by_val(to_remove_entity).clone();
+            if self.is_live(*to_remove_entity) {
+                removed_ids.insert(*to_remove_entity);
+                removed.insert(*self.allocator.get_by_val(to_remove_entity));
+            }
+        }
+
+        if removed.is_empty() {
+            return;
+        }
+
+        let allocator = &*self.allocator;
+        let replicated = self
+            .recycler
+            .live()
+            .map(|uid| (*allocator.get_by_val(&uid), uid))
+            .collect::<HashMap<Entity, Uid>>();
+
+        let parents = <(Entity, Read<ParentComponent>)>::query()
+            .iter(&*self.world)
+            .map(|(entity, parent)| (*entity, parent.parent().entity_id()))
+            .collect::<Vec<(Entity, Uid)>>();
+
+        loop {
+            let mut found_child = false;
+
+            for (child, parent_id) in parents.iter() {
+                if removed_ids.contains(parent_id) && removed.insert(*child) {
+                    if let Some(child_id) = replicated.get(child) {
+                        removed_ids.insert(*child_id);
+                    }
+                    found_child = true;
+                }
+            }
+
+            if !found_child {
+                break;
+            }
+        }
 
+        for entity in removed {
             self.world.remove(entity);
 
-            self.allocator
-                .deallocate(entity)
-                .expect("Entity should be allocated.");
+            if let Some(entity_id) = replicated.get(&entity) {
+                self.allocator
+                    .deallocate(entity)
+                    .expect("Entity should be allocated.");
+                self.recycler.release(*entity_id);
+            }
         }
     }
 
-    fn apply_entity_inserts(&mut self) {
+    pub(crate) fn apply_entity_inserts(&mut self) {
         let registry_by_id = self.registry.by_uid();
 
         for to_insert_entity in self.update.inserted.iter() {
+            if self.recycler.is_live(to_insert_entity.entity_id()) {
+                log::warn!(
+                    "Ignoring insert of already known entity id {}.",
+                    to_insert_entity.entity_id()
+                );
+                continue;
+            }
+
             let entity = self.world.extend(vec![()])[0].clone();
 
             for component in to_insert_entity.components() {
@@ -438,17 +993,19 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
                     .get(&component.component_id())
                     .expect("Component should be registered.");
 
-                let deserializer =
-                    &mut bincode::Deserializer::from_slice(component.data(), default_options());
-                component_registration.add_component(
-                    &mut self.world,
-                    entity,
-                    &mut erased_serde::Deserializer::erase(deserializer),
+                let world = &mut *self.world;
+                component_registration.replication().encoding.deserialize(
+                    self.codec,
+                    component.data(),
+                    |deserializer| {
+                        component_registration.add_component(world, entity, deserializer)
+                    },
                 );
             }
 
             self.allocator
                 .allocate(entity, Some(to_insert_entity.entity_id()));
+            self.recycler.claim(to_insert_entity.entity_id());
         }
     }
 
@@ -456,6 +1013,10 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
         let registry_by_id = self.registry.by_uid();
 
         for to_remove_component in self.update.component_removed.iter() {
+            if !self.is_live(to_remove_component.entity_id()) {
+                continue;
+            }
+
             let entity = self.allocator.get_by_val(&to_remove_component.entity_id());
             let component_registration = registry_by_id
                 .get(&to_remove_component.component_id())
@@ -468,19 +1029,21 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
         let registry_by_id = self.registry.by_uid();
 
         for to_add_component in self.update.component_added.iter() {
+            if !self.is_live(to_add_component.entity_id()) {
+                continue;
+            }
+
             let entity = self.allocator.get_by_val(&to_add_component.entity_id());
             let component_data = to_add_component.component_data();
             let component_registration = registry_by_id
                 .get(&component_data.component_id())
                 .expect("Component should be registered.");
 
-            let deserializer =
-                &mut bincode::Deserializer::from_slice(component_data.data(), default_options());
-
-            component_registration.add_component(
-                self.world,
-                *entity,
-                &mut erased_serde::Deserializer::erase(deserializer),
+            let world = &mut *self.world;
+            component_registration.replication().encoding.deserialize(
+                self.codec,
+                component_data.data(),
+                |deserializer| component_registration.add_component(world, *entity, deserializer),
             );
         }
     }
@@ -494,10 +1057,18 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
         let command_frame = self.update.command_frame;
 
         // Loop trough all client-side predicted state for the current server-authorizing command frame.
+        // Interpolated components are not predicted, the server state is applied as is.
         for (grouped_entity_id, group) in &self
             .client_buffer
             .iter()
             .filter(|x| x.command_frame == command_frame)
+            .filter(|x| {
+                registry_by_type
+                    .get(&x.component_type)
+                    .map_or(false, |registration| {
+                        registration.replication().simulation == Simulation::Predicted
+                    })
+            })
             .group_by(|x| x.entity_id)
         {
             // The buffer stores entries from latest to oldest changes therefore, the newest change is the first result.
@@ -513,6 +1084,10 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
                 .first()
                 .expect("Should have at least one element because of the filter.");
 
+            if !self.is_live(grouped_entity_id) {
+                continue;
+            }
+
             // Get allocated entity id.
             let entity = self.allocator.get_by_val(&grouped_entity_id);
 
@@ -521,31 +1096,28 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
                 .get(&oldest_change.component_type)
                 .expect("Should exist");
 
-            // Create deserializer of the oldest changed component.
-            let oldest_change_deserializer = &mut bincode::Deserializer::from_slice(
-                &oldest_change.unchanged_data,
-                default_options(),
-            );
-
-            // Create deserializer for the unchanged component.
-            let latest_change_deserializer = &mut bincode::Deserializer::from_slice(
-                &latest_change.changed_data,
-                default_options(),
-            );
-
-            // Those deserializers are used to find the difference between the the oldest unchanged and latest changed data.
-            // This difference should be the same as calculated on the server.
-
-            let mut buffer = Vec::new();
-
-            let mut bincode = bincode::Serializer::new(&mut buffer, default_options());
-            let serialized = &mut erased_serde::Serializer::erase(&mut bincode);
+            // Deserialize the oldest unchanged and latest changed data to find the difference between them.
+            // This difference should be the same as calculated on the server, therefore it is encoded the same way.
+            let encoding = registration.replication().encoding;
+            let codec = self.codec;
+
+            let (difference, buffer) =
+                TRACKER_CODEC.deserialize_erased(&latest_change.changed_data, |latest_change| {
+                    TRACKER_CODEC.deserialize_erased(
+                        &oldest_change.unchanged_data,
+                        |oldest_change| {
+                            encoding.serialize(codec, |serializer| {
+                                registration.serialize_difference(
+                                    latest_change,
+                                    oldest_change,
+                                    serializer,
+                                )
+                            })
+                        },
+                    )
+                });
 
-            match registration.serialize_difference(
-                &mut erased_serde::Deserializer::erase(latest_change_deserializer),
-                &mut erased_serde::Deserializer::erase(oldest_change_deserializer),
-                serialized.borrow_mut(),
-            ) {
+            match difference {
                 // There is a difference, lets figure out if this is the same as on the server.
                 Ok(true) => {
                     // Create entry, when hashed, should also be in the server authority sate.
@@ -578,21 +1150,11 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
                         // The client should resimmulate the world state from this state.
                         to_resimmulate.push(oldest_change.entity_id);
 
-                        let mut bincode = bincode::Deserializer::from_slice(
-                            &mut server_difference.1.data(),
-                            default_options(),
-                        );
-
-                        // Create deserializer of the server-difference.
-                        let mut server_difference_deserializer =
-                            erased_serde::Deserializer::erase(&mut bincode);
-
                         // Now apply the authoritative server-differences.
-                        registration.apply_changes(
-                            self.world,
-                            *entity,
-                            &mut server_difference_deserializer,
-                        )
+                        let world = &mut *self.world;
+                        encoding.deserialize(codec, server_difference.1.data(), |deserializer| {
+                            registration.apply_changes(world, *entity, deserializer)
+                        })
                     }
                 }
                 Ok(false) => {}
@@ -605,18 +1167,20 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
         for change in self.update.changed.iter() {
             if let Some(registration) = registry_by_uid.get(&change.component_data().component_id())
             {
+                if !self.is_live(change.entity_id()) {
+                    continue;
+                }
+
                 // Get allocated entity id.
                 let entity = self.allocator.get_by_val(&change.entity_id());
 
-                let mut bincode =
-                    bincode::Deserializer::from_slice(&mut change.1.data(), default_options());
-
-                // Create deserializer of the server-difference.
-                let mut server_difference_deserializer =
-                    erased_serde::Deserializer::erase(&mut bincode);
-
                 // Now apply the authoritative server-differences.
-                registration.apply_changes(self.world, *entity, &mut server_difference_deserializer)
+                let world = &mut *self.world;
+                registration.replication().encoding.deserialize(
+                    self.codec,
+                    change.1.data(),
+                    |deserializer| registration.apply_changes(world, *entity, deserializer),
+                )
             }
         }
 
@@ -636,9 +1200,3 @@ impl<'a, C: NetworkCommand, CompressionStrategy: compression::CompressionStrateg
         }
     }
 }
-
-fn default_options() -> impl Options {
-    bincode::DefaultOptions::new()
-        .with_fixint_encoding()
-        .allow_trailing_bytes()
-}
@@ -0,0 +1,234 @@
+//! Keeps the command frame of the client a little ahead of the command frame of the server.
+//!
+//! Every state update tells the client how early its commands arrive in the jitter buffer of the server.
+//! A [ClockSync](ClockSync) strategy turns that offset into a tick rate for the client,
+//! running slightly faster or slower until the commands arrive with the targeted cushion.
+
+use net_sync::synchronisation::CommandFrame;
+
+/// What the client knows about its clock when a state update arrives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockSample {
+    /// The number of command frames the commands of the client arrive too late at the server,
+    /// negative when they arrive ahead and wait in the jitter buffer.
+    pub offset: i32,
+    /// The offset the client should have, the negated cushion derived from the jitter of the connection.
+    pub target_offset: i32,
+    /// The command frame of the state update.
+    pub server_command_frame: CommandFrame,
+    /// The command frame of the client when the state update is applied.
+    pub client_command_frame: CommandFrame,
+    /// The tick rate at which the client and server run in sync.
+    pub default_tick_rate: f32,
+}
+
+impl ClockSample {
+    /// Returns how many command frames the client is off from the target, positive when it lags behind.
+    pub fn error(&self) -> i32 {
+        self.offset - self.target_offset
+    }
+}
+
+/// How the client should adjust its clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockAdjustment {
+    /// The number of command frames per second the client should run at.
+    pub tick_rate: f32,
+    /// Jumps to the given command frame, for when the client is too far off to catch up smoothly.
+    pub reset_command_frame: Option<CommandFrame>,
+}
+
+/// The state of a clock synchronization strategy, for display and debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockSyncState {
+    /// The error of the latest sample in command frames.
+    pub error: i32,
+    /// The error after smoothing.
+    pub smoothed_error: f32,
+    /// The tick rate relative to the default tick rate, 1 when in sync.
+    pub rate_factor: f32,
+    /// The number of times the command frame was reset.
+    pub resets: u32,
+}
+
+/// Decides the tick rate of the client.
+///
+/// A custom strategy is plugged in with `with_clock_sync` on the client world builder.
+pub trait ClockSync: Send + Sync + 'static {
+    /// Handles the sample of a state update and returns how the clock should be adjusted.
+    fn update(&mut self, sample: ClockSample) -> ClockAdjustment;
+
+    /// Returns the current state of the strategy.
+    fn state(&self) -> ClockSyncState;
+}
+
+/// The default clock synchronization, a PI controller on the exponentially smoothed error.
+///
+/// Smoothing keeps a single late state update from changing the rate,
+/// the proportional term eases the rate towards the target and the integral term removes a constant drift.
+/// The rate never deviates more than `max_adjustment` from the default tick rate,
+/// only when the error exceeds `reset_threshold` the command frame is reset.
+#[derive(Clone, Debug)]
+pub struct PidClockSync {
+    /// The weight of a new sample in the smoothed error, between 0 and 1.
+    pub smoothing: f32,
+    pub proportional_gain: f32,
+    pub integral_gain: f32,
+    /// The maximum deviation from the default tick rate, as a fraction of it.
+    pub max_adjustment: f32,
+    /// The error in command frames above which the command frame is reset.
+    pub reset_threshold: i32,
+
+    smoothed_error: Option<f32>,
+    integral: f32,
+    state: ClockSyncState,
+}
+
+impl PidClockSync {
+    pub fn new() -> PidClockSync {
+        PidClockSync {
+            smoothing: 0.1,
+            proportional_gain: 0.01,
+            integral_gain: 0.001,
+            max_adjustment: 0.1,
+            reset_threshold: 60,
+
+            smoothed_error: None,
+            integral: 0.,
+            state: ClockSyncState {
+                rate_factor: 1.,
+                ..ClockSyncState::default()
+            },
+        }
+    }
+
+    fn reset(&mut self, sample: &ClockSample) -> ClockAdjustment {
+        self.smoothed_error = None;
+        self.integral = 0.;
+        self.state.smoothed_error = 0.;
+        self.state.rate_factor = 1.;
+        self.state.resets += 1;
+
+        ClockAdjustment {
+            tick_rate: sample.default_tick_rate,
+            reset_command_frame: Some(
+                (sample.client_command_frame as i64 + sample.error() as i64).max(0) as CommandFrame,
+            ),
+        }
+    }
+}
+
+impl ClockSync for PidClockSync {
+    fn update(&mut self, sample: ClockSample) -> ClockAdjustment {
+        let error = sample.error();
+        self.state.error = error;
+
+        if error.abs() > self.reset_threshold {
+            return self.reset(&sample);
+        }
+
+        let smoothed_error = match self.smoothed_error {
+            Some(smoothed) => smoothed + self.smoothing * (error as f32 - smoothed),
+            None => error as f32,
+        };
+        self.smoothed_error = Some(smoothed_error);
+
+        // The integral is bounded so that it can not wind up beyond the maximum adjustment.
+        if self.integral_gain > 0. {
+            let bound = self.max_adjustment / self.integral_gain;
+            self.integral = (self.integral + smoothed_error).max(-bound).min(bound);
+        }
+
+        let adjustment = (self.proportional_gain * smoothed_error
+            + self.integral_gain * self.integral)
+            .max(-self.max_adjustment)
+            .min(self.max_adjustment);
+
+        self.state.smoothed_error = smoothed_error;
+        self.state.rate_factor = 1. + adjustment;
+
+        ClockAdjustment {
+            tick_rate: sample.default_tick_rate * self.state.rate_factor,
+            reset_command_frame: None,
+        }
+    }
+
+    fn state(&self) -> ClockSyncState {
+        self.state
+    }
+}
+
+impl Default for PidClockSync {
+    fn default() -> Self {
+        PidClockSync::new()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::world::clock::{ClockSample, ClockSync, PidClockSync};
+
+    fn sample(offset: i32) -> ClockSample {
+        ClockSample {
+            offset,
+            target_offset: 5,
+            server_command_frame: 100,
+            client_command_frame: 110,
+            default_tick_rate: 30.,
+        }
+    }
+
+    #[test]
+    fn in_sync_keeps_default_rate_test() {
+        let mut clock_sync = PidClockSync::new();
+        let adjustment = clock_sync.update(sample(5));
+
+        assert_eq!(adjustment.tick_rate, 30.);
+        assert_eq!(adjustment.reset_command_frame, None);
+    }
+
+    #[test]
+    fn lagging_behind_speeds_up_test() {
+        let mut clock_sync = PidClockSync::new();
+        let adjustment = clock_sync.update(sample(15));
+
+        assert!(adjustment.tick_rate > 30.);
+        assert!(clock_sync.state().rate_factor > 1.);
+    }
+
+    #[test]
+    fn running_ahead_slows_down_test() {
+        let mut clock_sync = PidClockSync::new();
+
+        assert!(clock_sync.update(sample(-5)).tick_rate < 30.);
+    }
+
+    #[test]
+    fn adjustment_is_bounded_test() {
+        let mut clock_sync = PidClockSync::new();
+
+        for _ in 0..100 {
+            let adjustment = clock_sync.update(sample(55));
+            assert!(adjustment.tick_rate <= 30. * 1.1 + 0.001);
+        }
+    }
+
+    #[test]
+    fn single_outlier_is_smoothed_test() {
+        let mut clock_sync = PidClockSync::new();
+        clock_sync.update(sample(5));
+        clock_sync.update(sample(25));
+
+        assert!((clock_sync.state().smoothed_error - 2.).abs() < 0.001);
+    }
+
+    #[test]
+    fn large_error_resets_command_frame_test() {
+        let mut clock_sync = PidClockSync::new();
+        let adjustment = clock_sync.update(sample(100));
+
+        assert_eq!(adjustment.reset_command_frame, Some(205));
+        assert_eq!(adjustment.tick_rate, 30.);
+        assert_eq!(clock_sync.state().resets, 1);
+    }
+}
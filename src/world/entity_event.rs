@@ -0,0 +1,164 @@
+//! Buffering of the entity events a client world receives.
+//!
+//! The server stamps an entity event with the command frame of the state update it is sent along with.
+//! The client holds the event back until it applied that state update,
+//! and longer when the entity it is addressed to is not yet known, e.g. because the insert of the entity got delayed.
+
+use legion::{Entity, Resources};
+
+use net_sync::{
+    synchronisation::CommandFrame,
+    uid::{Uid, UidAllocator},
+};
+
+use crate::{
+    codec::WireCodec, protocol::MessageKind, register::RegisteredEntityEventsResource,
+    resources::UidRecycler,
+};
+
+/// The number of command frames an event waits for its entity after its state update was applied.
+pub const DEFAULT_ENTITY_EVENT_WAIT: CommandFrame = 60;
+
+/// An entity event that has not yet been delivered.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PendingEntityEvent {
+    pub(crate) kind: MessageKind,
+    pub(crate) entity_id: Uid,
+    pub(crate) command_frame: CommandFrame,
+    pub(crate) data: Vec<u8>,
+}
+
+pub(crate) struct EntityEventBuffer {
+    pending: Vec<PendingEntityEvent>,
+    max_wait: CommandFrame,
+}
+
+impl EntityEventBuffer {
+    pub(crate) fn new(max_wait: CommandFrame) -> EntityEventBuffer {
+        EntityEventBuffer {
+            pending: Vec::new(),
+            max_wait,
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: PendingEntityEvent) {
+        self.pending.push(event);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Takes the events of the applied command frames whose entity is live, in command frame order.
+    ///
+    /// Events that waited longer than the maximum wait for their entity are dropped.
+    pub(crate) fn ready(
+        &mut self,
+        applied_frame: CommandFrame,
+        is_live: impl Fn(Uid) -> bool,
+    ) -> Vec<PendingEntityEvent> {
+        let max_wait = self.max_wait;
+        let mut ready = Vec::new();
+
+        self.pending.sort_by_key(|event| event.command_frame);
+        self.pending.retain(|event| {
+            if event.command_frame > applied_frame {
+                return true;
+            }
+
+            if is_live(event.entity_id) {
+                ready.push(event.clone());
+                false
+            } else if applied_frame - event.command_frame >= max_wait {
+                log::warn!(
+                    "Dropping event for unknown entity id {} of command frame {}.",
+                    event.entity_id,
+                    event.command_frame
+                );
+                false
+            } else {
+                true
+            }
+        });
+
+        ready
+    }
+
+    /// Puts the ready events in the [EntityEventInbox](crate::resources::EntityEventInbox) of their event type.
+    pub(crate) fn deliver(
+        &mut self,
+        applied_frame: CommandFrame,
+        allocator: &UidAllocator<Entity>,
+        recycler: &UidRecycler,
+        resources: &Resources,
+        codec: WireCodec,
+    ) {
+        let registrations = resources.get::<RegisteredEntityEventsResource>().unwrap();
+
+        for event in self.ready(applied_frame, |uid| recycler.is_live(uid)) {
+            let registration = match registrations.get(event.kind) {
+                Some(registration) => registration,
+                None => {
+                    log::warn!(
+                        "The server sent an entity event of unknown kind {}.",
+                        event.kind
+                    );
+                    continue;
+                }
+            };
+
+            let entity = *allocator.get_by_val(&event.entity_id);
+
+            if let Err(e) = codec.deserialize_erased(&event.data, |deserializer| {
+                registration.receive(resources, entity, deserializer)
+            }) {
+                log::error!(
+                    "Cannot deserialize event {} from the server: {}",
+                    registration.type_name(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use net_sync::{synchronisation::CommandFrame, uid::Uid};
+
+    use crate::world::entity_event::{EntityEventBuffer, PendingEntityEvent};
+
+    fn event(entity_id: Uid, command_frame: CommandFrame) -> PendingEntityEvent {
+        PendingEntityEvent {
+            kind: 0,
+            entity_id,
+            command_frame,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn events_wait_for_their_state_update_test() {
+        let mut buffer = EntityEventBuffer::new(10);
+        buffer.push(event(1, 6));
+        buffer.push(event(2, 5));
+
+        assert!(buffer.ready(4, |_| true).is_empty());
+        assert_eq!(buffer.ready(5, |_| true), vec![event(2, 5)]);
+        assert_eq!(buffer.ready(7, |_| true), vec![event(1, 6)]);
+    }
+
+    #[test]
+    fn events_wait_for_their_entity_test() {
+        let mut buffer = EntityEventBuffer::new(10);
+        buffer.push(event(1, 5));
+        buffer.push(event(2, 5));
+
+        assert!(buffer.ready(5, |_| false).is_empty());
+        assert_eq!(buffer.ready(6, |uid| uid == 1), vec![event(1, 5)]);
+
+        // The entity never showed up.
+        assert!(buffer.ready(15, |_| false).is_empty());
+        assert!(buffer.ready(16, |_| true).is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+//! Interest management, which of the replicated entities each client is sent.
+//!
+//! Without an interest filter every client is sent every replicated entity.
+//! With a filter the server asks it for each client and replicated entity once per command frame:
+//! an entity that comes into the interest of a client is inserted on that client in full,
+//! and an entity that goes out of its interest is removed from it.
+//! A child is only sent to the clients its parent is sent to, such that the hierarchy on a client is never missing a parent.
+
+use std::collections::{HashMap, HashSet};
+
+use legion::{
+    query::{IntoQuery, Read},
+    Entity, World,
+};
+
+use net_sync::{
+    synchronisation::{ComponentData, WorldState},
+    uid::{Uid, UidAllocator},
+};
+
+use crate::{components::ParentComponent, protocol::ClientId, resources::UidRecycler};
+
+/// Returns whether the client is interested in the replicated entity.
+pub type InterestFilter = Box<dyn Fn(ClientId, Entity, &World) -> bool + Send + Sync>;
+
+/// The replicated entities each client is interested in.
+pub(crate) struct InterestSets {
+    filter: InterestFilter,
+    /// The network ids of the entities each client has been sent.
+    sent: HashMap<ClientId, HashSet<Uid>>,
+    /// The network ids of the entities each client is interested in at the current command frame.
+    current: HashMap<ClientId, HashSet<Uid>>,
+}
+
+impl InterestSets {
+    pub(crate) fn new(filter: InterestFilter) -> InterestSets {
+        InterestSets {
+            filter,
+            sent: HashMap::new(),
+            current: HashMap::new(),
+        }
+    }
+
+    /// Asks the filter which of the replicated entities the given clients are interested in at the current command frame,
+    /// and forgets the other clients.
+    pub(crate) fn refresh<'a>(
+        &mut self,
+        clients: impl Iterator<Item = &'a ClientId>,
+        world: &World,
+        replicated: &HashSet<Entity>,
+        allocator: &UidAllocator<Entity>,
+        recycler: &UidRecycler,
+    ) {
+        let parents = <(Entity, Read<ParentComponent>)>::query()
+            .iter(world)
+            .filter(|(entity, _)| replicated.contains(*entity))
+            .map(|(entity, parent)| (*entity, parent.parent()))
+            .collect::<HashMap<Entity, _>>();
+
+        self.current.clear();
+
+        for client in clients {
+            let interesting = replicated
+                .iter()
+                .filter(|entity| (self.filter)(*client, **entity, world))
+                .cloned()
+                .collect::<HashSet<Entity>>();
+
+            // Roots first, then the children of the entities that are sent, down the hierarchy.
+            let mut visible = interesting
+                .iter()
+                .filter(|entity| !parents.contains_key(*entity))
+                .cloned()
+                .collect::<HashSet<Entity>>();
+
+            loop {
+                let children = interesting
+                    .iter()
+                    .filter(|entity| !visible.contains(*entity))
+                    .filter(|entity| {
+                        parents
+                            .get(*entity)
+                            .and_then(|parent| parent.resolve(allocator, recycler))
+                            .map_or(false, |parent| visible.contains(&parent))
+                    })
+                    .cloned()
+                    .collect::<Vec<Entity>>();
+
+                if children.is_empty() {
+                    break;
+                }
+
+                visible.extend(children);
+            }
+
+            self.current.insert(
+                *client,
+                visible.iter().map(|entity| allocator.get(entity)).collect(),
+            );
+        }
+
+        let current = &self.current;
+        self.sent.retain(|client, _| current.contains_key(client));
+    }
+
+    /// Returns whether the client is interested in the entity at the current command frame.
+    pub(crate) fn is_visible(&self, client: ClientId, entity_id: Uid) -> bool {
+        self.current
+            .get(&client)
+            .map_or(false, |visible| visible.contains(&entity_id))
+    }
+
+    /// Returns the initial state for the client with only the entities it is interested in.
+    pub(crate) fn initial_state(&mut self, client: ClientId, initial: &WorldState) -> WorldState {
+        let visible = self.current.get(&client).cloned().unwrap_or_default();
+        let filtered = filter_entities(initial, |entity_id| visible.contains(&entity_id));
+
+        self.sent.insert(client, visible);
+        filtered
+    }
+
+    /// Returns the state update for the client with only the entities it is interested in.
+    ///
+    /// The entities that came into its interest are inserted in full with `serialize`,
+    /// the entities that went out of its interest are removed.
+    pub(crate) fn state_update(
+        &mut self,
+        client: ClientId,
+        world_state: &WorldState,
+        serialize: impl Fn(Uid) -> Vec<ComponentData>,
+    ) -> WorldState {
+        let visible = self.current.get(&client).cloned().unwrap_or_default();
+        let sent = self.sent.remove(&client).unwrap_or_default();
+
+        let inserted = world_state
+            .inserted
+            .iter()
+            .map(|insert| insert.entity_id())
+            .collect::<HashSet<Uid>>();
+
+        // The changes of an entity only go to a client that knows the entity, removals included.
+        let mut filtered = filter_entities(world_state, |entity_id| {
+            if inserted.contains(&entity_id) {
+                visible.contains(&entity_id)
+            } else {
+                sent.contains(&entity_id)
+                    && (visible.contains(&entity_id) || world_state.removed.contains(&entity_id))
+            }
+        });
+
+        for entity_id in visible.iter() {
+            if !sent.contains(entity_id) && !inserted.contains(entity_id) {
+                filtered.insert_entity(*entity_id, serialize(*entity_id));
+            }
+        }
+
+        for entity_id in sent.iter() {
+            if !visible.contains(entity_id) && !world_state.removed.contains(entity_id) {
+                filtered.remove_entity(*entity_id);
+            }
+        }
+
+        self.sent.insert(client, visible);
+        filtered
+    }
+}
+
+// Create a copy of the world state with only the changes of the entities that are kept.
+fn filter_entities(world_state: &WorldState, keep: impl Fn(Uid) -> bool) -> WorldState {
+    let mut filtered = WorldState::new(world_state.command_frame);
+    filtered.command_frame_offset = world_state.command_frame_offset;
+
+    for entity_id in world_state.removed.iter() {
+        if keep(*entity_id) {
+            filtered.remove_entity(*entity_id);
+        }
+    }
+
+    for inserted in world_state.inserted.iter() {
+        if keep(inserted.entity_id()) {
+            filtered.insert_entity(inserted.entity_id(), inserted.components().to_vec());
+        }
+    }
+
+    for removed in world_state.component_removed.iter() {
+        if keep(removed.entity_id()) {
+            filtered.remove_component(removed.entity_id(), removed.component_id());
+        }
+    }
+
+    for added in world_state.component_added.iter() {
+        if keep(added.entity_id()) {
+            filtered.add_component(added.entity_id(), added.component_data().clone());
+        }
+    }
+
+    for changed in world_state.changed.iter() {
+        if keep(changed.entity_id()) {
+            filtered.change(changed.entity_id(), changed.component_data().clone());
+        }
+    }
+
+    filtered
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashSet;
+
+    use legion::{Entity, World};
+
+    use net_sync::{synchronisation::WorldState, uid::UidAllocator};
+
+    use crate::{
+        components::{NetworkEntity, ParentComponent},
+        resources::UidRecycler,
+        world::interest::InterestSets,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Zone(u32);
+
+    struct Interest {
+        world: World,
+        allocator: UidAllocator<Entity>,
+        recycler: UidRecycler,
+        replicated: HashSet<Entity>,
+        sets: InterestSets,
+    }
+
+    impl Interest {
+        // Client 1 is interested in zone 1, client 2 in every entity.
+        fn new() -> Interest {
+            Interest {
+                world: World::default(),
+                allocator: UidAllocator::new(),
+                recycler: UidRecycler::new(),
+                replicated: HashSet::new(),
+                sets: InterestSets::new(Box::new(|client, entity, world| {
+                    client == 2
+                        || world
+                            .entry_ref(entity)
+                            .and_then(|entry| entry.get_component::<Zone>().ok().cloned())
+                            == Some(Zone(1))
+                })),
+            }
+        }
+
+        fn push(&mut self, zone: Zone, parent: Option<Entity>) -> Entity {
+            let entity = match parent {
+                Some(parent) => {
+                    let parent = NetworkEntity::from_entity_id(self.allocator.get(&parent));
+                    self.world.push((zone, ParentComponent::new(parent)))
+                }
+                None => self.world.push((zone,)),
+            };
+
+            let entity_id = self.recycler.allocate().unwrap();
+            self.allocator.allocate(entity, Some(entity_id));
+            self.replicated.insert(entity);
+            entity
+        }
+
+        fn refresh(&mut self) {
+            self.sets.refresh(
+                [1, 2].iter(),
+                &self.world,
+                &self.replicated,
+                &self.allocator,
+                &self.recycler,
+            );
+        }
+    }
+
+    #[test]
+    fn child_is_only_sent_along_with_its_parent_test() {
+        let mut interest = Interest::new();
+        let parent = interest.push(Zone(2), None);
+        let child = interest.push(Zone(1), Some(parent));
+        let root = interest.push(Zone(1), None);
+        interest.refresh();
+
+        let parent_id = interest.allocator.get(&parent);
+        let child_id = interest.allocator.get(&child);
+        let root_id = interest.allocator.get(&root);
+
+        assert!(interest.sets.is_visible(1, root_id));
+        assert!(!interest.sets.is_visible(1, parent_id));
+        assert!(!interest.sets.is_visible(1, child_id));
+        assert!(interest.sets.is_visible(2, child_id));
+
+        let mut initial = WorldState::new(1);
+        for entity_id in [parent_id, child_id, root_id].iter() {
+            initial.insert_entity(*entity_id, Vec::new());
+        }
+
+        let state = interest.sets.initial_state(1, &initial);
+        assert_eq!(state.inserted.len(), 1);
+        assert_eq!(state.inserted[0].entity_id(), root_id);
+    }
+
+    #[test]
+    fn entities_entering_and_leaving_interest_are_inserted_and_removed_test() {
+        let mut interest = Interest::new();
+        let parent = interest.push(Zone(2), None);
+        let child = interest.push(Zone(1), Some(parent));
+        interest.refresh();
+
+        let parent_id = interest.allocator.get(&parent);
+        let child_id = interest.allocator.get(&child);
+        interest.sets.initial_state(1, &WorldState::new(1));
+
+        // The parent moves into the zone of client 1, its child comes along.
+        *interest
+            .world
+            .entry(parent)
+            .unwrap()
+            .get_component_mut::<Zone>()
+            .unwrap() = Zone(1);
+        interest.refresh();
+
+        let mut world_state = WorldState::new(2);
+        world_state.remove_entity(12345);
+        let state = interest.sets.state_update(1, &world_state, |_| Vec::new());
+
+        let inserted = state
+            .inserted
+            .iter()
+            .map(|insert| insert.entity_id())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            inserted,
+            [parent_id, child_id]
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>()
+        );
+        // The client never knew the removed entity.
+        assert!(state.removed.is_empty());
+
+        // The parent leaves the zone, the client removes it and its child.
+        *interest
+            .world
+            .entry(parent)
+            .unwrap()
+            .get_component_mut::<Zone>()
+            .unwrap() = Zone(2);
+        interest.refresh();
+
+        let state = interest
+            .sets
+            .state_update(1, &WorldState::new(3), |_| Vec::new());
+
+        let removed = state.removed.iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(
+            removed,
+            [parent_id, child_id]
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>()
+        );
+        assert!(state.inserted.is_empty());
+    }
+}
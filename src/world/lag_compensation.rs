@@ -0,0 +1,246 @@
+//! Keeps a history of the server world, such that the server can see the world as a client saw it.
+//!
+//! When a client shoots, it aims at where it saw its target, which is where the target was a few command frames ago.
+//! The server records the tracked components of all replicated entities every command frame,
+//! and [rewind](LagCompensation::rewind) hands out a view of an earlier frame to validate the hit against,
+//! which decodes the recorded components on demand.
+
+use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet, VecDeque},
+};
+
+use legion::{storage::Component, Entity, World};
+use serde::de::DeserializeOwned;
+
+use net_sync::{
+    synchronisation::CommandFrame,
+    uid::{Uid, UidAllocator},
+};
+
+use crate::{codec::TRACKER_CODEC, resources::RegisteredComponentsResource};
+
+/// Which components the server keeps a history of, and for how many command frames.
+#[derive(Clone, Debug)]
+pub struct LagCompensationConfig {
+    /// The number of command frames that can be rewound.
+    pub history_length: usize,
+    /// The types of the registered components that are recorded.
+    pub components: HashSet<TypeId>,
+    /// The number of command frames the clients show interpolated entities behind the latest state update they applied.
+    pub interpolation_delay: CommandFrame,
+}
+
+impl LagCompensationConfig {
+    pub fn new(history_length: usize) -> LagCompensationConfig {
+        LagCompensationConfig {
+            history_length,
+            components: HashSet::new(),
+            interpolation_delay: 0,
+        }
+    }
+
+    /// Rewinds the given number of command frames further, for the clients that interpolate between state updates.
+    pub fn with_interpolation_delay(mut self, frames: CommandFrame) -> Self {
+        self.interpolation_delay = frames;
+        self
+    }
+
+    /// Records the given registered component.
+    pub fn track<T: Component>(mut self) -> Self {
+        self.components.insert(TypeId::of::<T>());
+        self
+    }
+}
+
+// The encoded tracked components by component id, of each entity by network id.
+type RecordedFrame = HashMap<Uid, HashMap<Uid, Vec<u8>>>;
+
+/// The recorded history of the tracked components.
+pub struct LagCompensation {
+    config: LagCompensationConfig,
+    frames: VecDeque<(CommandFrame, RecordedFrame)>,
+}
+
+impl LagCompensation {
+    pub fn new(config: LagCompensationConfig) -> LagCompensation {
+        LagCompensation {
+            frames: VecDeque::with_capacity(config.history_length),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &LagCompensationConfig {
+        &self.config
+    }
+
+    /// Returns the oldest and newest command frame that can be rewound.
+    pub fn frames(&self) -> Option<(CommandFrame, CommandFrame)> {
+        match (self.frames.front(), self.frames.back()) {
+            (Some(oldest), Some(newest)) => Some((oldest.0, newest.0)),
+            _ => None,
+        }
+    }
+
+    /// Records the tracked components of the given entities for the given command frame.
+    pub(crate) fn record<'a>(
+        &mut self,
+        command_frame: CommandFrame,
+        world: &World,
+        entities: impl Iterator<Item = &'a Entity>,
+        allocator: &UidAllocator<Entity>,
+        components: &RegisteredComponentsResource,
+    ) {
+        if self.config.history_length == 0 {
+            return;
+        }
+
+        let tracked = components
+            .slice_with_uid()
+            .iter()
+            .filter(|x| self.config.components.contains(&x.1.ty()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // The map of the oldest frame is reused for the new frame.
+        let mut recorded = if self.frames.len() >= self.config.history_length {
+            let mut oldest = self
+                .frames
+                .pop_front()
+                .expect("History should not be empty.")
+                .1;
+            oldest.clear();
+            oldest
+        } else {
+            HashMap::new()
+        };
+
+        for entity in entities {
+            let entity_id = allocator.get(entity);
+
+            for (component_id, registration) in tracked.iter() {
+                registration.serialize_if_exists_in_world(world, *entity, &mut |serialize| {
+                    let (result, data) = TRACKER_CODEC.serialize_erased(|serializer| {
+                        erased_serde::serialize(&serialize, serializer)
+                    });
+
+                    if result.is_ok() {
+                        recorded
+                            .entry(entity_id)
+                            .or_insert_with(HashMap::new)
+                            .insert(*component_id, data);
+                    }
+                });
+            }
+        }
+
+        self.frames.push_back((command_frame, recorded));
+    }
+
+    /// Hands a view of the given command frame to `view`, `None` when the frame is not in the history.
+    pub fn rewind<R>(
+        &self,
+        command_frame: CommandFrame,
+        components: &RegisteredComponentsResource,
+        view: impl FnOnce(&HistoryView<'_>) -> R,
+    ) -> Option<R> {
+        let (_, recorded) = self.frames.iter().find(|x| x.0 == command_frame)?;
+
+        Some(view(&HistoryView {
+            command_frame,
+            recorded,
+            components,
+        }))
+    }
+}
+
+/// The tracked components of the replicated entities as they were at an earlier command frame.
+pub struct HistoryView<'a> {
+    command_frame: CommandFrame,
+    recorded: &'a RecordedFrame,
+    components: &'a RegisteredComponentsResource,
+}
+
+impl<'a> HistoryView<'a> {
+    pub fn command_frame(&self) -> CommandFrame {
+        self.command_frame
+    }
+
+    /// Returns whether the entity with the given network id had a tracked component.
+    pub fn contains(&self, entity_id: Uid) -> bool {
+        self.recorded.contains_key(&entity_id)
+    }
+
+    /// Returns the network ids of the entities that had a tracked component.
+    pub fn entity_ids(&self) -> impl Iterator<Item = &Uid> {
+        self.recorded.keys()
+    }
+
+    /// Returns the component of the entity with the given network id as it was at the rewound command frame,
+    /// decoded from the history.
+    pub fn get<T: Component + DeserializeOwned>(&self, entity_id: Uid) -> Option<T> {
+        let component_id = self.components.get_uid(&TypeId::of::<T>())?;
+        let data = self.recorded.get(&entity_id)?.get(component_id)?;
+
+        TRACKER_CODEC.deserialize(data).ok()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use legion::{Entity, World};
+
+    use net_sync::uid::UidAllocator;
+
+    use crate::{
+        components::UidComponent,
+        resources::RegisteredComponentsResource,
+        world::lag_compensation::{LagCompensation, LagCompensationConfig},
+    };
+
+    #[test]
+    fn rewind_returns_recorded_components_test() {
+        let components = RegisteredComponentsResource::new();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut world = World::default();
+        let mut history =
+            LagCompensation::new(LagCompensationConfig::new(2).track::<UidComponent>());
+
+        let entity = world.push((UidComponent::new(7),));
+        allocator.allocate(entity, Some(3));
+
+        for frame in 10..13 {
+            *world
+                .entry(entity)
+                .unwrap()
+                .get_component_mut::<UidComponent>()
+                .unwrap() = UidComponent::new(frame);
+
+            history.record(frame, &world, [entity].iter(), &allocator, &components);
+        }
+
+        assert_eq!(history.frames(), Some((11, 12)));
+        assert_eq!(history.rewind(10, &components, |_| ()), None);
+        assert_eq!(
+            history.rewind(11, &components, |view| view.get::<UidComponent>(3)),
+            Some(Some(UidComponent::new(11)))
+        );
+    }
+
+    #[test]
+    fn untracked_components_are_not_recorded_test() {
+        let components = RegisteredComponentsResource::new();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut world = World::default();
+        let mut history = LagCompensation::new(LagCompensationConfig::new(2));
+
+        let entity = world.push((UidComponent::new(7),));
+        allocator.allocate(entity, Some(3));
+        history.record(1, &world, [entity].iter(), &allocator, &components);
+
+        assert_eq!(
+            history.rewind(1, &components, |view| view.contains(3)),
+            Some(false)
+        );
+    }
+}
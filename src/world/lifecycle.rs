@@ -0,0 +1,107 @@
+//! Hooks that let user code react to clients connecting to and leaving the server.
+//!
+//! The server world calls the hooks at the start of its tick with the world and the resources,
+//! such that a hook can spawn the entity of a new player or despawn the entities of a player that left.
+
+use legion::{Resources, World};
+
+use crate::protocol::ClientId;
+
+/// Whether the server accepts a new client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectDecision {
+    Accept,
+    /// The client is not served, with the reason why.
+    Reject(String),
+}
+
+/// Called for a new client once its hello arrived, a reconnecting client that resumes its session is not connected again.
+pub type ConnectHook =
+    Box<dyn FnMut(ClientId, &mut World, &mut Resources) -> ConnectDecision + Send + Sync>;
+
+/// Called for a client that disconnected or whose session expired.
+pub type ClientHook = Box<dyn FnMut(ClientId, &mut World, &mut Resources) + Send + Sync>;
+
+/// The registered connection hooks of a server world.
+#[derive(Default)]
+pub(crate) struct LifecycleHooks {
+    pub(crate) on_connect: Vec<ConnectHook>,
+    pub(crate) on_disconnect: Vec<ClientHook>,
+    pub(crate) on_timeout: Vec<ClientHook>,
+}
+
+impl LifecycleHooks {
+    /// Calls the connect hooks until one rejects the client.
+    pub(crate) fn connect(
+        &mut self,
+        client_id: ClientId,
+        world: &mut World,
+        resources: &mut Resources,
+    ) -> ConnectDecision {
+        for hook in self.on_connect.iter_mut() {
+            if let ConnectDecision::Reject(reason) = hook(client_id, world, resources) {
+                return ConnectDecision::Reject(reason);
+            }
+        }
+
+        ConnectDecision::Accept
+    }
+
+    pub(crate) fn disconnect(
+        &mut self,
+        client_id: ClientId,
+        world: &mut World,
+        resources: &mut Resources,
+    ) {
+        for hook in self.on_disconnect.iter_mut() {
+            hook(client_id, world, resources);
+        }
+    }
+
+    pub(crate) fn timeout(
+        &mut self,
+        client_id: ClientId,
+        world: &mut World,
+        resources: &mut Resources,
+    ) {
+        for hook in self.on_timeout.iter_mut() {
+            hook(client_id, world, resources);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use legion::{Resources, World};
+
+    use crate::world::lifecycle::{ConnectDecision, LifecycleHooks};
+
+    #[test]
+    fn first_rejection_wins_test() {
+        let mut hooks = LifecycleHooks::default();
+        hooks.on_connect.push(Box::new(|client_id, world, _| {
+            world.push((client_id,));
+            ConnectDecision::Accept
+        }));
+        hooks.on_connect.push(Box::new(|client_id, _, _| {
+            if client_id == 2 {
+                ConnectDecision::Reject(String::from("Server is full."))
+            } else {
+                ConnectDecision::Accept
+            }
+        }));
+
+        let mut world = World::default();
+        let mut resources = Resources::default();
+
+        assert_eq!(
+            hooks.connect(1, &mut world, &mut resources),
+            ConnectDecision::Accept
+        );
+        assert_eq!(
+            hooks.connect(2, &mut world, &mut resources),
+            ConnectDecision::Reject(String::from("Server is full."))
+        );
+        assert_eq!(world.len(), 2);
+    }
+}
@@ -0,0 +1,150 @@
+//! Reconnection of a client world whose connection to the server dropped.
+//!
+//! The server pings every client regularly, a client that receives nothing for the timeout considers the connection lost.
+//! It then connects again, waiting twice as long after every failed attempt up to a maximum,
+//! and resumes its session such that the server hands back the entities of the previous connection.
+
+use std::time::{Duration, Instant};
+
+/// When a client considers its connection lost and how often it tries to reconnect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectConfig {
+    /// The time without any message from the server after which the connection is considered lost.
+    pub timeout: Duration,
+    /// The wait before the second attempt, doubled after every attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReconnectAction {
+    Wait,
+    /// The connection has just been considered lost.
+    ConnectionLost,
+    /// Make a new connection.
+    Connect,
+}
+
+/// Decides when to reconnect.
+pub(crate) struct Reconnector {
+    config: ReconnectConfig,
+    last_received: Instant,
+    lost: bool,
+    backoff: Duration,
+    next_attempt: Instant,
+    attempts: u32,
+}
+
+impl Reconnector {
+    pub(crate) fn new(config: ReconnectConfig, now: Instant) -> Reconnector {
+        Reconnector {
+            config,
+            last_received: now,
+            lost: false,
+            backoff: config.initial_backoff,
+            next_attempt: now,
+            attempts: 0,
+        }
+    }
+
+    /// Returns the number of attempts since the connection was lost.
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Marks the connection alive because a message from the server arrived.
+    pub(crate) fn received(&mut self, now: Instant) {
+        self.last_received = now;
+        self.lost = false;
+        self.backoff = self.config.initial_backoff;
+        self.attempts = 0;
+    }
+
+    pub(crate) fn poll(&mut self, now: Instant) -> ReconnectAction {
+        if !self.lost {
+            if now.duration_since(self.last_received) < self.config.timeout {
+                return ReconnectAction::Wait;
+            }
+
+            self.lost = true;
+            self.next_attempt = now;
+            return ReconnectAction::ConnectionLost;
+        }
+
+        if now < self.next_attempt {
+            return ReconnectAction::Wait;
+        }
+
+        // A connection that is made but never answers counts as a failed attempt as well.
+        self.attempts += 1;
+        self.next_attempt = now + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+        ReconnectAction::Connect
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::world::reconnect::{ReconnectAction, ReconnectConfig, Reconnector};
+
+    fn config() -> ReconnectConfig {
+        ReconnectConfig {
+            timeout: Duration::from_secs(5),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+        }
+    }
+
+    #[test]
+    fn connection_is_lost_after_timeout_test() {
+        let now = Instant::now();
+        let mut reconnector = Reconnector::new(config(), now);
+
+        assert_eq!(
+            reconnector.poll(now + Duration::from_secs(4)),
+            ReconnectAction::Wait
+        );
+        reconnector.received(now + Duration::from_secs(4));
+        assert_eq!(
+            reconnector.poll(now + Duration::from_secs(8)),
+            ReconnectAction::Wait
+        );
+        assert_eq!(
+            reconnector.poll(now + Duration::from_secs(9)),
+            ReconnectAction::ConnectionLost
+        );
+        assert_eq!(
+            reconnector.poll(now + Duration::from_secs(9)),
+            ReconnectAction::Connect
+        );
+    }
+
+    #[test]
+    fn attempts_back_off_test() {
+        let now = Instant::now();
+        let mut reconnector = Reconnector::new(config(), now);
+        reconnector.poll(now + Duration::from_secs(5));
+
+        let attempts = (0..10)
+            .map(|second| now + Duration::from_secs(5 + second))
+            .filter(|at| reconnector.poll(*at) == ReconnectAction::Connect)
+            .map(|at| at.duration_since(now).as_secs())
+            .collect::<Vec<_>>();
+
+        // Waits one, two and then at most three seconds.
+        assert_eq!(attempts, vec![5, 6, 8, 11, 14]);
+        assert_eq!(reconnector.attempts(), 5);
+    }
+}
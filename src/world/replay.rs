@@ -0,0 +1,485 @@
+//! Plays a recorded session back into a world, without a server.
+//!
+//! The recorded welcomes, initial state syncs, state updates and entity events are applied the same way the client world applies them,
+//! such that the world and the replicated resources show what the recorded client received.
+//! Playback runs in real time, scaled or one state update at a time,
+//! and [seek](ReplayWorld::seek) rebuilds the world from the nearest initial state before the requested frame.
+
+use std::{
+    io::Read,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use legion::{Entity, Resources, World};
+
+use net_sync::{
+    compression::lz4::Lz4,
+    synchronisation::{
+        ClientCommandBuffer, CommandFrame, NetworkCommand, ResimulationBuffer, WorldState,
+    },
+    uid::UidAllocator,
+};
+
+use crate::{
+    codec::WireCodec,
+    event::{SyncEvent, SyncEventQueue},
+    protocol::{ClientId, ResourceState},
+    recording::{Record, RecordedMessage, RecordingError, RecordingHeader, SessionReader},
+    resources::{RegisteredComponentsResource, ResourcesExt, UidRecycler},
+    world::{
+        client::{apply_resources, clear_replicated, StateUpdater},
+        entity_event::{EntityEventBuffer, PendingEntityEvent, DEFAULT_ENTITY_EVENT_WAIT},
+        sequence::StateUpdateSequencer,
+    },
+};
+
+/// How fast a [ReplayWorld](ReplayWorld) plays back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    /// As fast as the messages were recorded.
+    RealTime,
+    /// The recorded time multiplied by the given factor.
+    Scaled(f32),
+    /// Only plays back on [step](ReplayWorld::step).
+    Stepped,
+}
+
+/// A world that plays back the messages of a recording.
+pub struct ReplayWorld<ClientToServerCommand: NetworkCommand> {
+    world: World,
+    resources: Resources,
+    header: RecordingHeader,
+    records: Vec<Record>,
+    /// The index of the next record to apply.
+    position: usize,
+    /// The recorded time up to which records are applied.
+    clock: Duration,
+    speed: ReplaySpeed,
+    last_tick: Instant,
+    sequencer: StateUpdateSequencer<(WorldState, Vec<ResourceState>)>,
+    entity_events: EntityEventBuffer,
+    command_frame: Option<CommandFrame>,
+
+    ctsc: PhantomData<ClientToServerCommand>,
+}
+
+impl<ClientToServerCommand: NetworkCommand> ReplayWorld<ClientToServerCommand> {
+    /// Reads the recording and plays back the messages of the given client,
+    /// `None` plays back a recording made by a client world.
+    ///
+    /// Fails when the components of the recording are not registered with the same ids in this program.
+    pub fn new(
+        reader: impl Read,
+        client_id: Option<ClientId>,
+    ) -> Result<ReplayWorld<ClientToServerCommand>, RecordingError> {
+        let reader = SessionReader::new(reader)?;
+        let header = reader.header().clone();
+
+        let mut records = Vec::new();
+        for record in reader {
+            let record = record?;
+
+            if record.client_id == client_id {
+                records.push(record);
+            }
+        }
+
+        let mut resources = Resources::default();
+        resources.insert_client_resources::<Lz4, ClientToServerCommand>(Lz4);
+        resources.insert(header.codec);
+
+        {
+            let registered = resources.get::<RegisteredComponentsResource>().unwrap();
+            let local = RecordingHeader::new(header.codec, &registered).components;
+
+            if let Some(entry) = header.components.iter().find(|x| !local.contains(x)) {
+                return Err(RecordingError::UnknownComponent(entry.clone()));
+            }
+        }
+
+        Ok(ReplayWorld {
+            world: World::default(),
+            resources,
+            header,
+            records,
+            position: 0,
+            clock: Duration::from_secs(0),
+            speed: ReplaySpeed::RealTime,
+            last_tick: Instant::now(),
+            sequencer: StateUpdateSequencer::new(),
+            entity_events: EntityEventBuffer::new(DEFAULT_ENTITY_EVENT_WAIT),
+            command_frame: None,
+
+            ctsc: PhantomData,
+        })
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    /// Returns the command frame of the latest applied state.
+    pub fn command_frame(&self) -> Option<CommandFrame> {
+        self.command_frame
+    }
+
+    /// Returns the recorded time of the playback.
+    pub fn elapsed(&self) -> Duration {
+        self.clock
+    }
+
+    /// Returns whether all records have been played back.
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.records.len()
+    }
+
+    pub fn set_speed(&mut self, speed: ReplaySpeed) {
+        self.speed = speed;
+        self.last_tick = Instant::now();
+    }
+
+    /// Advances the playback by the time since the previous tick, does nothing when stepped.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        let passed = now.duration_since(self.last_tick);
+        self.last_tick = now;
+
+        let scale = match self.speed {
+            ReplaySpeed::RealTime => 1.,
+            ReplaySpeed::Scaled(scale) => scale.max(0.),
+            ReplaySpeed::Stepped => return,
+        };
+
+        self.clock += passed.mul_f32(scale);
+
+        while self
+            .records
+            .get(self.position)
+            .map_or(false, |record| record.elapsed <= self.clock)
+        {
+            self.apply_next();
+        }
+    }
+
+    /// Plays back the next record, returns `false` at the end of the recording.
+    pub fn step(&mut self) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+
+        self.clock = self.records[self.position].elapsed;
+        self.apply_next();
+        true
+    }
+
+    /// Rebuilds the world as it was at the given command frame,
+    /// starting from the latest initial state at or before it.
+    ///
+    /// Returns `false` when the recording has no initial state at or before the frame.
+    pub fn seek(&mut self, command_frame: CommandFrame) -> Result<bool, RecordingError> {
+        let mut start = None;
+        let mut welcome = None;
+
+        for (index, record) in self.records.iter().enumerate() {
+            match &record.message {
+                RecordedMessage::Welcome { .. } => welcome = Some(index),
+                RecordedMessage::InitialStateSync { state, .. } => {
+                    let initial_state: WorldState = self.header.codec.deserialize(state)?;
+
+                    if initial_state.command_frame > command_frame {
+                        break;
+                    }
+
+                    // The welcome before the initial state carries the data of the resources.
+                    start = Some(welcome.take().unwrap_or(index));
+                }
+                RecordedMessage::StateUpdate { state, .. } => {
+                    let state: WorldState = self.header.codec.deserialize(state)?;
+
+                    if state.command_frame > command_frame {
+                        break;
+                    }
+                }
+                RecordedMessage::EntityEvent { .. } => {}
+            }
+        }
+
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(false),
+        };
+
+        self.world = World::default();
+        self.resources.insert(UidAllocator::<Entity>::new());
+        self.resources.insert(UidRecycler::new());
+        self.sequencer = StateUpdateSequencer::new();
+        self.entity_events.clear();
+        self.command_frame = None;
+        self.position = start;
+
+        while let Some(record) = self.records.get(self.position) {
+            if let RecordedMessage::StateUpdate { state, .. } = &record.message {
+                let state: WorldState = self.header.codec.deserialize(state)?;
+
+                if state.command_frame > command_frame {
+                    break;
+                }
+            }
+
+            self.clock = record.elapsed;
+            self.apply_next();
+        }
+
+        self.last_tick = Instant::now();
+        Ok(true)
+    }
+
+    fn apply_next(&mut self) {
+        let record = self.records[self.position].clone();
+        self.position += 1;
+
+        let codec = self.header.codec;
+        let mut events = Vec::new();
+
+        match record.message {
+            RecordedMessage::Welcome {
+                sequence,
+                resources,
+            } => {
+                self.sequencer.reset(sequence);
+
+                // A welcome after a resync is followed by a fresh initial state.
+                {
+                    let mut allocator = self.resources.get_mut::<UidAllocator<Entity>>().unwrap();
+                    let mut recycler = self.resources.get_mut::<UidRecycler>().unwrap();
+                    clear_replicated(&mut self.world, &mut allocator, &mut recycler);
+                }
+                self.entity_events.clear();
+
+                apply_resources(&self.resources, &resources, codec, false);
+            }
+            RecordedMessage::InitialStateSync { sequence, state } => {
+                match codec.deserialize::<WorldState>(&state) {
+                    Ok(mut initial_state) => {
+                        self.sequencer.reset(sequence);
+                        self.apply(&mut initial_state, true);
+                    }
+                    Err(e) => log::error!("Cannot decode the recorded initial state: {}", e),
+                }
+            }
+            RecordedMessage::StateUpdate {
+                sequence,
+                state,
+                resources,
+            } => match codec.deserialize::<WorldState>(&state) {
+                Ok(state) => {
+                    if let Some(event) = self.sequencer.receive(sequence, (state, resources)) {
+                        events.push(event);
+                    }
+                }
+                Err(e) => log::error!("Cannot decode the recorded state update: {}", e),
+            },
+            RecordedMessage::EntityEvent {
+                kind,
+                entity_id,
+                command_frame,
+                data,
+            } => self.entity_events.push(PendingEntityEvent {
+                kind,
+                entity_id,
+                command_frame,
+                data,
+            }),
+        }
+
+        for (mut update, resource_differences) in self.sequencer.ready(&mut events) {
+            self.apply(&mut update, false);
+            apply_resources(&self.resources, &resource_differences, codec, true);
+        }
+
+        if let Some(applied_frame) = self.command_frame {
+            let resources = &self.resources;
+            let allocator = resources.get::<UidAllocator<Entity>>().unwrap();
+            let recycler = resources.get::<UidRecycler>().unwrap();
+
+            self.entity_events
+                .deliver(applied_frame, &allocator, &recycler, resources, codec);
+        }
+
+        self.push_events(events);
+    }
+
+    fn apply(&mut self, state: &mut WorldState, initial: bool) {
+        let resources = &self.resources;
+        let mut allocator = resources.get_mut::<UidAllocator<Entity>>().unwrap();
+        let mut recycler = resources.get_mut::<UidRecycler>().unwrap();
+        let registered = resources.get::<RegisteredComponentsResource>().unwrap();
+        let mut client_buffer = resources
+            .get_mut::<ClientCommandBuffer<ClientToServerCommand>>()
+            .unwrap();
+        let mut resimulation_buffer = resources
+            .get_mut::<ResimulationBuffer<ClientToServerCommand>>()
+            .unwrap();
+        let codec = *resources.get::<WireCodec>().unwrap();
+
+        let command_frame = state.command_frame;
+
+        let mut state_updater = StateUpdater::new(
+            &mut allocator,
+            &mut recycler,
+            &mut self.world,
+            &registered,
+            state,
+            &mut client_buffer,
+            &mut resimulation_buffer,
+            command_frame,
+            codec,
+            Lz4,
+        );
+
+        if initial {
+            state_updater.apply_entity_inserts();
+        } else {
+            state_updater.apply();
+        }
+
+        self.command_frame = Some(command_frame);
+    }
+
+    fn push_events(&mut self, events: Vec<SyncEvent>) {
+        if events.is_empty() {
+            return;
+        }
+
+        let mut event_queue = self.resources.get_mut::<SyncEventQueue>().unwrap();
+        for event in events {
+            event_queue.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::io::Cursor;
+
+    use serde::{Deserialize, Serialize};
+
+    use net_sync::synchronisation::{NetworkCommand, WorldState};
+
+    use crate::{
+        codec::WireCodec,
+        recording::{
+            test::SharedBuffer, ManifestEntry, RecordingError, RecordingHeader, SessionRecorder,
+        },
+        resources::RegisteredComponentsResource,
+        world::replay::{ReplaySpeed, ReplayWorld},
+    };
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Command;
+
+    impl NetworkCommand for Command {}
+
+    const CODEC: WireCodec = WireCodec::BincodeFixint;
+
+    // A server recording for client 1: an entity in the initial state, one inserted and then the first one removed.
+    fn recording(header: &RecordingHeader) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut recorder = SessionRecorder::new(buffer.clone(), header).unwrap();
+
+        let mut initial = WorldState::new(3);
+        initial.insert_entity(1, Vec::new());
+        recorder
+            .record_initial_state_sync(Some(1), 0, &CODEC.serialize(&initial).unwrap())
+            .unwrap();
+
+        let mut inserted = WorldState::new(4);
+        inserted.insert_entity(2, Vec::new());
+        recorder
+            .record_state_update(Some(1), 1, &CODEC.serialize(&inserted).unwrap(), &[])
+            .unwrap();
+
+        let mut removed = WorldState::new(5);
+        removed.remove_entity(1);
+        recorder
+            .record_state_update(Some(1), 2, &CODEC.serialize(&removed).unwrap(), &[])
+            .unwrap();
+
+        // Another client is not played back.
+        recorder
+            .record_state_update(Some(2), 1, &CODEC.serialize(&removed).unwrap(), &[])
+            .unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        bytes
+    }
+
+    fn header() -> RecordingHeader {
+        RecordingHeader::new(CODEC, &RegisteredComponentsResource::new())
+    }
+
+    #[test]
+    fn recording_plays_back_test() {
+        let mut replay =
+            ReplayWorld::<Command>::new(Cursor::new(recording(&header())), Some(1)).unwrap();
+        replay.set_speed(ReplaySpeed::Stepped);
+
+        assert!(replay.step());
+        assert_eq!(replay.command_frame(), Some(3));
+        assert_eq!(replay.world().len(), 1);
+
+        assert!(replay.step());
+        assert_eq!(replay.command_frame(), Some(4));
+        assert_eq!(replay.world().len(), 2);
+
+        assert!(replay.step());
+        assert_eq!(replay.command_frame(), Some(5));
+        assert_eq!(replay.world().len(), 1);
+
+        assert!(!replay.step());
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn seek_rebuilds_the_world_test() {
+        let mut replay =
+            ReplayWorld::<Command>::new(Cursor::new(recording(&header())), Some(1)).unwrap();
+        replay.set_speed(ReplaySpeed::Stepped);
+        while replay.step() {}
+
+        assert!(replay.seek(4).unwrap());
+        assert_eq!(replay.command_frame(), Some(4));
+        assert_eq!(replay.world().len(), 2);
+        assert!(!replay.is_finished());
+
+        // The recording starts at command frame 3.
+        assert!(!replay.seek(2).unwrap());
+    }
+
+    #[test]
+    fn unknown_component_is_rejected_test() {
+        let mut header = header();
+        header.components.push(ManifestEntry {
+            uid: 999,
+            type_name: String::from("Unknown"),
+        });
+
+        match ReplayWorld::<Command>::new(Cursor::new(recording(&header)), Some(1)) {
+            Err(RecordingError::UnknownComponent(entry)) => assert_eq!(entry.uid, 999),
+            _ => panic!("Expected the recording to be rejected."),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Puts the state updates from the server back in order before they are applied.
+//!
+//! Each state update carries a wrapping sequence number.
+//! Updates that arrive early are held back until the updates before them arrived,
+//! if those do not arrive in time they are reported as lost and a fresh initial state sync is requested,
+//! as the updates after the gap are differences against a state the client never received.
+
+use std::collections::HashMap;
+
+use net_sync::synchronisation::WorldState;
+
+use crate::event::SyncEvent;
+
+/// The number of ticks an early state update waits for the updates before it.
+const GAP_TIMEOUT_TICKS: u32 = 3;
+/// The number of ticks to wait for a requested initial state sync before it is requested again.
+const SYNC_RETRY_TICKS: u32 = 30;
+/// The number of updates held back, beyond it the oldest update is dropped.
+const MAX_PENDING: usize = 64;
+
+/// Returns whether sequence `a` comes after sequence `b`, taking wrapping into account.
+pub fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+/// Puts the updates in order, an update is the world state along with whatever else is applied with it.
+pub(crate) struct StateUpdateSequencer<S = WorldState> {
+    last_applied: Option<u16>,
+    pending: HashMap<u16, S>,
+    waiting_ticks: u32,
+    /// No updates are applied until the requested initial state sync arrived.
+    awaiting_sync: bool,
+    sync_requested: bool,
+}
+
+impl<S> StateUpdateSequencer<S> {
+    pub(crate) fn new() -> StateUpdateSequencer<S> {
+        StateUpdateSequencer {
+            last_applied: None,
+            pending: HashMap::new(),
+            waiting_ticks: 0,
+            awaiting_sync: false,
+            sync_requested: false,
+        }
+    }
+
+    /// Starts over from the state with the given sequence, as received with the initial state sync.
+    pub(crate) fn reset(&mut self, sequence: u16) {
+        self.last_applied = Some(sequence);
+        self.pending
+            .retain(|pending, _| sequence_greater_than(*pending, sequence));
+        self.waiting_ticks = 0;
+        self.awaiting_sync = false;
+        self.sync_requested = false;
+    }
+
+    /// Returns the sequence of the latest applied state update.
+    pub(crate) fn last_applied(&self) -> Option<u16> {
+        self.last_applied
+    }
+
+    /// Holds a received state update until it can be applied, returns an event if an update is dropped.
+    ///
+    /// At most [MAX_PENDING](MAX_PENDING) updates are held back, e.g. while a slow initial state sync is awaited.
+    /// The oldest update is dropped first, the awaited initial state already contains its changes.
+    pub(crate) fn receive(&mut self, sequence: u16, state: S) -> Option<SyncEvent> {
+        if let Some(last_applied) = self.last_applied {
+            if sequence == last_applied {
+                return Some(SyncEvent::DuplicateStateUpdate { sequence });
+            }
+
+            if !sequence_greater_than(sequence, last_applied) {
+                return Some(SyncEvent::StaleStateUpdate { sequence });
+            }
+        }
+
+        if self.pending.contains_key(&sequence) {
+            return Some(SyncEvent::DuplicateStateUpdate { sequence });
+        }
+
+        self.pending.insert(sequence, state);
+
+        if self.pending.len() > MAX_PENDING {
+            let from = self.last_applied.map_or(0, |x| x.wrapping_add(1));
+            let oldest = self
+                .earliest_pending(from)
+                .expect("Should have pending updates.");
+            self.pending.remove(&oldest);
+
+            return Some(SyncEvent::StaleStateUpdate { sequence: oldest });
+        }
+
+        None
+    }
+
+    /// Returns whether an initial state sync should be requested from the server, once per request.
+    pub(crate) fn take_sync_request(&mut self) -> bool {
+        std::mem::replace(&mut self.sync_requested, false)
+    }
+
+    /// Takes the state updates that can be applied in order, called once per tick.
+    pub(crate) fn ready(&mut self, events: &mut Vec<SyncEvent>) -> Vec<S> {
+        let mut ready = Vec::new();
+
+        if self.awaiting_sync {
+            self.waiting_ticks += 1;
+
+            if self.waiting_ticks >= SYNC_RETRY_TICKS {
+                self.waiting_ticks = 0;
+                self.sync_requested = true;
+            }
+
+            return ready;
+        }
+
+        loop {
+            let next = match self.last_applied {
+                Some(last_applied) => last_applied.wrapping_add(1),
+                // Without a starting point the earliest update is next.
+                None => match self.earliest_pending(0) {
+                    Some(earliest) => earliest,
+                    None => break,
+                },
+            };
+
+            if let Some(state) = self.pending.remove(&next) {
+                ready.push(state);
+                self.last_applied = Some(next);
+                self.waiting_ticks = 0;
+                continue;
+            }
+
+            if self.pending.is_empty() {
+                break;
+            }
+
+            if self.waiting_ticks < GAP_TIMEOUT_TICKS {
+                self.waiting_ticks += 1;
+                break;
+            }
+
+            // Give up on the missing updates and start over from a fresh initial state.
+            let earliest = self
+                .earliest_pending(next)
+                .expect("Should have pending updates.");
+            events.push(SyncEvent::StateUpdatesLost {
+                first: next,
+                count: earliest.wrapping_sub(next),
+            });
+            self.waiting_ticks = 0;
+            self.awaiting_sync = true;
+            self.sync_requested = true;
+            break;
+        }
+
+        ready
+    }
+
+    fn earliest_pending(&self, from: u16) -> Option<u16> {
+        self.pending
+            .keys()
+            .min_by_key(|sequence| sequence.wrapping_sub(from))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use net_sync::synchronisation::WorldState;
+
+    use crate::{
+        event::SyncEvent,
+        world::sequence::{sequence_greater_than, StateUpdateSequencer, MAX_PENDING},
+    };
+
+    fn state(command_frame: u32) -> WorldState {
+        WorldState::new(command_frame)
+    }
+
+    fn ready_frames(sequencer: &mut StateUpdateSequencer, events: &mut Vec<SyncEvent>) -> Vec<u32> {
+        sequencer
+            .ready(events)
+            .iter()
+            .map(|x| x.command_frame)
+            .collect()
+    }
+
+    #[test]
+    fn sequence_wraps_test() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(sequence_greater_than(0, u16::max_value()));
+        assert!(!sequence_greater_than(u16::max_value(), 0));
+        assert!(!sequence_greater_than(5, 5));
+    }
+
+    #[test]
+    fn out_of_order_updates_are_applied_in_order_test() {
+        let mut sequencer = StateUpdateSequencer::new();
+        let mut events = Vec::new();
+        sequencer.reset(0);
+
+        sequencer.receive(2, state(2));
+        assert!(ready_frames(&mut sequencer, &mut events).is_empty());
+
+        sequencer.receive(1, state(1));
+        assert_eq!(ready_frames(&mut sequencer, &mut events), vec![1, 2]);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn stale_and_duplicate_updates_are_dropped_test() {
+        let mut sequencer = StateUpdateSequencer::new();
+        let mut events = Vec::new();
+        sequencer.reset(5);
+
+        assert_eq!(
+            sequencer.receive(4, state(4)),
+            Some(SyncEvent::StaleStateUpdate { sequence: 4 })
+        );
+        assert_eq!(
+            sequencer.receive(5, state(5)),
+            Some(SyncEvent::DuplicateStateUpdate { sequence: 5 })
+        );
+
+        sequencer.receive(7, state(7));
+        assert_eq!(
+            sequencer.receive(7, state(7)),
+            Some(SyncEvent::DuplicateStateUpdate { sequence: 7 })
+        );
+        assert!(ready_frames(&mut sequencer, &mut events).is_empty());
+    }
+
+    #[test]
+    fn gap_requests_initial_state_after_timeout_test() {
+        let mut sequencer = StateUpdateSequencer::new();
+        let mut events = Vec::new();
+        sequencer.reset(u16::max_value());
+
+        sequencer.receive(2, state(2));
+
+        for _ in 0..3 {
+            assert!(ready_frames(&mut sequencer, &mut events).is_empty());
+        }
+        assert!(!sequencer.take_sync_request());
+
+        assert!(ready_frames(&mut sequencer, &mut events).is_empty());
+        assert_eq!(
+            events,
+            vec![SyncEvent::StateUpdatesLost { first: 0, count: 2 }]
+        );
+        assert!(sequencer.take_sync_request());
+        assert!(!sequencer.take_sync_request());
+
+        // Nothing is applied until the initial state arrived.
+        sequencer.receive(0, state(0));
+        assert!(ready_frames(&mut sequencer, &mut events).is_empty());
+
+        sequencer.receive(6, state(6));
+        sequencer.reset(5);
+        assert_eq!(ready_frames(&mut sequencer, &mut events), vec![6]);
+    }
+
+    #[test]
+    fn sync_is_requested_again_test() {
+        let mut sequencer = StateUpdateSequencer::new();
+        let mut events = Vec::new();
+        sequencer.reset(0);
+
+        sequencer.receive(2, state(2));
+        for _ in 0..4 {
+            ready_frames(&mut sequencer, &mut events);
+        }
+        assert!(sequencer.take_sync_request());
+
+        for _ in 0..30 {
+            ready_frames(&mut sequencer, &mut events);
+        }
+        assert!(sequencer.take_sync_request());
+    }
+
+    #[test]
+    fn pending_updates_are_capped_while_awaiting_sync_test() {
+        let mut sequencer = StateUpdateSequencer::new();
+        let mut events = Vec::new();
+        sequencer.reset(0);
+
+        sequencer.receive(2, state(2));
+        for _ in 0..4 {
+            ready_frames(&mut sequencer, &mut events);
+        }
+        assert!(sequencer.take_sync_request());
+
+        for sequence in 3..(2 + MAX_PENDING as u16) {
+            assert_eq!(sequencer.receive(sequence, state(sequence as u32)), None);
+        }
+
+        // The oldest update makes room for the newest.
+        let newest = 2 + MAX_PENDING as u16;
+        assert_eq!(
+            sequencer.receive(newest, state(newest as u32)),
+            Some(SyncEvent::StaleStateUpdate { sequence: 2 })
+        );
+
+        sequencer.reset(2);
+        let applied = ready_frames(&mut sequencer, &mut events);
+        assert_eq!(applied.len(), MAX_PENDING);
+        assert_eq!(applied.last(), Some(&(newest as u32)));
+    }
+}
@@ -1,8 +1,15 @@
-use std::net::TcpListener;
+use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::TcpListener,
+    path::Path,
+};
 
 use legion::{
-    any,
-    systems::{Builder, Resource},
+    query::{component, IntoQuery, Read},
+    systems::{Builder, Resource, Schedule},
+    world::EntityStore,
     Entity, Resources, Universe, World,
 };
 use serde::export::PhantomData;
@@ -10,29 +17,75 @@ use serde::export::PhantomData;
 use net_sync::{
     compression::{lz4::Lz4, CompressionStrategy},
     synchronisation::{
-        CommandFrameTicker, ComponentData, ModifiedComponentsBuffer, NetworkCommand,
+        CommandFrame, CommandFrameTicker, ComponentData, ModifiedComponentsBuffer, NetworkCommand,
         NetworkMessage, WorldState,
     },
     transport,
-    transport::PostOffice,
-    uid::UidAllocator,
+    uid::{Uid, UidAllocator},
 };
 
 use crate::{
-    event::{LegionEvent, LegionEventHandler},
-    resources::{EventResource, RegisteredComponentsResource, ResourcesExt},
-    systems::BuilderExt,
-    world::{world_instance::WorldInstance, WorldBuilder},
+    codec::{WireCodec, TRACKER_CODEC},
+    components::{NetworkEntity, OwnerComponent, ParentComponent, PriorityComponent, Replicated},
+    event::{LegionEvent, LegionEventHandler, SyncEvent, SyncEventQueue},
+    protocol::{
+        ClientId, ClientMessage, ClientToServer, DisconnectReason, MessageKind, ResourceState,
+        ServerMessage, ServerPostOffice, ServerToClient, SessionToken,
+    },
+    recording::{RecordingHeader, SessionRecorder},
+    register::{
+        ComponentRegistration, Delivery, RegisteredEntityEventsResource,
+        RegisteredMessagesResource, ResourceRegister, Simulation, Tracking,
+    },
+    resources::{
+        BanList, EventResource, LinkConditioner, LinkConditions, RegisteredComponentsResource,
+        ReplicationMetrics, ResourcesExt, ServerCommandBuffer, Sessions, UidRecycler,
+        DEFAULT_SESSION_GRACE_PERIOD,
+    },
+    systems::{tcp, BuilderExt},
+    world::{
+        bandwidth::{limit_bandwidth, measure_bandwidth, BandwidthBudget, PriorityAccumulator},
+        change_detection::ChangeDetector,
+        interest::{InterestFilter, InterestSets},
+        lag_compensation::{HistoryView, LagCompensation, LagCompensationConfig},
+        lifecycle::{ConnectDecision, LifecycleHooks},
+        snapshot::{SnapshotError, WorldSnapshot},
+        world_instance::WorldInstance,
+        WorldBuilder,
+    },
 };
-use bincode::Options;
-use net_sync::re_exports::bincode;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// The number of command frames after which a client receives a state update even if nothing changed,
+/// it carries the command ack and the command frame offset the client keeps its clock with.
+const KEEPALIVE_FRAMES: CommandFrame = 10;
+
+// The latest state update sent to a client, its sequence is stamped on the state updates to put them in order.
+struct SentUpdate {
+    sequence: u16,
+    command_ack: Option<CommandFrame>,
+    command_frame: CommandFrame,
+}
 
-pub struct ServerConfig {}
+pub struct ServerConfig {
+    /// The amount of inserted entities and component changes sent to each client, `None` sends all changes every command frame.
+    ///
+    /// Changes of predicted components are always sent in the command frame they happened in.
+    pub bandwidth_budget: Option<BandwidthBudget>,
+    /// The components the server keeps a history of to rewind to, `None` keeps no history.
+    pub lag_compensation: Option<LagCompensationConfig>,
+    /// The time a disconnected client has to resume its session,
+    /// after which the entities it owns are removed.
+    pub session_grace_period: Duration,
+}
 
 impl Default for ServerConfig {
     fn default() -> Self {
-        ServerConfig {}
+        ServerConfig {
+            bandwidth_budget: None,
+            lag_compensation: None,
+            session_grace_period: DEFAULT_SESSION_GRACE_PERIOD,
+        }
     }
 }
 
@@ -40,6 +93,11 @@ pub struct ServerWorldBuilder<ServerToClientMessage, ClientToServerMessage, Clie
     resources: Resources,
     system_builder: Builder,
     config: ServerConfig,
+    recording: Option<Box<dyn Write + Send + Sync>>,
+    snapshot: Option<WorldSnapshot>,
+    hooks: LifecycleHooks,
+    interest: Option<InterestFilter>,
+    flush_systems: Option<Builder>,
 
     stcm: PhantomData<ServerToClientMessage>,
     ctsm: PhantomData<ClientToServerMessage>,
@@ -58,6 +116,11 @@ impl<
             resources: Default::default(),
             system_builder: Builder::default(),
             config: ServerConfig::default(),
+            recording: None,
+            snapshot: None,
+            hooks: LifecycleHooks::default(),
+            interest: None,
+            flush_systems: None,
 
             stcm: PhantomData,
             ctsm: PhantomData,
@@ -109,12 +172,52 @@ impl<
         let universe = Universe::new();
         let mut main_world = universe.create_world();
 
+        // Restored before the event subscription, the restored entities keep their network ids.
+        let restored = s.snapshot.as_ref().map(|snapshot| {
+            let mut allocator = s.resources.get_mut::<UidAllocator<Entity>>().unwrap();
+            let components = s.resources.get::<RegisteredComponentsResource>().unwrap();
+            let restored = snapshot.restore(&mut main_world, &mut allocator, &components);
+
+            s.resources
+                .get_mut::<CommandFrameTicker>()
+                .unwrap()
+                .set_command_frame(snapshot.command_frame());
+
+            restored
+        });
+
+        if let Some(snapshot) = &s.snapshot {
+            s.resources.insert(snapshot.recycler().clone());
+        }
+
         s.resources.insert(EventResource::new(&mut main_world));
         s.resources.insert(universe);
 
         let world = WorldInstance::new(main_world, s.system_builder.build());
 
-        ServerWorld::new(s.resources, world)
+        let resources = &s.resources;
+        let recorder = s.recording.take().map(|writer| {
+            let header = RecordingHeader::new(
+                *resources.get::<WireCodec>().unwrap(),
+                &resources.get::<RegisteredComponentsResource>().unwrap(),
+            );
+            SessionRecorder::new(writer, &header).expect("Cannot write the recording header.")
+        });
+
+        let mut server = ServerWorld::new(s.resources, world);
+        server.recorder = recorder;
+        server.lag_compensation = s.config.lag_compensation.clone().map(LagCompensation::new);
+
+        if let (Some(snapshot), Some(restored)) = (&s.snapshot, restored) {
+            server.state_update_sequence = snapshot.state_update_sequence();
+            server.replicated.extend(restored);
+        }
+
+        server.config = s.config;
+        server.hooks = s.hooks;
+        server.interest = s.interest.map(InterestSets::new);
+        server.flush = s.flush_systems.as_mut().map(|builder| builder.build());
+        server
     }
 }
 
@@ -130,6 +233,11 @@ impl<
             .expect("Cannot set non-blocking on TCP socket.");
         self.resources.insert_tcp_listener_resources(listener);
         self.system_builder = self.system_builder.add_tcp_server_systems::<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>();
+        self.flush_systems = Some(tcp::tcp_server_sent_system::<
+            ServerToClientMessage,
+            ClientToServerMessage,
+            ClientToServerCommand,
+        >(Builder::default()));
         self
     }
 
@@ -137,6 +245,87 @@ impl<
         self.config = config;
         self
     }
+
+    /// Simulates the given network conditions for the messages exchanged with each client.
+    ///
+    /// The seed makes the simulation reproducible.
+    pub fn with_link_conditioner(mut self, conditions: LinkConditions, seed: u64) -> Self {
+        self.resources.insert(LinkConditioner::<(
+            ClientId,
+            ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+        )>::new(conditions, seed));
+        self.resources.insert(LinkConditioner::<(
+            ClientId,
+            ServerToClient<ServerToClientMessage>,
+        )>::new(conditions, seed.wrapping_add(1)));
+        self
+    }
+
+    /// Sets the codec the server encodes its data with, the clients should use the same codec.
+    pub fn with_wire_codec(mut self, codec: WireCodec) -> Self {
+        self.resources.insert(codec);
+        self
+    }
+
+    /// Creates a builder that restores the world saved with [save_snapshot](ServerWorld::save_snapshot).
+    ///
+    /// The restored entities keep their network ids and the server continues at the saved command frame,
+    /// such that reconnecting clients resync without losing the identity of their entities.
+    pub fn from_snapshot(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Ok(Self::default().with_snapshot(WorldSnapshot::load(path)?))
+    }
+
+    /// Restores the given snapshot when the world is built.
+    pub fn with_snapshot(mut self, snapshot: WorldSnapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Calls the hook for every new client, a rejected client is not served.
+    pub fn on_connect(
+        mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) -> ConnectDecision
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.hooks.on_connect.push(Box::new(hook));
+        self
+    }
+
+    /// Calls the hook for every client whose connection dropped, it may still resume its session.
+    pub fn on_disconnect(
+        mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_disconnect.push(Box::new(hook));
+        self
+    }
+
+    /// Calls the hook for every disconnected client that did not resume its session within the grace period,
+    /// before the entities it owns are removed.
+    pub fn on_timeout(
+        mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_timeout.push(Box::new(hook));
+        self
+    }
+
+    /// Records the initial state syncs and state updates sent to each client to the given writer.
+    pub fn with_recording(mut self, writer: impl Write + Send + Sync + 'static) -> Self {
+        self.recording = Some(Box::new(writer));
+        self
+    }
+
+    /// Sends each client only the replicated entities the filter returns true for, and their children along with them.
+    pub fn with_interest(
+        mut self,
+        filter: impl Fn(ClientId, Entity, &World) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.interest = Some(Box::new(filter));
+        self
+    }
 }
 
 pub struct ServerWorld<
@@ -147,9 +336,45 @@ pub struct ServerWorld<
     pub(crate) world: WorldInstance,
     config: ServerConfig,
     pub(crate) resources: Resources,
+    /// The sequence new clients start from, advanced every command frame.
     pub(crate) state_update_sequence: u16,
+    /// The latest state update sent to each client.
+    sent_updates: HashMap<ClientId, SentUpdate>,
 
-    pub(crate) last_tick: Instant,
+    /// The entities that have a network id and are sent to the clients.
+    replicated: HashSet<Entity>,
+    /// The entities that keep their network id while they are not sent to the clients, because their parent is not.
+    held: HashSet<Entity>,
+    /// The latest command frame each client acknowledged to have applied.
+    acknowledged: HashMap<ClientId, CommandFrame>,
+    update_limiter: UpdateLimiter,
+    /// The changes that did not fit in the bandwidth budget of each client.
+    accumulators: HashMap<ClientId, PriorityAccumulator>,
+    lag_compensation: Option<LagCompensation>,
+    recorder: Option<SessionRecorder>,
+    hooks: LifecycleHooks,
+    /// The entities each client is sent, `None` sends every replicated entity to every client.
+    interest: Option<InterestSets>,
+    /// The clients of the post office as of the previous tick.
+    known_clients: HashSet<ClientId>,
+    /// The accepted clients that did not yet send their hello.
+    handshakes: Vec<ClientId>,
+    /// The accepted clients that are welcomed at the next command frame.
+    welcome: Vec<ClientId>,
+    /// The clients that received their welcome, only they are sent the world and only their commands and messages are handled.
+    welcomed: HashSet<ClientId>,
+    /// The clients that are no longer served, because a connect hook rejected them or they were disconnected.
+    dropped: HashSet<ClientId>,
+    /// The clients to disconnect at the next tick.
+    pending_disconnects: Vec<(ClientId, DisconnectReason)>,
+    /// Sends the pending messages on shutdown.
+    flush: Option<Schedule>,
+    /// The replicated resources as of the previous command frame, the changes since are sent as differences.
+    resource_states: HashMap<MessageKind, Vec<u8>>,
+    /// Finds the changes of the components with automatic tracking.
+    change_detector: ChangeDetector,
+    /// The owning client of each entity as of the previous command frame.
+    owners: HashMap<Uid, ClientId>,
 
     stcm: PhantomData<ServerToClientMessage>,
     ctsm: PhantomData<ClientToServerMessage>,
@@ -171,8 +396,27 @@ impl<
             resources,
             config: ServerConfig::default(),
             state_update_sequence: 0,
+            sent_updates: HashMap::new(),
 
-            last_tick: Instant::now(),
+            replicated: HashSet::new(),
+            held: HashSet::new(),
+            acknowledged: HashMap::new(),
+            update_limiter: UpdateLimiter::default(),
+            accumulators: HashMap::new(),
+            lag_compensation: None,
+            recorder: None,
+            hooks: LifecycleHooks::default(),
+            interest: None,
+            known_clients: HashSet::new(),
+            handshakes: Vec::new(),
+            welcome: Vec::new(),
+            welcomed: HashSet::new(),
+            dropped: HashSet::new(),
+            pending_disconnects: Vec::new(),
+            flush: None,
+            resource_states: HashMap::new(),
+            change_detector: ChangeDetector::new(),
+            owners: HashMap::new(),
 
             stcm: PhantomData,
             ctsm: PhantomData,
@@ -180,207 +424,2309 @@ impl<
         }
     }
 
-    pub fn tick(&mut self) {
-        let resources = &mut self.resources;
-
-        self.world.execute(resources);
+    /// Runs the systems and, once per command frame, exchanges the state with the clients.
+    ///
+    /// After the systems ran, the connection hooks are called for the clients that connected or left.
+    /// The commands of the clients are moved into the [ServerCommandBuffer](ServerCommandBuffer) jitter buffer,
+    /// the copies that clients resend are compared with the buffered commands and dropped.
+    /// The typed messages of the clients are put in their [Inbox](crate::resources::Inbox)
+    /// and the messages in the [Outbox](crate::resources::Outbox) of each message type are sent,
+    /// as are the events in the [EntityEventOutbox](crate::resources::EntityEventOutbox) of each entity event type.
+    pub fn tick(&mut self)
+    where
+        ClientToServerCommand: PartialEq,
+    {
+        self.world.execute(&mut self.resources);
+        self.handle_connections();
 
+        let resources = &mut self.resources;
         let mut command_ticker = resources.get_mut::<CommandFrameTicker>().unwrap();
 
         if command_ticker.try_tick() {
-            let last_tick = self.last_tick;
-
             // This state packet is for the previous command frame.
             let previous_command_frame = command_ticker.command_frame() - 1;
             let mut world_state = WorldState::new(previous_command_frame);
+            self.state_update_sequence = self.state_update_sequence.wrapping_add(1);
 
             // Setup resources
             let mut allocator = resources.get_mut::<UidAllocator<Entity>>().unwrap();
+            let mut recycler = resources.get_mut::<UidRecycler>().unwrap();
             let components = resources.get::<RegisteredComponentsResource>().unwrap();
             let event_resource = resources.get_mut::<EventResource>().unwrap();
             let mut modified_buffer = resources.get_mut::<ModifiedComponentsBuffer>().unwrap();
+            let mut metrics = resources.get_mut::<ReplicationMetrics>().unwrap();
+            let codec = *resources.get::<WireCodec>().unwrap();
+            let mut command_buffer = resources
+                .get_mut::<ServerCommandBuffer<ClientToServerCommand>>()
+                .unwrap();
+            let mut sessions = resources.get_mut::<Sessions>().unwrap();
+
+            let mut postoffice = resources
+                .get_mut::<ServerPostOffice<
+                    ServerToClientMessage,
+                    ClientToServerMessage,
+                    ClientToServerCommand,
+                >>()
+                .unwrap();
+
+            let incoming = drain_client_messages(&mut postoffice, &self.welcomed);
+
+            let mut outgoing = Vec::new();
+
+            receive_disconnects(&incoming, &mut self.pending_disconnects);
+
+            // Network ids can be reused once all clients applied the state update that removed them.
+            collect_acknowledgements(&mut self.acknowledged, &incoming, &postoffice);
+
+            // Clients that lost state updates start over with a fresh initial state.
+            let resyncs = incoming
+                .iter()
+                .filter_map(|(id, message)| match message {
+                    transport::ClientToServerMessage::Message(ClientMessage::RequestSync) => {
+                        Some(*id)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<ClientId>>();
+
+            receive_typed_messages(resources, &incoming, codec);
+
+            buffer_commands(
+                &mut command_buffer,
+                incoming,
+                &postoffice,
+                command_ticker.command_frame(),
+            );
+            let acknowledged_frame = self
+                .acknowledged
+                .values()
+                .min()
+                .cloned()
+                .unwrap_or(CommandFrame::max_value());
+            recycler.release_acknowledged(acknowledged_frame);
+
+            // Components with automatic tracking are compared with the state sent last.
+            let automatic = components
+                .slice_with_uid()
+                .iter()
+                .map(|x| x.1)
+                .filter(|x| {
+                    x.replication().tracking == Tracking::Automatic && !x.replication().server_only
+                })
+                .collect::<Vec<&ComponentRegistration>>();
+            let detected = if automatic.is_empty() {
+                Vec::new()
+            } else {
+                self.change_detector.detect(
+                    &self.world.world,
+                    self.replicated.iter(),
+                    &allocator,
+                    &automatic,
+                )
+            };
 
             // Add the serializes differences to the world state.
+            let mut baselines = HashMap::new();
             add_differences_to_state(
                 &components,
                 &mut world_state,
                 &mut modified_buffer,
+                detected,
+                &mut baselines,
+                &mut self.update_limiter,
                 &self.world.world,
                 &allocator,
+                &recycler,
+                codec,
             );
 
+            let resource_differences =
+                resource_differences(resources, &mut self.resource_states, codec);
+
             handle_world_events(
                 &self.world.world,
                 &mut allocator,
+                &mut recycler,
+                &mut self.replicated,
+                &mut self.held,
                 &components,
                 &event_resource,
                 &mut world_state,
+                codec,
             );
 
-            let mut postoffice =
-                resources
-                    .get_mut::<PostOffice<
-                        ServerToClientMessage,
-                        ClientToServerMessage,
-                        ClientToServerCommand,
-                    >>()
-                    .unwrap();
+            // The marker can be added to or removed from an entity without any registered component changing.
+            update_replication_markers(
+                &self.world.world,
+                &mut allocator,
+                &mut recycler,
+                &mut self.replicated,
+                &mut self.held,
+                &components,
+                &mut world_state,
+                codec,
+            );
+
+            // Children are only replicated along with their parent.
+            update_hierarchy(
+                &self.world.world,
+                &allocator,
+                &recycler,
+                &mut self.replicated,
+                &mut self.held,
+                &components,
+                &mut world_state,
+                codec,
+            );
+            order_parents_first(&self.world.world, &allocator, &recycler, &mut world_state);
+
+            // The new entities are compared with the state they were inserted with from now on.
+            if !automatic.is_empty() {
+                let inserted = world_state
+                    .inserted
+                    .iter()
+                    .map(|insert| *allocator.get_by_val(&insert.entity_id()))
+                    .collect::<Vec<Entity>>();
+
+                self.change_detector.seed(
+                    &self.world.world,
+                    inserted.iter(),
+                    &allocator,
+                    &automatic,
+                );
+            }
+
+            if let Some(lag_compensation) = &mut self.lag_compensation {
+                lag_compensation.record(
+                    previous_command_frame,
+                    &self.world.world,
+                    self.replicated.iter(),
+                    &allocator,
+                    &components,
+                );
+            }
+
+            let world = &self.world.world;
+
+            // Owner-only components are filtered out for the clients that do not own the entity.
+            let owner_only = components
+                .slice_with_uid()
+                .iter()
+                .filter(|x| x.1.replication().owner_only)
+                .map(|x| x.0)
+                .collect::<HashSet<Uid>>();
+
+            let owners = if owner_only.is_empty() {
+                HashMap::new()
+            } else {
+                entity_owners(&self.world.world, &self.replicated, &allocator)
+            };
+
+            // The new owner of an entity receives its owner-only components in full, the previous owner loses them.
+            let transfers = owner_transfers(
+                &self.owners,
+                &owners,
+                &world_state,
+                world,
+                &allocator,
+                &components,
+                &owner_only,
+                codec,
+            );
+            self.owners = owners.clone();
+
+            let state_for_client = |world_state: &WorldState, client: ClientId| {
+                if owner_only.is_empty() {
+                    world_state.clone()
+                } else {
+                    filter_owner_only(world_state, client, &owner_only, &owners)
+                }
+            };
+
+            // First do an initial state sync to each new client, and again to each resyncing client.
+            let mut new_clients = self
+                .welcome
+                .drain(..)
+                .chain(resyncs)
+                .filter(|id| postoffice.clients().any(|x| x.0 == id))
+                .collect::<Vec<ClientId>>();
+            new_clients.sort();
+            new_clients.dedup();
+
+            if let Some(interest) = &mut self.interest {
+                let welcomed = &self.welcomed;
+                let clients = postoffice
+                    .clients()
+                    .map(|x| *x.0)
+                    .filter(|id| welcomed.contains(id) || new_clients.contains(id))
+                    .collect::<Vec<ClientId>>();
+
+                interest.refresh(
+                    clients.iter(),
+                    world,
+                    &self.replicated,
+                    &allocator,
+                    &recycler,
+                );
+            }
+
+            if !new_clients.is_empty() {
+                let mut initial_state = initial_world_state(
+                    &self.world.world,
+                    &self.replicated,
+                    &allocator,
+                    &components,
+                    previous_command_frame,
+                    codec,
+                );
+                order_parents_first(&self.world.world, &allocator, &recycler, &mut initial_state);
+                let initial_resources = resource_states(resources, codec);
+
+                for id in new_clients.iter() {
+                    let client_state = match &mut self.interest {
+                        Some(interest) => {
+                            state_for_client(&interest.initial_state(*id, &initial_state), *id)
+                        }
+                        None => state_for_client(&initial_state, *id),
+                    };
+                    let bytes = codec
+                        .serialize(&client_state)
+                        .expect("World state should be serializable.");
+
+                    // The initial state already contains the changes of this command frame.
+                    self.acknowledged.insert(*id, previous_command_frame);
+                    self.accumulators.remove(id);
+
+                    // A resyncing client continues its sequence, such that the updates it still holds are dropped.
+                    let state_update_sequence = self.state_update_sequence;
+                    let sent = self.sent_updates.entry(*id).or_insert(SentUpdate {
+                        sequence: state_update_sequence,
+                        command_ack: None,
+                        command_frame: previous_command_frame,
+                    });
+                    sent.command_frame = previous_command_frame;
+
+                    // The client checks the codec before it decodes the initial state.
+                    outgoing.push((
+                        *id,
+                        transport::ServerToClientMessage::Message(ServerMessage::Welcome {
+                            codec,
+                            state_update_sequence: sent.sequence,
+                            session: sessions.token(*id).expect("Session should be open."),
+                            resources: initial_resources.clone(),
+                        }),
+                    ));
+                    outgoing.push((
+                        *id,
+                        transport::ServerToClientMessage::InitialStateSync(bytes),
+                    ));
+                    self.welcomed.insert(*id);
+                }
+            }
+
+            // Weight of a change when the bandwidth is limited.
+            let change_weight = |entity_id: Uid, component_id: Uid| {
+                let component_priority = components
+                    .by_uid()
+                    .get(&component_id)
+                    .map_or(1., |x| x.replication().priority);
+
+                let entity_priority = world
+                    .entry_ref(*allocator.get_by_val(&entity_id))
+                    .and_then(|entry| {
+                        entry
+                            .get_component::<PriorityComponent>()
+                            .ok()
+                            .map(|x| x.priority())
+                    })
+                    .unwrap_or(1.);
+
+                component_priority * entity_priority
+            };
+
+            let is_predicted = |component_id: Uid| {
+                components.by_uid().get(&component_id).map_or(false, |x| {
+                    x.replication().simulation == Simulation::Predicted
+                })
+            };
+
+            // Compares the state a client knows of an unreliable component with its current state.
+            let unreliable_difference = |entity_id: Uid, component_id: Uid, baseline: &[u8]| {
+                let registered_component = components.by_uid().get(&component_id).cloned()?;
+                let entity = allocator.get_by_val(&entity_id);
+
+                difference_with_current(registered_component, world, *entity, baseline, codec)
+                    .map(|buffer| ComponentData::new(component_id, buffer))
+            };
+
+            let budget = self
+                .config
+                .bandwidth_budget
+                .map(|x| x.bytes_per_tick(command_ticker.default_simulation_speed() as f32));
 
-            // First do an state update to each new client.
-            let new_clients = postoffice
+            // Sent state update to all other clients.
+            // The entity events of this command frame are delivered after its state update.
+            let served_clients = postoffice
                 .clients()
-                .filter(|x| x.1.connected_at() > last_tick)
-                .count();
-
-            if new_clients != 0 {
-                let new_clients = postoffice
-                    .clients_mut()
-                    .filter(|x| x.1.connected_at() > last_tick);
-
-                let bytes = bincode::serialize(
-                    &self
-                        .world
-                        .world
-                        .as_serializable(any(), components.legion_registry()),
-                )
-                .unwrap();
+                .map(|x| *x.0)
+                .filter(|id| self.welcomed.contains(id))
+                .collect::<Vec<ClientId>>();
+            let interest = &mut self.interest;
 
-                if bytes.len() != 0 {
-                    let universe = resources.get_mut::<Universe>().unwrap();
+            send_entity_events(
+                resources,
+                &served_clients,
+                |client, entity_id| {
+                    interest
+                        .as_ref()
+                        .map_or(true, |interest| interest.is_visible(client, entity_id))
+                },
+                &self.replicated,
+                &allocator,
+                previous_command_frame,
+                codec,
+                &mut outgoing,
+            );
 
-                    let registry = components.legion_registry();
+            let update_clients = served_clients
+                .iter()
+                .filter(|id| !new_clients.contains(id))
+                .cloned()
+                .collect::<Vec<ClientId>>();
 
-                    for (_id, client) in new_clients {
-                        client.postbox_mut().send(
-                            transport::ServerToClientMessage::InitialStateSync(bytes.clone()),
-                        )
+            for id in update_clients.iter() {
+                let mut client_state = match interest.as_mut() {
+                    Some(interest) => {
+                        let mut client_state =
+                            interest.state_update(*id, &world_state, |entity_id| {
+                                let entity = *allocator.get_by_val(&entity_id);
+                                serialize_entity(world, entity, &components, codec)
+                            });
+                        order_parents_first(world, &allocator, &recycler, &mut client_state);
+                        state_for_client(&client_state, *id)
                     }
+                    None => state_for_client(&world_state, *id),
+                };
+                transfer_owner_only(&mut client_state, *id, &transfers, |entity_id| {
+                    interest
+                        .as_ref()
+                        .map_or(true, |interest| interest.is_visible(*id, entity_id))
+                });
+                // Tells the client how far ahead its commands arrive, such that it keeps a cushion in the jitter buffer.
+                client_state.command_frame_offset = command_buffer.offset(*id).unwrap_or(0);
+
+                match budget {
+                    Some(budget) => limit_bandwidth(
+                        &mut client_state,
+                        self.accumulators
+                            .entry(*id)
+                            .or_insert_with(PriorityAccumulator::new),
+                        budget,
+                        &change_weight,
+                        &is_predicted,
+                        &baselines,
+                        &unreliable_difference,
+                        metrics.client_mut(*id),
+                    ),
+                    None => measure_bandwidth(&client_state, metrics.client_mut(*id)),
+                }
+
+                let state_update_sequence = self.state_update_sequence;
+                let sent = self.sent_updates.entry(*id).or_insert(SentUpdate {
+                    sequence: state_update_sequence,
+                    command_ack: None,
+                    command_frame: previous_command_frame,
+                });
+                let command_ack = command_buffer.acknowledged(*id);
+                let has_entity_events = outgoing.iter().any(|(to, message)| {
+                    to == id
+                        && matches!(
+                            message,
+                            transport::ServerToClientMessage::Message(
+                                ServerMessage::EntityEvent { .. }
+                            )
+                        )
+                });
+
+                // Updates without changes are only sent for a new command ack, for entity events waiting on them,
+                // or to keep the client clock in sync.
+                if is_empty_state(&client_state)
+                    && resource_differences.is_empty()
+                    && !has_entity_events
+                    && command_ack == sent.command_ack
+                    && previous_command_frame.saturating_sub(sent.command_frame) < KEEPALIVE_FRAMES
+                {
+                    continue;
                 }
+
+                sent.sequence = sent.sequence.wrapping_add(1);
+                sent.command_ack = command_ack;
+                sent.command_frame = previous_command_frame;
+
+                outgoing.push((
+                    *id,
+                    transport::ServerToClientMessage::Message(ServerMessage::StateUpdate {
+                        sequence: sent.sequence,
+                        command_ack,
+                        state: codec
+                            .serialize(&client_state)
+                            .expect("World state should be serializable."),
+                        resources: resource_differences.clone(),
+                    }),
+                ));
             }
 
-            // Sent state update to all clients.
-            if !world_state.is_empty() {
-                // Then broadcast the world state to all clients.
-                postoffice.broadcast(transport::ServerToClientMessage::StateUpdate(world_state));
+            // Typed messages follow the welcome of a new client.
+            send_typed_messages(resources, &served_clients, codec, &mut outgoing);
+
+            if let Some(recorder) = &mut self.recorder {
+                record_outgoing(recorder, &outgoing, &self.sent_updates);
+            }
+
+            for (id, message) in outgoing {
+                if let Some((_, client)) = postoffice.clients_mut().find(|x| *x.0 == id) {
+                    client.postbox_mut().send(message);
+                }
             }
 
-            self.last_tick = Instant::now();
+            // Forget the pending changes, sequences and metrics of clients that are gone.
+            self.accumulators
+                .retain(|id, _| postoffice.clients().any(|x| x.0 == id));
+            self.sent_updates
+                .retain(|id, _| postoffice.clients().any(|x| x.0 == id));
+            self.welcomed
+                .retain(|id| postoffice.clients().any(|x| x.0 == id));
+            metrics.retain_clients(|id| postoffice.clients().any(|x| x.0 == id));
         }
     }
 
-    pub fn resources(&self) -> &Resources {
-        &self.resources
+    /// Calls the hook for every new client, a rejected client is not served.
+    pub fn on_connect(
+        &mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) -> ConnectDecision
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.hooks.on_connect.push(Box::new(hook));
     }
 
-    pub fn resources_mut(&mut self) -> &mut Resources {
-        &mut self.resources
+    /// Calls the hook for every client whose connection dropped, it may still resume its session.
+    pub fn on_disconnect(
+        &mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) + Send + Sync + 'static,
+    ) {
+        self.hooks.on_disconnect.push(Box::new(hook));
     }
-}
 
-// Handle the events from above merge operation.
-fn handle_world_events(
-    world: &World,
-    allocator: &mut UidAllocator<Entity>,
-    components: &RegisteredComponentsResource,
-    event_resource: &EventResource,
-    world_state: &mut WorldState,
-) {
-    let mut event_handler = LegionEventHandler::new();
+    /// Calls the hook for every disconnected client that did not resume its session within the grace period,
+    /// before the entities it owns are removed.
+    pub fn on_timeout(
+        &mut self,
+        hook: impl FnMut(ClientId, &mut World, &mut Resources) + Send + Sync + 'static,
+    ) {
+        self.hooks.on_timeout.push(Box::new(hook));
+    }
 
-    let events = event_handler.handle(&event_resource.legion_receiver(), world, &components);
+    /// Sends each client only the replicated entities the filter returns true for, and their children along with them.
+    ///
+    /// From the next command frame on, the clients are sent the entities that came into their interest
+    /// and the clients remove the entities that went out of it.
+    pub fn set_interest(
+        &mut self,
+        filter: impl Fn(ClientId, Entity, &World) -> bool + Send + Sync + 'static,
+    ) {
+        self.interest = Some(InterestSets::new(Box::new(filter)));
+    }
 
-    for legion_event in events {
-        match legion_event {
-            LegionEvent::ComponentAdded(entity, _component_count) => {
-                let identifier = allocator.get(&entity);
-                world_state.add_component(identifier, ComponentData::new(0, vec![]))
-            }
-            LegionEvent::ComponentRemoved(entity, _component_count) => {
-                let identifier = allocator.get(&entity);
-                world_state.remove_component(identifier, 0);
+    // Compare the clients of the post office with the previous tick and call the hooks for the changes.
+    //
+    // The transport also reports connections as network events, but that queue belongs to the user code that drains it,
+    // while the clients of the post office are the connections the server world serves.
+    // A new client is only handed to the connect hooks after its hello, once it is known whether it resumes a session.
+    fn handle_connections(&mut self) {
+        for (client_id, reason) in std::mem::take(&mut self.pending_disconnects) {
+            self.disconnect(client_id, reason);
+        }
+
+        let now = Instant::now();
+
+        let (disconnected, expired, hellos) = {
+            let mut postoffice = self
+                .resources
+                .get_mut::<ServerPostOffice<
+                    ServerToClientMessage,
+                    ClientToServerMessage,
+                    ClientToServerCommand,
+                >>()
+                .unwrap();
+            let mut sessions = self.resources.get_mut::<Sessions>().unwrap();
+
+            let clients = postoffice
+                .clients()
+                .map(|x| *x.0)
+                .collect::<HashSet<ClientId>>();
+            let connected = clients
+                .difference(&self.known_clients)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let disconnected = self
+                .known_clients
+                .difference(&clients)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            sessions.close_gone(|id| clients.contains(id), now);
+            let expired = sessions.expire(self.config.session_grace_period, now);
+
+            // A hello can arrive in the same tick as its connection.
+            let received = receive_hellos(&mut postoffice, &self.dropped);
+            let hellos = handshake(&mut self.handshakes, connected, received);
+
+            self.known_clients = clients;
+            (disconnected, expired, hellos)
+        };
+
+        let world = &mut self.world.world;
+        let resources = &mut self.resources;
+        let mut events = Vec::new();
+
+        for client_id in disconnected {
+            self.welcome.retain(|id| *id != client_id);
+            self.welcomed.remove(&client_id);
+
+            // The hooks never heard of a client that left before its hello.
+            if self.dropped.remove(&client_id) || self.handshakes.contains(&client_id) {
+                self.handshakes.retain(|id| *id != client_id);
+                continue;
             }
-            LegionEvent::EntityRemoved(entity) => {
-                let identifier = allocator.get(&entity);
-                world_state.remove_entity(identifier);
 
-                // TODO?
-                // let identifier = allocator
-                //     .deallocate(to_remove)
-                //     .expect("Entity should be allocated.");
+            self.hooks.disconnect(client_id, world, resources);
+            events.push(SyncEvent::ClientDisconnected { client_id });
+        }
+
+        for client_id in expired {
+            self.hooks.timeout(client_id, world, resources);
+            remove_owned_entities(world, &self.replicated, client_id);
+            events.push(SyncEvent::SessionExpired { client_id });
+        }
+
+        let (hellos, banned) = refuse_banned(hellos, &resources.get::<BanList>().unwrap());
+
+        for client_id in banned {
+            log::info!("Client {} resumes a banned session.", client_id);
+            self.dropped.insert(client_id);
+            send_disconnect::<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>(
+                resources,
+                client_id,
+                DisconnectReason::Banned(String::from("The session is banned.")),
+            );
+        }
+
+        for (client_id, session) in hellos {
+            if let Some(token) = session {
+                let resumed = resources
+                    .get_mut::<Sessions>()
+                    .unwrap()
+                    .resume(client_id, token);
+
+                // A resumed client takes over the session and entities of its previous connection.
+                if let Some(previous_client_id) = resumed {
+                    transfer_owned_entities(world, &self.replicated, previous_client_id, client_id);
+                    self.welcome.push(client_id);
+                    events.push(SyncEvent::SessionResumed {
+                        client_id,
+                        previous_client_id,
+                    });
+                    continue;
+                }
+
+                log::warn!(
+                    "Client {} cannot resume an unknown, open or expired session, it continues with a new session.",
+                    client_id
+                );
             }
-            LegionEvent::EntityInserted(entity, _component_count) => {
-                let identifier = allocator.get(&entity);
-
-                let mut entity_components = Vec::new();
-
-                for component in components.slice_with_uid().iter() {
-                    component
-                        .1
-                        .serialize_if_exists_in_world(&world, entity, &mut |serialize| {
-                            let mut buffer = Vec::new();
-                            let serializer = &mut bincode::Serializer::new(
-                                &mut buffer,
-                                bincode::DefaultOptions::new()
-                                    .with_fixint_encoding()
-                                    .allow_trailing_bytes(),
-                            );
-
-                            if let Ok(_) = erased_serde::serialize(&serialize, serializer) {
-                                entity_components.push(ComponentData::new(component.0, buffer));
-                            }
-                        });
+
+            match self.hooks.connect(client_id, world, resources) {
+                ConnectDecision::Accept => {
+                    resources.get_mut::<Sessions>().unwrap().open(client_id);
+                    self.welcome.push(client_id);
+                    events.push(SyncEvent::ClientConnected { client_id });
+                }
+                ConnectDecision::Reject(reason) => {
+                    log::info!("Rejected client {}: {}", client_id, reason);
+                    self.dropped.insert(client_id);
+                    send_disconnect::<
+                        ServerToClientMessage,
+                        ClientToServerMessage,
+                        ClientToServerCommand,
+                    >(resources, client_id, DisconnectReason::Rejected(reason));
                 }
+            }
+        }
+
+        let mut sync_events = resources.get_mut::<SyncEventQueue>().unwrap();
+        for event in events {
+            sync_events.push(event);
+        }
+    }
+
+    /// Stops serving the client, which receives the reason as the last message.
+    ///
+    /// The session of the client ends without a grace period,
+    /// the disconnect hooks are called and the entities the client owns are removed.
+    pub fn disconnect(&mut self, client_id: ClientId, reason: DisconnectReason) {
+        if !self.known_clients.contains(&client_id) || !self.dropped.insert(client_id) {
+            return;
+        }
+
+        log::info!("Disconnecting client {}: {}", client_id, reason);
+        send_disconnect::<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>(
+            &self.resources,
+            client_id,
+            reason,
+        );
+
+        // The hooks never heard of a client that is kicked before its hello.
+        if self.handshakes.contains(&client_id) {
+            self.handshakes.retain(|id| *id != client_id);
+            return;
+        }
 
-                world_state.insert_entity(identifier, entity_components);
+        self.welcome.retain(|id| *id != client_id);
+        self.welcomed.remove(&client_id);
+        self.acknowledged.remove(&client_id);
+        self.resources.get_mut::<Sessions>().unwrap().end(client_id);
+
+        self.hooks
+            .disconnect(client_id, &mut self.world.world, &mut self.resources);
+        remove_owned_entities(&mut self.world.world, &self.replicated, client_id);
+
+        self.resources
+            .get_mut::<SyncEventQueue>()
+            .unwrap()
+            .push(SyncEvent::ClientDisconnected { client_id });
+    }
+
+    /// Bans the session of the client and disconnects it.
+    ///
+    /// A client that resumes the banned session is disconnected with its hello.
+    pub fn ban(&mut self, client_id: ClientId, reason: String) {
+        let token = self
+            .resources
+            .get::<Sessions>()
+            .and_then(|sessions| sessions.token(client_id));
+
+        if let Some(token) = token {
+            self.resources.get_mut::<BanList>().unwrap().ban(token);
+        }
+
+        self.disconnect(client_id, DisconnectReason::Banned(reason));
+    }
+
+    /// Disconnects all clients with the reason, sends the pending messages and closes the connections.
+    pub fn shutdown(mut self, reason: String) {
+        let clients = self.known_clients.iter().cloned().collect::<Vec<_>>();
+
+        for client_id in clients {
+            self.disconnect(client_id, DisconnectReason::Shutdown(reason.clone()));
+        }
+
+        if let Some(flush) = &mut self.flush {
+            flush.execute(&mut self.world.world, &mut self.resources);
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.flush() {
+                log::error!("Failed to flush the recording: {}", e);
             }
         }
+
+        // Dropping the resources closes the connections with the listener.
+    }
+
+    /// Returns the smoothed round-trip time of the given client, `None` until it has been measured.
+    pub fn round_trip_time(&self, client_id: ClientId) -> Option<Duration> {
+        self.resources
+            .get::<ServerPostOffice<ServerToClientMessage, ClientToServerMessage, ClientToServerCommand>>()
+            .and_then(|postoffice| postoffice.round_trip_time(client_id))
+    }
+
+    /// Hands a view of an earlier command frame from the lag compensation history to `view`.
+    ///
+    /// Returns `None` when lag compensation is not configured or the frame is no longer in the history.
+    pub fn rewind<R>(
+        &self,
+        command_frame: CommandFrame,
+        view: impl FnOnce(&HistoryView<'_>) -> R,
+    ) -> Option<R> {
+        let lag_compensation = self.lag_compensation.as_ref()?;
+        let components = self.resources.get::<RegisteredComponentsResource>()?;
+
+        lag_compensation.rewind(command_frame, &components, view)
+    }
+
+    /// Returns the command frame of the world the client saw when it sent the commands that are processed now.
+    ///
+    /// This is the latest state update the client acknowledged, before the first acknowledgement it is estimated from the round-trip time,
+    /// minus the [interpolation delay](LagCompensationConfig::interpolation_delay) of the clients.
+    pub fn client_view_frame(&self, client_id: ClientId) -> Option<CommandFrame> {
+        let interpolation_delay = self
+            .lag_compensation
+            .as_ref()
+            .map_or(0, |x| x.config().interpolation_delay);
+
+        if let Some(command_frame) = self.acknowledged.get(&client_id) {
+            return Some(command_frame.saturating_sub(interpolation_delay));
+        }
+
+        let rtt = self.round_trip_time(client_id)?;
+        let command_ticker = self.resources.get::<CommandFrameTicker>()?;
+        let frames = (rtt.as_secs_f32() * command_ticker.default_simulation_speed() as f32).ceil();
+
+        Some(
+            command_ticker
+                .command_frame()
+                .saturating_sub(frames as CommandFrame)
+                .saturating_sub(interpolation_delay),
+        )
+    }
+
+    /// Captures the entities with a network id, their network ids and the current command frame.
+    pub fn snapshot(&self) -> WorldSnapshot {
+        let allocator = self.resources.get::<UidAllocator<Entity>>().unwrap();
+        let recycler = self.resources.get::<UidRecycler>().unwrap();
+        let components = self
+            .resources
+            .get::<RegisteredComponentsResource>()
+            .unwrap();
+        let command_ticker = self.resources.get::<CommandFrameTicker>().unwrap();
+
+        WorldSnapshot::capture(
+            &self.world.world,
+            self.replicated.iter().chain(self.held.iter()),
+            &allocator,
+            &recycler,
+            &components,
+            command_ticker.command_frame(),
+            self.state_update_sequence,
+        )
+    }
+
+    /// Writes a [snapshot](ServerWorld::snapshot) to the file at the given path,
+    /// a restarted server restores it with [from_snapshot](ServerWorldBuilder::from_snapshot).
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        self.snapshot().save(path)
+    }
+
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn resources_mut(&mut self) -> &mut Resources {
+        &mut self.resources
     }
 }
 
-fn add_differences_to_state(
-    components: &RegisteredComponentsResource,
-    world_state: &mut WorldState,
-    modification_buffer: &mut ModifiedComponentsBuffer,
-    world: &World,
-    allocator: &UidAllocator<Entity>,
-) {
-    let entries = modification_buffer.drain_entries();
-
-    for entry in entries {
-        for ((entity_id, component_type), unchanged) in entry.1 {
-            let component_id = components.get_uid(&component_type).expect("Should exist");
-            let entity = allocator.get_by_val(&entity_id);
-
-            let components = components.by_type_id();
-            let registered_component = components.get(&component_type).expect("Should exist");
-
-            let mut buffer = Vec::new();
-            let serializer = &mut bincode::Serializer::new(
-                &mut buffer,
-                bincode::DefaultOptions::new()
-                    .with_fixint_encoding()
-                    .allow_trailing_bytes(),
-            );
+// Drain the messages from the clients that are handled by the server world.
+fn drain_client_messages<
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    postoffice: &mut ServerPostOffice<
+        ServerToClientMessage,
+        ClientToServerMessage,
+        ClientToServerCommand,
+    >,
+    welcomed: &HashSet<ClientId>,
+) -> Vec<(
+    ClientId,
+    ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+)> {
+    let mut messages = Vec::new();
 
-            let unchanged = &mut bincode::Deserializer::from_slice(
-                &unchanged,
-                bincode::DefaultOptions::new()
-                    .with_fixint_encoding()
-                    .allow_trailing_bytes(),
-            );
+    for (id, client) in postoffice.clients_mut() {
+        // Everything a client sends before its welcome or after it is no longer served is dropped,
+        // its hello was already taken by the handshake.
+        if !welcomed.contains(id) {
+            client.postbox_mut().drain_inbox(|_| true);
+            continue;
+        }
 
-            let is_different = registered_component
-                .serialize_difference_with_current(
-                    world,
-                    *entity,
-                    &mut erased_serde::Deserializer::erase(unchanged),
-                    &mut erased_serde::Serializer::erase(serializer),
-                )
-                .unwrap();
+        let drained = client.postbox_mut().drain_inbox(|m| match m {
+            transport::ClientToServerMessage::Command(_, _) => true,
+            transport::ClientToServerMessage::Message(ClientMessage::StateAck(_)) => true,
+            transport::ClientToServerMessage::Message(ClientMessage::RequestSync) => true,
+            transport::ClientToServerMessage::Message(ClientMessage::CommandFrames(_)) => true,
+            transport::ClientToServerMessage::Message(ClientMessage::Disconnect(_)) => true,
+            transport::ClientToServerMessage::Message(ClientMessage::Typed { .. }) => true,
+            _ => false,
+        });
 
-            if is_different {
-                world_state.change(entity_id, ComponentData::new(*component_id, buffer));
-            }
+        messages.extend(drained.into_iter().map(|message| (*id, message)));
+    }
+
+    messages
+}
+
+// Clients that leave on their own are disconnected at the next tick.
+fn receive_disconnects<
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    messages: &[(
+        ClientId,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    )],
+    pending_disconnects: &mut Vec<(ClientId, DisconnectReason)>,
+) {
+    for (id, message) in messages {
+        if let transport::ClientToServerMessage::Message(ClientMessage::Disconnect(reason)) =
+            message
+        {
+            log::info!("Client {} leaves: {}", id, reason);
+            pending_disconnects.push((*id, reason.clone()));
         }
     }
 }
+
+// Collect the state acknowledgements the clients have sent since the last tick.
+fn collect_acknowledgements<
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    acknowledged: &mut HashMap<ClientId, CommandFrame>,
+    messages: &[(
+        ClientId,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    )],
+    postoffice: &ServerPostOffice<
+        ServerToClientMessage,
+        ClientToServerMessage,
+        ClientToServerCommand,
+    >,
+) {
+    for (id, message) in messages {
+        if let transport::ClientToServerMessage::Message(ClientMessage::StateAck(frame)) = message {
+            let latest = acknowledged.entry(*id).or_insert(*frame);
+            *latest = (*latest).max(*frame);
+        }
+    }
+
+    // Clients that are gone no longer hold back the reuse of network ids.
+    acknowledged.retain(|id, _| postoffice.clients().any(|x| x.0 == id));
+}
+
+// Take the hellos the clients have sent, with the session they want to resume.
+fn receive_hellos<
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    postoffice: &mut ServerPostOffice<
+        ServerToClientMessage,
+        ClientToServerMessage,
+        ClientToServerCommand,
+    >,
+    dropped: &HashSet<ClientId>,
+) -> Vec<(ClientId, Option<SessionToken>)> {
+    let mut hellos = Vec::new();
+
+    for (id, client) in postoffice.clients_mut() {
+        if dropped.contains(id) {
+            continue;
+        }
+
+        let drained = client.postbox_mut().drain_inbox(|m| match m {
+            transport::ClientToServerMessage::Message(ClientMessage::Hello { .. }) => true,
+            _ => false,
+        });
+
+        for message in drained {
+            if let transport::ClientToServerMessage::Message(ClientMessage::Hello { session }) =
+                message
+            {
+                hellos.push((*id, session));
+            }
+        }
+    }
+
+    hellos
+}
+
+// Start the handshakes of the new connections and complete the handshakes whose hello arrived,
+// a client that already completed its handshake cannot start over with another hello.
+fn handshake(
+    handshakes: &mut Vec<ClientId>,
+    connected: Vec<ClientId>,
+    hellos: Vec<(ClientId, Option<SessionToken>)>,
+) -> Vec<(ClientId, Option<SessionToken>)> {
+    handshakes.extend(connected);
+
+    let mut completed = Vec::new();
+
+    for (id, session) in hellos {
+        if !handshakes.contains(&id) {
+            log::warn!("Client {} sent a second hello, it is ignored.", id);
+            continue;
+        }
+
+        handshakes.retain(|x| *x != id);
+        completed.push((id, session));
+    }
+
+    completed
+}
+
+// Split off the clients whose hello resumes a banned session.
+fn refuse_banned(
+    hellos: Vec<(ClientId, Option<SessionToken>)>,
+    bans: &BanList,
+) -> (Vec<(ClientId, Option<SessionToken>)>, Vec<ClientId>) {
+    let (banned, hellos): (Vec<_>, Vec<_>) = hellos
+        .into_iter()
+        .partition(|(_, session)| session.map_or(false, |token| bans.is_banned(token)));
+
+    (hellos, banned.into_iter().map(|(id, _)| id).collect())
+}
+
+// Give the entities the previous connection of a resumed client owned to its new connection.
+//
+// The other clients see the new owner once they resync, the owner is only used to filter their state.
+fn transfer_owned_entities(
+    world: &mut World,
+    replicated: &HashSet<Entity>,
+    previous_client_id: ClientId,
+    client_id: ClientId,
+) {
+    for entity in replicated.iter() {
+        if let Some(mut entry) = world.entry(*entity) {
+            if let Ok(owner) = entry.get_component_mut::<OwnerComponent>() {
+                if owner.client_id() == previous_client_id {
+                    *owner = OwnerComponent::new(client_id);
+                }
+            }
+        }
+    }
+}
+
+// Put the typed messages of the clients in the inboxes of their message types.
+fn receive_typed_messages<
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    resources: &Resources,
+    messages: &[(
+        ClientId,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    )],
+    codec: WireCodec,
+) {
+    let registrations = resources.get::<RegisteredMessagesResource>().unwrap();
+
+    for (id, message) in messages {
+        if let transport::ClientToServerMessage::Message(ClientMessage::Typed { kind, data }) =
+            message
+        {
+            match registrations.get(*kind) {
+                Some(registration) => {
+                    if let Err(e) = codec.deserialize_erased(data, |deserializer| {
+                        registration.receive(resources, Some(*id), deserializer)
+                    }) {
+                        log::error!(
+                            "Cannot deserialize message {} from client {}: {}",
+                            registration.type_name(),
+                            id,
+                            e
+                        );
+                    }
+                }
+                None => log::warn!("Client {} sent a message of unknown kind {}.", id, kind),
+            }
+        }
+    }
+}
+
+// Serialize the differences of the replicated resources since the previous command frame.
+fn resource_differences(
+    resources: &Resources,
+    previous_states: &mut HashMap<MessageKind, Vec<u8>>,
+    codec: WireCodec,
+) -> Vec<ResourceState> {
+    let mut differences = Vec::new();
+
+    for registration in ResourceRegister.iter() {
+        let kind = registration.kind();
+        let mut current = None;
+
+        registration.serialize_if_exists(resources, &mut |resource| {
+            let (result, data) = TRACKER_CODEC
+                .serialize_erased(|serializer| erased_serde::serialize(resource, serializer));
+
+            match result {
+                Ok(_) => current = Some(data),
+                Err(e) => log::error!(
+                    "Cannot serialize resource {}: {}",
+                    registration.type_name(),
+                    e
+                ),
+            }
+        });
+
+        let current = match current {
+            Some(current) => current,
+            None => continue,
+        };
+
+        // The first state is the starting point, the clients receive it with their welcome.
+        let unchanged = match previous_states.insert(kind, current.clone()) {
+            Some(unchanged) if unchanged != current => unchanged,
+            _ => continue,
+        };
+
+        let (difference, data) = TRACKER_CODEC.deserialize_erased(&unchanged, |unchanged| {
+            TRACKER_CODEC.deserialize_erased(&current, |changed| {
+                codec.serialize_erased(|serializer| {
+                    registration.serialize_difference(unchanged, changed, serializer)
+                })
+            })
+        });
+
+        match difference {
+            Ok(true) => differences.push(ResourceState { kind, data }),
+            Ok(false) => {}
+            Err(e) => log::error!(
+                "Cannot serialize the difference of resource {}: {:?}",
+                registration.type_name(),
+                e
+            ),
+        }
+    }
+
+    differences
+}
+
+// Serialize the data of the replicated resources for a new client.
+fn resource_states(resources: &Resources, codec: WireCodec) -> Vec<ResourceState> {
+    let mut states = Vec::new();
+
+    for registration in ResourceRegister.iter() {
+        registration.serialize_if_exists(resources, &mut |resource| {
+            let (result, data) =
+                codec.serialize_erased(|serializer| erased_serde::serialize(resource, serializer));
+
+            match result {
+                Ok(_) => states.push(ResourceState {
+                    kind: registration.kind(),
+                    data,
+                }),
+                Err(e) => log::error!(
+                    "Cannot serialize resource {}: {}",
+                    registration.type_name(),
+                    e
+                ),
+            }
+        });
+    }
+
+    states
+}
+
+// Empty the outboxes of the message types and address their messages to the targeted clients.
+fn send_typed_messages<ServerToClientMessage: NetworkMessage>(
+    resources: &Resources,
+    clients: &[ClientId],
+    codec: WireCodec,
+    outgoing: &mut Vec<(ClientId, ServerToClient<ServerToClientMessage>)>,
+) {
+    let registrations = resources.get::<RegisteredMessagesResource>().unwrap();
+
+    for (kind, registration) in registrations.iter() {
+        registration.drain_outbox(resources, &mut |target, message| {
+            let (result, data) =
+                codec.serialize_erased(|serializer| erased_serde::serialize(message, serializer));
+
+            if let Err(e) = result {
+                log::error!("Cannot send message {}: {}", registration.type_name(), e);
+                return;
+            }
+
+            for id in clients.iter().filter(|id| target.includes(**id)) {
+                outgoing.push((
+                    *id,
+                    transport::ServerToClientMessage::Message(ServerMessage::Typed {
+                        kind,
+                        data: data.clone(),
+                    }),
+                ));
+            }
+        });
+    }
+}
+
+// Empty the outboxes of the entity event types and address their events to the targeted clients that are sent the entity,
+// the events are delivered after the state update of the given command frame.
+fn send_entity_events<ServerToClientMessage: NetworkMessage>(
+    resources: &Resources,
+    clients: &[ClientId],
+    is_visible: impl Fn(ClientId, Uid) -> bool,
+    replicated: &HashSet<Entity>,
+    allocator: &UidAllocator<Entity>,
+    command_frame: CommandFrame,
+    codec: WireCodec,
+    outgoing: &mut Vec<(ClientId, ServerToClient<ServerToClientMessage>)>,
+) {
+    let registrations = resources.get::<RegisteredEntityEventsResource>().unwrap();
+
+    for (kind, registration) in registrations.iter() {
+        registration.drain_outbox(resources, &mut |entity, target, event| {
+            // The entity may have been removed in the same command frame.
+            if !replicated.contains(&entity) {
+                log::warn!(
+                    "Dropping event {} for entity {:?} without a network id.",
+                    registration.type_name(),
+                    entity
+                );
+                return;
+            }
+
+            let (result, data) =
+                codec.serialize_erased(|serializer| erased_serde::serialize(event, serializer));
+
+            if let Err(e) = result {
+                log::error!("Cannot send event {}: {}", registration.type_name(), e);
+                return;
+            }
+
+            let entity_id = allocator.get(&entity);
+
+            for id in clients
+                .iter()
+                .filter(|id| target.includes(**id) && is_visible(**id, entity_id))
+            {
+                outgoing.push((
+                    *id,
+                    transport::ServerToClientMessage::Message(ServerMessage::EntityEvent {
+                        kind,
+                        entity_id,
+                        command_frame,
+                        data: data.clone(),
+                    }),
+                ));
+            }
+        });
+    }
+}
+
+// Send the last message to a client that is no longer served.
+fn send_disconnect<
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand,
+>(
+    resources: &Resources,
+    client_id: ClientId,
+    reason: DisconnectReason,
+) {
+    let mut postoffice = resources
+        .get_mut::<ServerPostOffice<
+            ServerToClientMessage,
+            ClientToServerMessage,
+            ClientToServerCommand,
+        >>()
+        .unwrap();
+
+    postoffice.disconnect(client_id, reason);
+}
+
+// Remove the entities owned by the client whose session ended.
+fn remove_owned_entities(world: &mut World, replicated: &HashSet<Entity>, client_id: ClientId) {
+    let owned = replicated
+        .iter()
+        .filter(|entity| {
+            world
+                .entry_ref(**entity)
+                .and_then(|entry| entry.get_component::<OwnerComponent>().ok().cloned())
+                .map_or(false, |owner| owner.client_id() == client_id)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for entity in owned {
+        world.remove(entity);
+    }
+}
+
+// Move the commands of the clients into the jitter buffer and make the commands for the new command frame current.
+fn buffer_commands<
+    ServerToClientMessage: NetworkMessage,
+    ClientToServerMessage: NetworkMessage,
+    ClientToServerCommand: NetworkCommand + PartialEq,
+>(
+    command_buffer: &mut ServerCommandBuffer<ClientToServerCommand>,
+    messages: Vec<(
+        ClientId,
+        ClientToServer<ClientToServerMessage, ClientToServerCommand>,
+    )>,
+    postoffice: &ServerPostOffice<
+        ServerToClientMessage,
+        ClientToServerMessage,
+        ClientToServerCommand,
+    >,
+    command_frame: CommandFrame,
+) {
+    let mut sent_frames = Vec::new();
+
+    for (id, message) in messages {
+        match message {
+            transport::ClientToServerMessage::Command(frame, command) => {
+                command_buffer.insert(id, frame, command);
+            }
+            transport::ClientToServerMessage::Message(ClientMessage::CommandFrames(frames)) => {
+                sent_frames.push((id, frames));
+            }
+            _ => {}
+        }
+    }
+
+    // The commands of a tick may arrive after the list of their frames, they are acknowledged at a later tick.
+    for (id, frames) in sent_frames {
+        command_buffer.acknowledge_sent(id, &frames);
+    }
+
+    command_buffer.retain_clients(|id| postoffice.clients().any(|x| x.0 == id));
+    command_buffer.advance(command_frame);
+}
+
+// Record the welcomes, initial state syncs, state updates and entity events that are about to be sent.
+fn record_outgoing<ServerToClientMessage: NetworkMessage>(
+    recorder: &mut SessionRecorder,
+    outgoing: &[(ClientId, ServerToClient<ServerToClientMessage>)],
+    sent_updates: &HashMap<ClientId, SentUpdate>,
+) {
+    for (id, message) in outgoing {
+        let result = match message {
+            transport::ServerToClientMessage::Message(ServerMessage::Welcome {
+                state_update_sequence,
+                resources,
+                ..
+            }) => recorder.record_welcome(Some(*id), *state_update_sequence, resources),
+            transport::ServerToClientMessage::InitialStateSync(state) => {
+                let sequence = sent_updates.get(id).map_or(0, |x| x.sequence);
+                recorder.record_initial_state_sync(Some(*id), sequence, state)
+            }
+            transport::ServerToClientMessage::Message(ServerMessage::StateUpdate {
+                sequence,
+                state,
+                resources,
+                ..
+            }) => recorder.record_state_update(Some(*id), *sequence, state, resources),
+            transport::ServerToClientMessage::Message(ServerMessage::EntityEvent {
+                kind,
+                entity_id,
+                command_frame,
+                data,
+            }) => recorder.record_entity_event(Some(*id), *kind, *entity_id, *command_frame, data),
+            _ => Ok(()),
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to record the message for client {}: {}", id, e);
+        }
+    }
+}
+
+// Returns whether the state has no entity or component changes.
+fn is_empty_state(state: &WorldState) -> bool {
+    state.inserted.is_empty()
+        && state.removed.is_empty()
+        && state.changed.is_empty()
+        && state.component_added.is_empty()
+        && state.component_removed.is_empty()
+}
+
+// Handle the events from above merge operation.
+fn handle_world_events(
+    world: &World,
+    allocator: &mut UidAllocator<Entity>,
+    recycler: &mut UidRecycler,
+    replicated: &mut HashSet<Entity>,
+    held: &mut HashSet<Entity>,
+    components: &RegisteredComponentsResource,
+    event_resource: &EventResource,
+    world_state: &mut WorldState,
+    codec: WireCodec,
+) {
+    let mut event_handler = LegionEventHandler::new();
+
+    let events = event_handler.handle(&event_resource.legion_receiver(), world, &components);
+
+    for legion_event in events {
+        match legion_event {
+            LegionEvent::ComponentAdded(entity, _component_count) => {
+                if replicated.contains(&entity) {
+                    let identifier = allocator.get(&entity);
+                    world_state.add_component(identifier, ComponentData::new(0, vec![]))
+                }
+            }
+            LegionEvent::ComponentRemoved(entity, _component_count) => {
+                if replicated.contains(&entity) {
+                    let identifier = allocator.get(&entity);
+                    world_state.remove_component(identifier, 0);
+                }
+            }
+            LegionEvent::EntityRemoved(entity) => {
+                let was_replicated = replicated.remove(&entity);
+
+                if was_replicated || held.remove(&entity) {
+                    let identifier = allocator
+                        .deallocate(entity)
+                        .expect("Entity should be allocated.");
+
+                    // The id is reused once all clients acknowledged this removal.
+                    recycler.free(identifier, world_state.command_frame);
+
+                    // The clients already removed an entity that was held.
+                    if was_replicated {
+                        retract_entity(world_state, identifier);
+                    }
+                }
+            }
+            LegionEvent::EntityInserted(entity, _component_count) => {
+                if !is_marked_replicated(world, entity) {
+                    continue;
+                }
+
+                // Component add/remove can report an insert for an entity we already know.
+                if replicated.contains(&entity) || held.contains(&entity) {
+                    continue;
+                }
+
+                if let Some(identifier) = allocate_network_id(allocator, recycler, entity) {
+                    replicated.insert(entity);
+                    world_state.insert_entity(
+                        identifier,
+                        serialize_entity(world, entity, components, codec),
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Give the entity a network id, `None` when all network ids are in use.
+fn allocate_network_id(
+    allocator: &mut UidAllocator<Entity>,
+    recycler: &mut UidRecycler,
+    entity: Entity,
+) -> Option<Uid> {
+    let identifier = recycler.allocate();
+
+    match identifier {
+        Some(identifier) => {
+            allocator.allocate(entity, Some(identifier));
+        }
+        None => log::error!(
+            "Cannot replicate entity {:?}, all network ids are in use.",
+            entity
+        ),
+    }
+
+    identifier
+}
+
+fn is_marked_replicated(world: &World, entity: Entity) -> bool {
+    world
+        .entry_ref(entity)
+        .map_or(false, |entry| entry.get_component::<Replicated>().is_ok())
+}
+
+// Start replicating the entities the marker was added to and stop replicating the entities it was removed from.
+//
+// The held entities are replicated again by the hierarchy once they are marked and their parent is replicated.
+fn update_replication_markers(
+    world: &World,
+    allocator: &mut UidAllocator<Entity>,
+    recycler: &mut UidRecycler,
+    replicated: &mut HashSet<Entity>,
+    held: &mut HashSet<Entity>,
+    components: &RegisteredComponentsResource,
+    world_state: &mut WorldState,
+    codec: WireCodec,
+) {
+    let marked = <Entity>::query()
+        .filter(component::<Replicated>())
+        .iter(world)
+        .cloned()
+        .collect::<HashSet<Entity>>();
+
+    for entity in marked
+        .difference(replicated)
+        .filter(|entity| !held.contains(entity))
+        .cloned()
+        .collect::<Vec<Entity>>()
+    {
+        let identifier = match allocate_network_id(allocator, recycler, entity) {
+            Some(identifier) => identifier,
+            None => continue,
+        };
+
+        replicated.insert(entity);
+        world_state.insert_entity(
+            identifier,
+            serialize_entity(world, entity, components, codec),
+        );
+    }
+
+    // The entity stays on the server with its network id, only the clients remove it.
+    for entity in replicated
+        .difference(&marked)
+        .cloned()
+        .collect::<Vec<Entity>>()
+    {
+        replicated.remove(&entity);
+        held.insert(entity);
+        retract_entity(world_state, allocator.get(&entity));
+    }
+}
+
+// Stop replicating the entities whose parent is not replicated, and their children in turn,
+// and replicate them again once their parent is.
+//
+// The server keeps such an entity and its network id, such that the references to it stay valid,
+// only the clients remove it. A child that was inserted in this command frame is left out of the state instead.
+fn update_hierarchy(
+    world: &World,
+    allocator: &UidAllocator<Entity>,
+    recycler: &UidRecycler,
+    replicated: &mut HashSet<Entity>,
+    held: &mut HashSet<Entity>,
+    components: &RegisteredComponentsResource,
+    world_state: &mut WorldState,
+    codec: WireCodec,
+) {
+    let parents = <(Entity, Read<ParentComponent>)>::query()
+        .iter(world)
+        .map(|(entity, parent)| (*entity, parent.parent()))
+        .collect::<HashMap<Entity, NetworkEntity>>();
+
+    let has_replicated_parent =
+        |entity: &Entity, replicated: &HashSet<Entity>| match parents.get(entity) {
+            Some(parent) => parent
+                .resolve(allocator, recycler)
+                .map_or(false, |parent| replicated.contains(&parent)),
+            None => true,
+        };
+
+    loop {
+        let orphans = replicated
+            .iter()
+            .filter(|entity| !has_replicated_parent(entity, replicated))
+            .cloned()
+            .collect::<Vec<Entity>>();
+
+        if orphans.is_empty() {
+            break;
+        }
+
+        for orphan in orphans {
+            replicated.remove(&orphan);
+            held.insert(orphan);
+            retract_entity(world_state, allocator.get(&orphan));
+        }
+    }
+
+    loop {
+        let adopted = held
+            .iter()
+            .filter(|entity| {
+                is_marked_replicated(world, **entity) && has_replicated_parent(entity, replicated)
+            })
+            .cloned()
+            .collect::<Vec<Entity>>();
+
+        if adopted.is_empty() {
+            break;
+        }
+
+        for entity in adopted {
+            held.remove(&entity);
+            replicated.insert(entity);
+            world_state.insert_entity(
+                allocator.get(&entity),
+                serialize_entity(world, entity, components, codec),
+            );
+        }
+    }
+}
+
+// Remove the entity from the clients along with its changes in the state,
+// an entity inserted in this command frame is left out of the state instead.
+fn retract_entity(world_state: &mut WorldState, entity_id: Uid) {
+    let inserted = world_state
+        .inserted
+        .iter()
+        .any(|insert| insert.entity_id() == entity_id);
+
+    if inserted {
+        world_state
+            .inserted
+            .retain(|insert| insert.entity_id() != entity_id);
+    } else {
+        world_state.remove_entity(entity_id);
+    }
+
+    world_state
+        .component_added
+        .retain(|added| added.entity_id() != entity_id);
+    world_state
+        .component_removed
+        .retain(|removed| removed.entity_id() != entity_id);
+    world_state
+        .changed
+        .retain(|changed| changed.entity_id() != entity_id);
+}
+
+// Put the inserts of parents before the inserts of their children.
+fn order_parents_first(
+    world: &World,
+    allocator: &UidAllocator<Entity>,
+    recycler: &UidRecycler,
+    world_state: &mut WorldState,
+) {
+    // A parent chain is not expected to be deeper, this also stops at a cycle.
+    const MAX_DEPTH: usize = 64;
+
+    let depth = |entity_id: Uid| {
+        let mut depth = 0;
+        let mut entity = *allocator.get_by_val(&entity_id);
+
+        while depth < MAX_DEPTH {
+            let parent = world
+                .entry_ref(entity)
+                .and_then(|entry| entry.get_component::<ParentComponent>().ok().cloned())
+                .and_then(|parent| parent.parent().resolve(allocator, recycler));
+
+            match parent {
+                Some(parent) => {
+                    entity = parent;
+                    depth += 1;
+                }
+                None => break,
+            }
+        }
+
+        depth
+    };
+
+    world_state
+        .inserted
+        .sort_by_cached_key(|insert| depth(insert.entity_id()));
+}
+
+// Serialize all registered components of the given entity.
+fn serialize_entity(
+    world: &World,
+    entity: Entity,
+    components: &RegisteredComponentsResource,
+    codec: WireCodec,
+) -> Vec<ComponentData> {
+    let mut entity_components = Vec::new();
+
+    for component in components.slice_with_uid().iter() {
+        if component.1.replication().server_only {
+            continue;
+        }
+
+        component
+            .1
+            .serialize_if_exists_in_world(&world, entity, &mut |serialize| {
+                let (result, buffer) = component
+                    .1
+                    .replication()
+                    .encoding
+                    .serialize(codec, |serializer| {
+                        erased_serde::serialize(&serialize, serializer)
+                    });
+
+                if let Ok(_) = result {
+                    entity_components.push(ComponentData::new(component.0, buffer));
+                }
+            });
+    }
+
+    entity_components
+}
+
+// Create the state that brings a new client up to date with the current world.
+fn initial_world_state(
+    world: &World,
+    replicated: &HashSet<Entity>,
+    allocator: &UidAllocator<Entity>,
+    components: &RegisteredComponentsResource,
+    command_frame: CommandFrame,
+    codec: WireCodec,
+) -> WorldState {
+    let mut world_state = WorldState::new(command_frame);
+
+    for entity in replicated.iter() {
+        world_state.insert_entity(
+            allocator.get(entity),
+            serialize_entity(world, *entity, components, codec),
+        );
+    }
+
+    world_state
+}
+
+// Returns the owning client of each entity that has an `OwnerComponent`.
+fn entity_owners(
+    world: &World,
+    replicated: &HashSet<Entity>,
+    allocator: &UidAllocator<Entity>,
+) -> HashMap<Uid, ClientId> {
+    let mut owners = HashMap::new();
+
+    for entity in replicated.iter() {
+        if let Some(entry) = world.entry_ref(*entity) {
+            if let Ok(owner) = entry.get_component::<OwnerComponent>() {
+                owners.insert(allocator.get(entity), owner.client_id());
+            }
+        }
+    }
+
+    owners
+}
+
+// Create a copy of the world state without the owner-only components of entities the client does not own.
+fn filter_owner_only(
+    world_state: &WorldState,
+    client: ClientId,
+    owner_only: &HashSet<Uid>,
+    owners: &HashMap<Uid, ClientId>,
+) -> WorldState {
+    let is_visible = |entity_id: Uid, component_id: Uid| {
+        !owner_only.contains(&component_id) || owners.get(&entity_id) == Some(&client)
+    };
+
+    let mut filtered = WorldState::new(world_state.command_frame);
+    filtered.command_frame_offset = world_state.command_frame_offset;
+
+    for entity_id in world_state.removed.iter() {
+        filtered.remove_entity(*entity_id);
+    }
+
+    for inserted in world_state.inserted.iter() {
+        let entity_components = inserted
+            .components()
+            .iter()
+            .filter(|x| is_visible(inserted.entity_id(), x.component_id()))
+            .cloned()
+            .collect();
+
+        filtered.insert_entity(inserted.entity_id(), entity_components);
+    }
+
+    for removed in world_state.component_removed.iter() {
+        if is_visible(removed.entity_id(), removed.component_id()) {
+            filtered.remove_component(removed.entity_id(), removed.component_id());
+        }
+    }
+
+    for added in world_state.component_added.iter() {
+        let component_data = added.component_data();
+
+        if is_visible(added.entity_id(), component_data.component_id()) {
+            filtered.add_component(added.entity_id(), component_data.clone());
+        }
+    }
+
+    for changed in world_state.changed.iter() {
+        let component_data = changed.component_data();
+
+        if is_visible(changed.entity_id(), component_data.component_id()) {
+            filtered.change(changed.entity_id(), component_data.clone());
+        }
+    }
+
+    filtered
+}
+
+// Serializes the difference between the unchanged state of a component and its current state, `None` if they are equal.
+//
+// The unchanged data comes from the modification tracker, the difference is encoded for the wire.
+fn difference_with_current(
+    registered_component: &ComponentRegistration,
+    world: &World,
+    entity: Entity,
+    unchanged: &[u8],
+    codec: WireCodec,
+) -> Option<Vec<u8>> {
+    let replication = registered_component.replication();
+
+    let (is_different, buffer) = TRACKER_CODEC.deserialize_erased(unchanged, |unchanged| {
+        replication.encoding.serialize(codec, |serializer| {
+            registered_component
+                .serialize_difference_with_current(world, entity, unchanged, serializer)
+                .unwrap()
+        })
+    });
+
+    if is_different {
+        Some(buffer)
+    } else {
+        None
+    }
+}
+
+/// An entity that changed owner since the previous command frame.
+struct OwnerTransfer {
+    entity_id: Uid,
+    previous: Option<ClientId>,
+    current: Option<ClientId>,
+    /// The owner-only components of the entity in full.
+    components: Vec<ComponentData>,
+    /// The owner-only components the previous owner knows of.
+    known: Vec<Uid>,
+}
+
+// Returns the entities that changed owner, entities inserted this command frame are sent to their owner in full already.
+fn owner_transfers(
+    previous: &HashMap<Uid, ClientId>,
+    current: &HashMap<Uid, ClientId>,
+    world_state: &WorldState,
+    world: &World,
+    allocator: &UidAllocator<Entity>,
+    components: &RegisteredComponentsResource,
+    owner_only: &HashSet<Uid>,
+    codec: WireCodec,
+) -> Vec<OwnerTransfer> {
+    let inserted = world_state
+        .inserted
+        .iter()
+        .map(|x| x.entity_id())
+        .collect::<HashSet<Uid>>();
+
+    previous
+        .keys()
+        .chain(current.keys())
+        .collect::<HashSet<&Uid>>()
+        .into_iter()
+        .filter(|entity_id| {
+            previous.get(entity_id) != current.get(entity_id)
+                && !inserted.contains(entity_id)
+                && !world_state.removed.contains(entity_id)
+        })
+        .map(|entity_id| {
+            let entity = allocator.get_by_val(entity_id);
+            let owner_only_components = serialize_entity(world, *entity, components, codec)
+                .into_iter()
+                .filter(|x| owner_only.contains(&x.component_id()))
+                .collect::<Vec<ComponentData>>();
+
+            // Owner-only components removed this command frame are known by the previous owner as well.
+            let known = owner_only_components
+                .iter()
+                .map(|x| x.component_id())
+                .chain(
+                    world_state
+                        .component_removed
+                        .iter()
+                        .filter(|x| {
+                            x.entity_id() == *entity_id && owner_only.contains(&x.component_id())
+                        })
+                        .map(|x| x.component_id()),
+                )
+                .collect();
+
+            OwnerTransfer {
+                entity_id: *entity_id,
+                previous: previous.get(entity_id).cloned(),
+                current: current.get(entity_id).cloned(),
+                components: owner_only_components,
+                known,
+            }
+        })
+        .collect()
+}
+
+// Adds the owner-only components of the entities the client gained and removes those of the entities it lost.
+fn transfer_owner_only(
+    world_state: &mut WorldState,
+    client: ClientId,
+    transfers: &[OwnerTransfer],
+    is_visible: impl Fn(Uid) -> bool,
+) {
+    for transfer in transfers.iter() {
+        // An entity the client is not sent has no components there, an inserted entity carries them already.
+        if !is_visible(transfer.entity_id)
+            || world_state
+                .inserted
+                .iter()
+                .any(|insert| insert.entity_id() == transfer.entity_id)
+        {
+            continue;
+        }
+
+        if transfer.current == Some(client) {
+            // The full state replaces the changes of this command frame.
+            world_state.changed.retain(|changed| {
+                changed.entity_id() != transfer.entity_id
+                    || !transfer
+                        .components
+                        .iter()
+                        .any(|x| x.component_id() == changed.component_data().component_id())
+            });
+
+            for component_data in transfer.components.iter() {
+                world_state.add_component(transfer.entity_id, component_data.clone());
+            }
+        } else if transfer.previous == Some(client) {
+            for component_id in transfer.known.iter() {
+                world_state.remove_component(transfer.entity_id, *component_id);
+            }
+        }
+    }
+}
+
+/// Holds back the changes of components that have a maximum update rate.
+#[derive(Default)]
+struct UpdateLimiter {
+    last_sent: HashMap<(Uid, TypeId), Instant>,
+    // The unchanged state of changes that are held back.
+    deferred: HashMap<(Uid, TypeId), Vec<u8>>,
+}
+
+fn add_differences_to_state(
+    components: &RegisteredComponentsResource,
+    world_state: &mut WorldState,
+    modification_buffer: &mut ModifiedComponentsBuffer,
+    detected: Vec<((Uid, TypeId), Vec<u8>)>,
+    baselines: &mut HashMap<(Uid, Uid), Vec<u8>>,
+    update_limiter: &mut UpdateLimiter,
+    world: &World,
+    allocator: &UidAllocator<Entity>,
+    recycler: &UidRecycler,
+    codec: WireCodec,
+) {
+    // Changes that were held back are compared against the state the clients received last.
+    let mut modifications = update_limiter
+        .deferred
+        .drain()
+        .collect::<HashMap<(Uid, TypeId), Vec<u8>>>();
+
+    for entry in modification_buffer.drain_entries() {
+        for (key, unchanged) in entry.1 {
+            // Keep the oldest unchanged state, the clients only know that one.
+            modifications.entry(key).or_insert(unchanged);
+        }
+    }
+
+    for (key, unchanged) in detected {
+        modifications.entry(key).or_insert(unchanged);
+    }
+
+    let now = Instant::now();
+    let registration_by_type = components.by_type_id();
+
+    update_limiter
+        .last_sent
+        .retain(|key, _| recycler.is_live(key.0));
+
+    for ((entity_id, component_type), unchanged) in modifications {
+        // The entity has been removed in the meantime.
+        if !recycler.is_live(entity_id) {
+            continue;
+        }
+
+        let component_id = components.get_uid(&component_type).expect("Should exist");
+        let entity = allocator.get_by_val(&entity_id);
+
+        let registered_component = registration_by_type
+            .get(&component_type)
+            .expect("Should exist");
+        let replication = registered_component.replication();
+
+        if replication.server_only {
+            continue;
+        }
+
+        if let Some(interval) = replication.update_interval() {
+            if let Some(last_sent) = update_limiter.last_sent.get(&(entity_id, component_type)) {
+                if now.duration_since(*last_sent) < interval {
+                    update_limiter
+                        .deferred
+                        .insert((entity_id, component_type), unchanged);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(buffer) =
+            difference_with_current(registered_component, world, *entity, &unchanged, codec)
+        {
+            world_state.change(entity_id, ComponentData::new(*component_id, buffer));
+
+            // A deferred change of an unreliable component is replaced by a difference with this state.
+            if replication.delivery == Delivery::Unreliable {
+                baselines.insert((entity_id, *component_id), unchanged);
+            }
+
+            if replication.max_update_rate.is_some() {
+                update_limiter
+                    .last_sent
+                    .insert((entity_id, component_type), now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashSet;
+
+    use legion::{Entity, World};
+
+    use net_sync::{synchronisation::WorldState, uid::UidAllocator};
+
+    use serde::{Deserialize, Serialize};
+
+    use net_sync::{
+        synchronisation::{NetworkCommand, NetworkMessage},
+        transport,
+    };
+
+    use crate::{
+        codec::WireCodec,
+        components::{NetworkEntity, ParentComponent, Replicated},
+        protocol::{ClientMessage, DisconnectReason},
+        resources::{BanList, EventResource, RegisteredComponentsResource, Sessions, UidRecycler},
+        world::server::{
+            handle_world_events, handshake, is_empty_state, order_parents_first,
+            receive_disconnects, refuse_banned, update_hierarchy, update_replication_markers,
+        },
+    };
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Message;
+
+    impl NetworkMessage for Message {}
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Command;
+
+    impl NetworkCommand for Command {}
+
+    struct Replication {
+        world: World,
+        event_resource: EventResource,
+        allocator: UidAllocator<Entity>,
+        recycler: UidRecycler,
+        replicated: HashSet<Entity>,
+        held: HashSet<Entity>,
+        components: RegisteredComponentsResource,
+    }
+
+    impl Replication {
+        fn new() -> Replication {
+            let mut world = World::default();
+            let event_resource = EventResource::new(&mut world);
+
+            Replication {
+                world,
+                event_resource,
+                allocator: UidAllocator::new(),
+                recycler: UidRecycler::new(),
+                replicated: HashSet::new(),
+                held: HashSet::new(),
+                components: RegisteredComponentsResource::new(),
+            }
+        }
+
+        // Replicate the changes of the command frame, as the server tick does.
+        fn update(&mut self, command_frame: u32) -> WorldState {
+            let mut world_state = WorldState::new(command_frame);
+
+            handle_world_events(
+                &self.world,
+                &mut self.allocator,
+                &mut self.recycler,
+                &mut self.replicated,
+                &mut self.held,
+                &self.components,
+                &self.event_resource,
+                &mut world_state,
+                WireCodec::default(),
+            );
+            update_replication_markers(
+                &self.world,
+                &mut self.allocator,
+                &mut self.recycler,
+                &mut self.replicated,
+                &mut self.held,
+                &self.components,
+                &mut world_state,
+                WireCodec::default(),
+            );
+            update_hierarchy(
+                &self.world,
+                &self.allocator,
+                &self.recycler,
+                &mut self.replicated,
+                &mut self.held,
+                &self.components,
+                &mut world_state,
+                WireCodec::default(),
+            );
+            order_parents_first(
+                &self.world,
+                &self.allocator,
+                &self.recycler,
+                &mut world_state,
+            );
+
+            world_state
+        }
+
+        fn push_child(&mut self, parent: Entity) -> Entity {
+            let parent_id = self.allocator.get(&parent);
+            self.world.push((
+                Replicated::new(),
+                ParentComponent::new(NetworkEntity::from_entity_id(parent_id)),
+            ))
+        }
+
+        // A replicated parent with a replicated child.
+        fn family(&mut self) -> (Entity, Entity) {
+            let parent = self.world.push((Replicated::new(),));
+            self.update(1);
+
+            let child = self.push_child(parent);
+            self.update(2);
+
+            (parent, child)
+        }
+    }
+
+    #[test]
+    fn children_of_unmarked_parent_stay_on_server_test() {
+        let mut replication = Replication::new();
+        let (parent, child) = replication.family();
+        let parent_id = replication.allocator.get(&parent);
+        let child_id = replication.allocator.get(&child);
+
+        replication
+            .world
+            .entry(parent)
+            .unwrap()
+            .remove_component::<Replicated>();
+        let world_state = replication.update(3);
+
+        assert!(world_state.removed.contains(&parent_id));
+        assert!(world_state.removed.contains(&child_id));
+        assert!(replication.replicated.is_empty());
+
+        assert!(replication.world.entry_ref(parent).is_some());
+        let child_entry = replication.world.entry_ref(child).unwrap();
+        assert!(child_entry.get_component::<ParentComponent>().is_ok());
+        assert!(child_entry.get_component::<Replicated>().is_ok());
+
+        // Both keep their network id on the server.
+        assert_eq!(replication.allocator.get(&parent), parent_id);
+        assert_eq!(replication.allocator.get(&child), child_id);
+
+        // The child does not come back as a root.
+        assert!(replication.update(4).inserted.is_empty());
+    }
+
+    #[test]
+    fn marking_parent_again_restores_its_children_test() {
+        let mut replication = Replication::new();
+        let (parent, child) = replication.family();
+        let parent_id = replication.allocator.get(&parent);
+        let child_id = replication.allocator.get(&child);
+
+        replication
+            .world
+            .entry(parent)
+            .unwrap()
+            .remove_component::<Replicated>();
+        replication.update(3);
+
+        replication
+            .world
+            .entry(parent)
+            .unwrap()
+            .add_component(Replicated::new());
+        let world_state = replication.update(4);
+
+        let inserted = world_state
+            .inserted
+            .iter()
+            .map(|insert| insert.entity_id())
+            .collect::<Vec<_>>();
+        assert_eq!(inserted, vec![parent_id, child_id]);
+        assert!(world_state.removed.is_empty());
+
+        assert!(replication.replicated.contains(&parent));
+        assert!(replication.replicated.contains(&child));
+        assert!(replication.held.is_empty());
+    }
+
+    #[test]
+    fn marked_child_of_unmarked_parent_is_not_replicated_test() {
+        let mut replication = Replication::new();
+        let parent = replication.world.push((Replicated::new(),));
+        replication.update(1);
+
+        replication
+            .world
+            .entry(parent)
+            .unwrap()
+            .remove_component::<Replicated>();
+        replication.update(2);
+
+        let child = replication.push_child(parent);
+        let world_state = replication.update(3);
+
+        assert!(is_empty_state(&world_state));
+        assert!(replication.held.contains(&child));
+        assert!(!replication.replicated.contains(&child));
+    }
+
+    #[test]
+    fn children_of_removed_parent_stay_on_server_test() {
+        let mut replication = Replication::new();
+        let (parent, child) = replication.family();
+        let parent_id = replication.allocator.get(&parent);
+        let child_id = replication.allocator.get(&child);
+
+        replication.world.remove(parent);
+        let world_state = replication.update(3);
+
+        let removed = world_state.removed.iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(
+            removed,
+            [parent_id, child_id]
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>()
+        );
+
+        // The server keeps the child and its network id.
+        assert!(replication.world.entry_ref(child).is_some());
+        assert!(replication.held.contains(&child));
+        assert!(replication.replicated.is_empty());
+        assert_eq!(replication.allocator.get(&child), child_id);
+
+        assert!(is_empty_state(&replication.update(4)));
+    }
+
+    #[test]
+    fn child_inserted_with_removal_of_its_parent_is_left_out_test() {
+        let mut replication = Replication::new();
+        let parent = replication.world.push((Replicated::new(),));
+        replication.update(1);
+        let parent_id = replication.allocator.get(&parent);
+
+        let child = replication.push_child(parent);
+        replication.world.remove(parent);
+        let world_state = replication.update(2);
+
+        // The clients never knew the child, they are neither sent its insert nor its removal.
+        assert!(world_state.inserted.is_empty());
+        assert_eq!(world_state.removed, vec![parent_id]);
+
+        assert!(replication.held.contains(&child));
+        assert!(replication.replicated.is_empty());
+
+        assert!(is_empty_state(&replication.update(3)));
+    }
+
+    #[test]
+    fn held_child_is_replicated_again_with_a_replicated_parent_test() {
+        let mut replication = Replication::new();
+        let (parent, child) = replication.family();
+        let child_id = replication.allocator.get(&child);
+
+        replication.world.remove(parent);
+        replication.update(3);
+
+        // The child is given a new replicated parent.
+        let new_parent = replication.world.push((Replicated::new(),));
+        replication.update(4);
+        let new_parent_id = replication.allocator.get(&new_parent);
+
+        *replication
+            .world
+            .entry(child)
+            .unwrap()
+            .get_component_mut::<ParentComponent>()
+            .unwrap() = ParentComponent::new(NetworkEntity::from_entity_id(new_parent_id));
+        let world_state = replication.update(5);
+
+        assert_eq!(world_state.inserted.len(), 1);
+        assert_eq!(world_state.inserted[0].entity_id(), child_id);
+        assert!(replication.replicated.contains(&child));
+        assert!(replication.held.is_empty());
+    }
+
+    #[test]
+    fn hello_in_the_tick_of_the_connection_completes_the_handshake_test() {
+        let mut handshakes = Vec::new();
+
+        let hellos = handshake(&mut handshakes, vec![1, 2], vec![(1, Some(7))]);
+        assert_eq!(hellos, vec![(1, Some(7))]);
+        assert_eq!(handshakes, vec![2]);
+
+        // A second hello of a client that completed its handshake is ignored.
+        let hellos = handshake(&mut handshakes, Vec::new(), vec![(1, None), (2, None)]);
+        assert_eq!(hellos, vec![(2, None)]);
+        assert!(handshakes.is_empty());
+    }
+
+    #[test]
+    fn hello_with_banned_session_is_refused_test() {
+        let mut sessions = Sessions::new();
+        let banned = sessions.open(1);
+        let other = sessions.open(2);
+
+        let mut bans = BanList::new();
+        bans.ban(banned);
+
+        let (hellos, refused) =
+            refuse_banned(vec![(3, Some(banned)), (4, Some(other)), (5, None)], &bans);
+        assert_eq!(hellos, vec![(4, Some(other)), (5, None)]);
+        assert_eq!(refused, vec![3]);
+    }
+
+    #[test]
+    fn client_that_leaves_is_disconnected_at_the_next_tick_test() {
+        let reason = DisconnectReason::CodecMismatch {
+            server: WireCodec::BincodeFixint,
+            client: WireCodec::MessagePack,
+        };
+        let messages = vec![
+            (
+                1,
+                transport::ClientToServerMessage::<ClientMessage<Message>, Command>::Message(
+                    ClientMessage::StateAck(3),
+                ),
+            ),
+            (
+                2,
+                transport::ClientToServerMessage::Message(ClientMessage::Disconnect(
+                    reason.clone(),
+                )),
+            ),
+        ];
+
+        let mut pending_disconnects = Vec::new();
+        receive_disconnects(&messages, &mut pending_disconnects);
+
+        assert_eq!(pending_disconnects, vec![(2, reason)]);
+    }
+}
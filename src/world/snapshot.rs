@@ -0,0 +1,314 @@
+//! Snapshots of the server world, such that a restarted server continues with the same network ids.
+//!
+//! A snapshot holds the registered components of the replicated entities, their network ids,
+//! the state of the [UidRecycler](UidRecycler) and the command frame the server was at.
+//! Components are stored by their registered type name, the name the legion registry knows them by,
+//! such that a snapshot can be restored by a build that registers its components in a different order.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use legion::{Entity, World};
+use serde::{Deserialize, Serialize};
+
+use net_sync::{
+    synchronisation::CommandFrame,
+    uid::{Uid, UidAllocator},
+};
+
+use crate::{
+    codec::{CodecError, WireCodec, TRACKER_CODEC},
+    resources::{RegisteredComponentsResource, UidRecycler},
+};
+
+/// The bytes every snapshot starts with.
+const MAGIC: [u8; 4] = *b"LSSN";
+/// The version of the snapshot format.
+pub const SNAPSHOT_VERSION: u16 = 1;
+/// The codec the snapshot is written with.
+const SNAPSHOT_CODEC: WireCodec = WireCodec::BincodeVarint;
+
+/// Errors that can occur while saving or loading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Codec(CodecError),
+    /// The data does not start with the snapshot magic bytes.
+    NotASnapshot,
+    UnsupportedVersion(u16),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(fmt, "IO error occurred: {:?}", e),
+            SnapshotError::Codec(e) => write!(fmt, "Codec error occurred: {}", e),
+            SnapshotError::NotASnapshot => write!(fmt, "The data is not a snapshot."),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(fmt, "Snapshot version {} is not supported.", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl From<CodecError> for SnapshotError {
+    fn from(error: CodecError) -> Self {
+        SnapshotError::Codec(error)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SnapshotComponent {
+    type_name: String,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SnapshotEntity {
+    entity_id: Uid,
+    components: Vec<SnapshotComponent>,
+}
+
+/// The persisted state of a server world.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    command_frame: CommandFrame,
+    state_update_sequence: u16,
+    recycler: UidRecycler,
+    entities: Vec<SnapshotEntity>,
+}
+
+impl WorldSnapshot {
+    /// Captures all registered components, including the server only components, of the given entities.
+    pub(crate) fn capture<'a>(
+        world: &World,
+        entities: impl Iterator<Item = &'a Entity>,
+        allocator: &UidAllocator<Entity>,
+        recycler: &UidRecycler,
+        components: &RegisteredComponentsResource,
+        command_frame: CommandFrame,
+        state_update_sequence: u16,
+    ) -> WorldSnapshot {
+        let registrations = components.slice_with_uid();
+        let mut snapshot_entities = Vec::new();
+
+        for entity in entities {
+            let mut snapshot_components = Vec::new();
+
+            for (_, registration) in registrations.iter() {
+                registration.serialize_if_exists_in_world(world, *entity, &mut |serialize| {
+                    let (result, data) = TRACKER_CODEC.serialize_erased(|serializer| {
+                        erased_serde::serialize(&serialize, serializer)
+                    });
+
+                    match result {
+                        Ok(_) => snapshot_components.push(SnapshotComponent {
+                            type_name: registration.type_name().to_string(),
+                            data,
+                        }),
+                        Err(e) => log::error!(
+                            "Cannot snapshot component {}: {}",
+                            registration.type_name(),
+                            e
+                        ),
+                    }
+                });
+            }
+
+            snapshot_entities.push(SnapshotEntity {
+                entity_id: allocator.get(entity),
+                components: snapshot_components,
+            });
+        }
+
+        WorldSnapshot {
+            command_frame,
+            state_update_sequence,
+            recycler: recycler.clone(),
+            entities: snapshot_entities,
+        }
+    }
+
+    /// Returns the command frame the server was at when the snapshot was taken.
+    pub fn command_frame(&self) -> CommandFrame {
+        self.command_frame
+    }
+
+    pub fn state_update_sequence(&self) -> u16 {
+        self.state_update_sequence
+    }
+
+    pub fn recycler(&self) -> &UidRecycler {
+        &self.recycler
+    }
+
+    /// Returns the number of entities in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Creates the entities of the snapshot in the world and allocates their network ids,
+    /// components that are no longer registered are skipped.
+    pub(crate) fn restore(
+        &self,
+        world: &mut World,
+        allocator: &mut UidAllocator<Entity>,
+        components: &RegisteredComponentsResource,
+    ) -> Vec<Entity> {
+        let registrations = components
+            .slice_with_uid()
+            .iter()
+            .map(|(_, registration)| (registration.type_name(), *registration))
+            .collect::<HashMap<_, _>>();
+
+        let mut restored = Vec::with_capacity(self.entities.len());
+
+        for snapshot_entity in self.entities.iter() {
+            let entity = world.extend(vec![()])[0];
+
+            for component in snapshot_entity.components.iter() {
+                match registrations.get(component.type_name.as_str()) {
+                    Some(registration) => {
+                        TRACKER_CODEC.deserialize_erased(&component.data, |deserializer| {
+                            registration.add_component(world, entity, deserializer)
+                        });
+                    }
+                    None => log::warn!(
+                        "Component {} in the snapshot is not registered.",
+                        component.type_name
+                    ),
+                }
+            }
+
+            allocator.allocate(entity, Some(snapshot_entity.entity_id));
+            restored.push(entity);
+        }
+
+        restored
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<(), SnapshotError> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&SNAPSHOT_CODEC.serialize(self)?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<WorldSnapshot, SnapshotError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        Ok(SNAPSHOT_CODEC.deserialize(&bytes)?)
+    }
+
+    /// Writes the snapshot to the file at the given path, replacing an existing file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<WorldSnapshot, SnapshotError> {
+        WorldSnapshot::read(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::io::Cursor;
+
+    use legion::{world::EntityStore, Entity, World};
+
+    use net_sync::uid::UidAllocator;
+
+    use crate::{
+        components::UidComponent,
+        resources::{RegisteredComponentsResource, UidRecycler},
+        world::snapshot::{SnapshotError, WorldSnapshot},
+    };
+
+    #[test]
+    fn snapshot_round_trip_test() {
+        let components = RegisteredComponentsResource::new();
+        let mut allocator = UidAllocator::<Entity>::new();
+        let mut recycler = UidRecycler::new();
+        let mut world = World::default();
+
+        let entity = world.push((UidComponent::new(7),));
+        allocator.allocate(entity, Some(recycler.allocate().unwrap()));
+        let entity_id = allocator.get(&entity);
+
+        let mut bytes = Vec::new();
+        WorldSnapshot::capture(
+            &world,
+            [entity].iter(),
+            &allocator,
+            &recycler,
+            &components,
+            12,
+            4,
+        )
+        .write(&mut bytes)
+        .unwrap();
+
+        let snapshot = WorldSnapshot::read(Cursor::new(bytes)).unwrap();
+        assert_eq!(snapshot.command_frame(), 12);
+        assert_eq!(snapshot.state_update_sequence(), 4);
+
+        let mut restored_world = World::default();
+        let mut restored_allocator = UidAllocator::<Entity>::new();
+        let restored = snapshot.restore(&mut restored_world, &mut restored_allocator, &components);
+
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored_allocator.get(&restored[0]), entity_id);
+        assert_eq!(
+            restored_world
+                .entry_ref(restored[0])
+                .and_then(|entry| entry.get_component::<UidComponent>().ok().cloned()),
+            Some(UidComponent::new(7))
+        );
+
+        // The restored recycler does not hand out the id of the restored entity again.
+        let mut restored_recycler = snapshot.recycler().clone();
+        assert_ne!(restored_recycler.allocate(), Some(entity_id));
+    }
+
+    #[test]
+    fn other_data_is_rejected_test() {
+        match WorldSnapshot::read(Cursor::new(b"not a snapshot".to_vec())) {
+            Err(SnapshotError::NotASnapshot) => {}
+            _ => panic!("Expected the data to be rejected."),
+        }
+    }
+}